@@ -34,8 +34,11 @@ unsafe extern "C" fn _entry() -> ! {
 
         jal lsd_main
 
-        j 0
-        EBREAK
+        // End the program once main returns
+        li a0, 1
+        li a1, 4
+        li a2, 0
+        ecall
     ", options(noreturn));
 }
 
@@ -108,6 +108,37 @@ impl Default for Sie {
     }
 }
 
+bitfield::bitfield! {
+    pub struct Sip(u16);
+
+    /// A supervisor software interrupt is pending
+    pub ssip, _: 1;
+
+    /// A supervisor timer interrupt is pending
+    pub stip, _: 5;
+
+    /// A supervisor external interrupt is pending
+    pub seip, _: 9;
+}
+
+impl Sip {
+    pub fn new() -> Self {
+        unsafe {
+            let mut sip_val: u16;
+
+            core::arch::asm!("csrr {sval}, sip", sval = out(reg) sip_val);
+
+            core::mem::transmute(sip_val)
+        }
+    }
+}
+
+impl Default for Sip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Time;
 
 impl Time {
@@ -1,16 +1,25 @@
 // [0][1][ptr][len]            = print string               -> no return
 // [0][2]                      = take input                 -> [char]
+// [0][3][lba][count][ptr][len] = read disk blocks           -> [status]
+// [0][4][lba][count][ptr][len] = write disk blocks          -> [status]
+// [0][5][ptr][len]            = fill with random bytes     -> [status]
 // 
 // [1][0]                      = forfeit task control       -> no return
-// [1][1][size]                = extend heap                -> [ptr]
-// [1][2]                      = spawn thread               -> [task_id][thread_id]
+// [1][1][size]                = extend heap                -> [ptr], 0 on failure
+// [1][2]                      = spawn thread               -> [task_id][thread_id], thread ID is 0 in the parent
+//                                                               and task ID is usize::MAX on failure
 // [1][3]                      = drop current thread        -> no return
-// [1][4]                      = End program                -> no return
+// [1][4][code]                = End program                -> no return
+// [1][5][nice]                = set thread priority        -> no return
+// [1][6][entry][arg][size]    = create thread              -> [thread_id], usize::MAX on failure
+// [1][7][thread_id]           = join thread                -> no return
+// [1][8][task_id]             = wait for task to end       -> [status][code]
+// [1][9][thread_id]           = detach thread              -> [status], 0 on success and 1 if there is no such thread
 
 use alloc::vec::Vec;
 use spin::Mutex;
 
-use crate::{print, println};
+use crate::print;
 
 pub fn syscall_core(trap_frame: &mut crate::traps::TrapFrame) {
     match trap_frame.a0 {
@@ -42,12 +51,15 @@ pub fn kernel_io(trap_frame: &mut crate::traps::TrapFrame) {
         2 => {
             use crate::traps::task;
 
-            INPUT_AWAIT_LIST.lock().push(
-                task::CURRENT_USER_TASK.read().current_task().task_id
-            );
-            task::CURRENT_USER_TASK.write().current_task_mut().waiting_on = task::WaitSrc::CharIn;
-            task::advance_task(trap_frame);
+            // Parked before it's on the list, so a character arriving on another hart finds its frame saved
+            let (task_id, _) = task::park(trap_frame, task::WaitSrc::CharIn);
+            INPUT_AWAIT_LIST.lock().push(task_id);
+
+            task::switch_to_next(trap_frame);
         },
+        3 => crate::drivers::block::user_request(trap_frame, false),
+        4 => crate::drivers::block::user_request(trap_frame, true),
+        5 => crate::random::user_request(trap_frame),
         subcall => panic!("Unrecognized io subcall 0x{:x} trapframe: \n{:#x?}", subcall, trap_frame)
     }
 }
@@ -56,103 +68,125 @@ pub fn kernel_task(trap_frame: &mut crate::traps::TrapFrame) {
     match trap_frame.a1 {
         0 => crate::traps::task::advance_task(trap_frame),
         1 => {
-            use crate::memory::{self, pmm, vmm};
-
-            let bytes = trap_frame.a2;
-            let frames = bytes.div_ceil(0x1000);
-
-            let mut claims = (0..frames).map(|_| {
-                pmm::REGION_LIST.lock().claim() as u64 - memory::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed)
-            });
-
-            let read = crate::traps::task::CURRENT_USER_TASK.read();
-
-            let cur_task = read.current_task();
-            let vaddr = cur_task.vmm.alloc(frames * 0x1000, vmem::AllocStrategy::NextFit).unwrap() as u64;
-
-            trap_frame.a0 = vaddr as usize;
-            
-            let vaddrs = (vaddr..vaddr + (frames as u64) * 0x1000).step_by(0x1000);
+            let lock = crate::traps::task::THREADS.lock();
 
-            let table = (cur_task.task_table.get_ppn() << 12) as *mut vmm::PageTable;
-            println!("extending heap on current task");
+            trap_frame.a0 = lock.current_process().alloc_heap(trap_frame.a2).unwrap_or(0);
+        },
+        2 => {
+            let mut lock = crate::traps::task::THREADS.lock();
 
-            for vaddr in vaddrs {
-                println!("Test with vaddr 0x{:x}", vaddr);
-                let paddr = claims.next().unwrap();
+            let nice = lock.current_thread().sched.nice;
+            let process = lock.current_process_mut();
+            let task_id = process.task_id;
 
-                let virt = memory::VirtualAddress(vaddr);
-                let phys = memory::PhysicalAddress(paddr);
-                println!("Test with vaddr 0x{:x}", vaddr);
+            // The new thread carries on from the same point, but on a stack of its own
+            let Some(mut thread) = process.new_thread(*trap_frame, nice, crate::traps::task::USER_STACK_SIZE) else {
+                trap_frame.a0 = usize::MAX;
+                trap_frame.a1 = 0;
+                return;
+            };
 
-                let mut lock = pmm::REGION_LIST.lock();
+            thread.trap_frame.a0 = task_id;
+            thread.trap_frame.a1 = thread.thread_id;
 
-                let flags = vmm::PageFlags::READ | vmm::PageFlags::WRITE | vmm::PageFlags::USER;
+            lock.new_thread(thread);
 
-                let level = vmm::LEVELS.load(core::sync::atomic::Ordering::Relaxed) as usize;
-                let level = vmm::PageLevel::from_usize(level);
-                println!("Test with vaddr 0x{:x}", vaddr);
+            trap_frame.a0 = task_id;
+            trap_frame.a1 = 0;
+        },
+        3 => crate::traps::task::exit_thread(trap_frame),
+        4 => crate::traps::task::exit_task(trap_frame, trap_frame.a2),
+        5 => {
+            let mut write = crate::traps::task::THREADS.lock();
+            let cur_task = write.current_thread_mut();
+
+            // Passed as a signed value in a register, so anything out of range is clamped
+            let nice = (trap_frame.a2 as isize).clamp(crate::traps::task::NICE_MIN as isize, crate::traps::task::NICE_MAX as isize);
+            cur_task.sched.nice = nice as i8;
+            cur_task.sched.level = cur_task.sched.base_level();
+        },
+        6 => {
+            let mut lock = crate::traps::task::THREADS.lock();
+
+            let nice = lock.current_thread().sched.nice;
+            let process = lock.current_process_mut();
+
+            // Starts at `entry` with `arg` as its only argument, the global and thread pointers carry over
+            let mut frame = crate::traps::TrapFrame::default();
+            frame.sepc = trap_frame.a2;
+            frame.a0 = trap_frame.a3;
+            frame.gp = trap_frame.gp;
+            frame.tp = trap_frame.tp;
+
+            // A size of zero gets the default
+            let stack_size = match trap_frame.a4 {
+                0 => crate::traps::task::USER_STACK_SIZE,
+                size => size,
+            };
+
+            trap_frame.a0 = match process.new_thread(frame, nice, stack_size) {
+                Some(thread) => {
+                    let thread_id = thread.thread_id;
+                    lock.new_thread(thread);
+
+                    thread_id
+                },
+                None => usize::MAX,
+            };
+        },
+        7 => {
+            let mut lock = crate::traps::task::THREADS.lock();
 
-                unsafe {
-                    vmm::map(
-                        table, 
-                        virt, 
-                        phys, 
-                        level, 
-                        vmm::PageLevel::Level1, 
-                        &mut lock, 
-                        flags
-                    );
+            let current = lock.current_thread().key();
+            let key = (current.0, trap_frame.a2);
 
-                    core::arch::asm!("sfence.vma");
-                }
-                println!("Test with vaddr 0x{:x}", vaddr);
+            // An exited thread's ID stays taken until it's joined
+            if lock.current_process_mut().join_exited(key.1) {
+                return;
             }
-            println!("extending heap on current task");
-        },
-        2 => {
-            crate::traps::task::update_current(trap_frame);
-            let mut read = crate::traps::task::CURRENT_USER_TASK.write();
 
-            let cur_task = read.current_task_mut();
-            trap_frame.a0 = 0;
+            // Joining a thread that was never there, or ourselves, returns straight away
+            if key == current || lock.find_thread_mut(key.0, key.1).is_none() {
+                return;
+            }
 
-            let mut task_clone = *cur_task;
-            task_clone.thread_id = task_clone.thread_manager.alloc(1, vmem::AllocStrategy::NextFit).unwrap();
-            task_clone.trap_frame.a0 = task_clone.task_id;
-            task_clone.trap_frame.a1 = task_clone.thread_id;
+            // Run the ecall again once woken, which lets go of the thread's ID
+            trap_frame.sepc -= 4;
+            lock.current_thread_mut().waiting_on = crate::traps::task::WaitSrc::Thread(key);
 
-            core::mem::drop(read);
-            crate::traps::task::new_task(task_clone);
+            core::mem::drop(lock);
+            crate::traps::task::advance_task(trap_frame);
         },
-        3 => {
-            let read = crate::traps::task::CURRENT_USER_TASK.read();
-            let index = read.cur_task_idx;
-            core::mem::drop(read);
+        8 => {
+            let mut lock = crate::traps::task::THREADS.lock();
+            let task_id = trap_frame.a2;
+
+            if let Some(code) = lock.reap_zombie(task_id) {
+                trap_frame.a0 = 0;
+                trap_frame.a1 = code;
+                return;
+            }
 
-            crate::traps::task::advance_task(trap_frame);
+            // Nothing to wait for, or waiting on ourselves, which would never end
+            if lock.process(task_id).is_none() || lock.current_thread().task_id == task_id {
+                trap_frame.a0 = 1;
+                return;
+            }
 
-            crate::traps::task::drop_task(index);
-        },
-        4 => {
-            let read = crate::traps::task::CURRENT_USER_TASK.read();
-            let current = read.current_task();
-            let id = current.task_id;
-            core::mem::drop(read);
+            // Run the ecall again once woken, which collects the exit code
+            trap_frame.sepc -= 4;
+            lock.current_thread_mut().waiting_on = crate::traps::task::WaitSrc::Task(task_id);
 
+            core::mem::drop(lock);
             crate::traps::task::advance_task(trap_frame);
+        },
+        9 => {
+            let mut lock = crate::traps::task::THREADS.lock();
 
-            let write = crate::traps::task::CURRENT_USER_TASK.write();
-
-            for (index, entry) in write.queue.iter().rev().enumerate() {
-                if entry.task_id == id {
-                    if entry.thread_id == 0 {
-                        crate::traps::task::full_drop_task(index);
-                    } else {
-                        crate::traps::task::drop_task(index)
-                    }
-                }
-            }
+            trap_frame.a0 = match lock.current_process_mut().detach(trap_frame.a2) {
+                true => 0,
+                false => 1,
+            };
         },
         subcall => panic!("Unrecognized task subcall 0x{:x} trapframe: \n{:#x?}", subcall, trap_frame)
     }
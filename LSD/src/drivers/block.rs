@@ -0,0 +1,194 @@
+//! Disks, independent of the controller they sit behind
+
+use alloc::vec::Vec;
+
+use super::{pci::nvme::{self, NvmeError}, virtio};
+use crate::{
+    memory::{vmm, DmaRegion, PhysicalAddress},
+    traps::TrapFrame,
+};
+
+/// Number of user transfers a disk can have staged at once
+pub const BOUNCE_BUFFERS: usize = 4;
+
+/// Size of each bounce buffer, and so the most a single user transfer can move
+pub const BOUNCE_LEN: usize = 0x10000;
+
+/// A disk addressed in fixed size blocks
+pub trait BlockDevice: Send {
+    /// Size of a block in bytes, zero if the device has no media to transfer to
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> u64;
+
+    /// Reads `count` blocks starting at `lba` into `buffer`
+    fn read_blocks(&mut self, lba: u64, count: u16, buffer: &mut DmaRegion<[u8]>) -> Result<(), BlockError>;
+
+    /// Writes `count` blocks from `buffer` starting at `lba`
+    fn write_blocks(&mut self, lba: u64, count: u16, buffer: &DmaRegion<[u8]>) -> Result<(), BlockError>;
+
+    /// Makes sure every completed write has reached stable storage
+    fn flush(&mut self) -> Result<(), BlockError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// There is no media to perform I/O on
+    NoMedia,
+    /// The buffer is smaller than the blocks being transferred
+    BufferTooSmall,
+    /// The blocks requested extend past the end of the device
+    OutOfRange,
+    /// The transfer is larger than the device accepts in one request
+    TransferTooLarge,
+    /// The device did not respond in time
+    Timeout,
+    /// The device completed the request with an error
+    Io,
+    /// There is no device to perform the request
+    NoDevice,
+    /// The buffer given isn't mapped in the requesting task
+    BadBuffer,
+    /// The device doesn't support the request
+    Unsupported,
+    /// The device can't be written to
+    ReadOnly,
+    /// The device has no room for another request
+    Busy,
+}
+
+impl BlockError {
+    /// The value handed back to userspace for this error, success is reported as 0
+    ///
+    /// These line up with the codes of [`NvmeError`], so userspace sees the same codes whichever disk it uses.
+    pub fn code(&self) -> usize {
+        match self {
+            Self::NoMedia => 1,
+            Self::BufferTooSmall => 2,
+            Self::OutOfRange => 3,
+            Self::TransferTooLarge => 4,
+            Self::Timeout => 5,
+            Self::Io => 6,
+            Self::NoDevice => 7,
+            Self::BadBuffer => 8,
+            Self::Unsupported => 9,
+            Self::ReadOnly => 10,
+            Self::Busy => 11,
+        }
+    }
+}
+
+impl From<NvmeError> for BlockError {
+    fn from(e: NvmeError) -> Self {
+        match e {
+            NvmeError::NoNamespace => Self::NoMedia,
+            NvmeError::BufferTooSmall => Self::BufferTooSmall,
+            NvmeError::OutOfRange => Self::OutOfRange,
+            NvmeError::TransferTooLarge => Self::TransferTooLarge,
+            NvmeError::Timeout => Self::Timeout,
+            NvmeError::Command(_) => Self::Io,
+            NvmeError::NoController => Self::NoDevice,
+            NvmeError::BadBuffer => Self::BadBuffer,
+            NvmeError::Unsupported => Self::Unsupported,
+            NvmeError::Busy => Self::Busy,
+        }
+    }
+}
+
+/// Runs `f` on the first disk found, NVMe controllers before virtio block devices
+///
+/// Returns `None` if there is no disk at all.
+pub fn with_disk<R>(f: impl FnOnce(&mut dyn BlockDevice) -> R) -> Option<R> {
+    // Completions for both kinds of disk are handled under the same locks
+    crate::traps::without_interrupts(|| {
+        if let Some(controller) = nvme::CONTROLLERS.lock().first_mut() {
+            return Some(f(controller));
+        }
+
+        virtio::block::BLOCK_DEVS.lock().first_mut().map(|device| f(device))
+    })
+}
+
+/// Backs the disk read and write syscalls, sending the request to the same disk `with_disk` would use
+pub fn user_request(trap_frame: &mut TrapFrame, write: bool) {
+    let has_nvme = !nvme::CONTROLLERS.lock().is_empty();
+
+    match has_nvme {
+        true => nvme::io::user_request(trap_frame, write),
+        false => virtio::block::user_request(trap_frame, write),
+    }
+}
+
+/// Splits `len` bytes of the current task's memory at `virt` into physically contiguous pieces
+///
+/// `device_writes` is set when data from the disk lands in the buffer, which the task then has to be able to write
+/// to itself. Kernel memory and pages the task can't touch are refused.
+pub fn user_segments(virt: usize, len: usize, device_writes: bool) -> Result<Vec<(PhysicalAddress, usize)>, BlockError> {
+    vmm::user_segments(virt, len, device_writes).ok_or(BlockError::BadBuffer)
+}
+
+/// Physically contiguous buffers user transfers are staged in, allocated once when the disk is brought up
+pub struct BouncePool {
+    buffers: Vec<DmaRegion<[u8]>>,
+    /// Indexes of the buffers not in use
+    free: Vec<usize>,
+}
+
+impl BouncePool {
+    pub fn new(count: usize, len: usize) -> Self {
+        Self {
+            buffers: (0..count).map(|_| unsafe { DmaRegion::new_raw(len, false) }).collect(),
+            free: (0..count).collect(),
+        }
+    }
+
+    /// Size of each buffer in the pool
+    pub fn buffer_len(&self) -> usize {
+        self.buffers.first().map_or(0, |buffer| buffer.len())
+    }
+
+    /// Takes a buffer out of the pool, returning its index, or `None` if every buffer is in use
+    pub fn take(&mut self) -> Option<usize> {
+        self.free.pop()
+    }
+
+    /// Puts a buffer from `take` back, once the device is done with it
+    pub fn release(&mut self, index: usize) {
+        self.free.push(index);
+    }
+
+    pub fn get(&self, index: usize) -> &DmaRegion<[u8]> {
+        &self.buffers[index]
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut DmaRegion<[u8]> {
+        &mut self.buffers[index]
+    }
+}
+
+/// Copies the pieces of a user buffer into the start of `bounce`
+pub fn gather(segments: &[(PhysicalAddress, usize)], bounce: &mut DmaRegion<[u8]>) {
+    let mut offset = 0;
+
+    for (phys, len) in segments.iter() {
+        unsafe {
+            core::ptr::copy_nonoverlapping(phys.as_ptr(), bounce.get_mut().as_mut_ptr().add(offset), *len);
+        }
+
+        offset += len;
+    }
+}
+
+/// Copies the start of `bounce` out to the pieces of a user buffer
+pub fn scatter(bounce: &DmaRegion<[u8]>, segments: &[(PhysicalAddress, usize)]) {
+    let mut offset = 0;
+
+    for (phys, len) in segments.iter() {
+        unsafe {
+            core::ptr::copy_nonoverlapping(bounce.as_ptr().add(offset), phys.as_ptr(), *len);
+        }
+
+        offset += len;
+    }
+}
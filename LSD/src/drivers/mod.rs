@@ -1,3 +1,4 @@
 pub mod virtio;
 pub mod goldfish_rtc;
-//pub mod pci;
\ No newline at end of file
+pub mod pci;
+pub mod block;
\ No newline at end of file
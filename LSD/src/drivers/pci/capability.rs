@@ -0,0 +1,232 @@
+use super::slot::{Bar, Slot};
+use crate::volatile::{Volatile, ReadWrite};
+
+/// Capability IDs we know the layout of
+pub mod id {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR: u8 = 0x09;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSIX: u8 = 0x11;
+}
+
+/// An entry in a function's capability list
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    /// Byte offset of the capability in configuration space
+    pub offset: u8,
+}
+
+/// Iterator over the capability linked list
+pub struct Capabilities<'a> {
+    slot: &'a Slot,
+    next: u8,
+    /// Guards against a malformed list that loops back on itself
+    remaining: usize,
+}
+
+impl<'a> Capabilities<'a> {
+    pub(super) fn new(slot: &'a Slot, next: u8) -> Self {
+        Self {
+            slot,
+            next,
+            // Capabilities are at least 4 bytes and live after the 64 byte header
+            remaining: (256 - 64) / 4,
+        }
+    }
+}
+
+impl Iterator for Capabilities<'_> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == 0 || self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+
+        let offset = self.next;
+        // SAFETY: The offset came from the capability list, which stays within the first 256 bytes
+        let header: u16 = unsafe { self.slot.read_config(offset as usize) };
+
+        self.next = (header >> 8) as u8 & !0b11;
+
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+/// Message Signaled Interrupts capability
+pub struct Msi<'a> {
+    slot: &'a Slot,
+    offset: usize,
+}
+
+impl<'a> Msi<'a> {
+    pub fn find(slot: &'a Slot) -> Option<Self> {
+        slot.capabilities()
+            .find(|cap| cap.id == id::MSI)
+            .map(|cap| Self { slot, offset: cap.offset as usize })
+    }
+
+    fn control(&self) -> u16 {
+        unsafe { self.slot.read_config(self.offset + 2) }
+    }
+
+    fn set_control(&self, control: u16) {
+        unsafe { self.slot.write_config(self.offset + 2, control) }
+    }
+
+    /// Whether the message address register is 64 bits
+    pub fn is_64bit(&self) -> bool {
+        self.control() & (1 << 7) != 0
+    }
+
+    pub fn per_vector_masking(&self) -> bool {
+        self.control() & (1 << 8) != 0
+    }
+
+    /// Number of vectors the function can use
+    pub fn vectors(&self) -> usize {
+        1 << ((self.control() >> 1) & 0x7)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.control() & 1 != 0
+    }
+
+    /// Enables MSI with a single vector
+    pub fn enable(&self) {
+        // Clearing Multiple Message Enable leaves a single vector
+        let control = self.control() & !(0x7 << 4);
+        self.set_control(control | 1);
+    }
+
+    pub fn disable(&self) {
+        self.set_control(self.control() & !1);
+    }
+
+    /// Sets the address the function writes `data` to when raising the interrupt
+    pub fn set_message(&self, address: u64, data: u16) {
+        unsafe {
+            self.slot.write_config(self.offset + 4, address as u32);
+
+            match self.is_64bit() {
+                true => {
+                    self.slot.write_config(self.offset + 8, (address >> 32) as u32);
+                    self.slot.write_config(self.offset + 12, data);
+                },
+                false => {
+                    assert!(address >> 32 == 0, "MSI address doesn't fit in 32 bits");
+                    self.slot.write_config(self.offset + 8, data);
+                },
+            }
+        }
+    }
+}
+
+/// An entry in the MSI-X table
+#[derive(Debug)]
+#[repr(C)]
+pub struct MsixEntry {
+    pub address_low: Volatile<u32, ReadWrite>,
+    pub address_high: Volatile<u32, ReadWrite>,
+    pub data: Volatile<u32, ReadWrite>,
+    /// Bit 0 masks the vector
+    pub control: Volatile<u32, ReadWrite>,
+}
+
+/// Extended Message Signaled Interrupts capability
+pub struct MsiX<'a> {
+    slot: &'a Slot,
+    offset: usize,
+}
+
+impl<'a> MsiX<'a> {
+    pub fn find(slot: &'a Slot) -> Option<Self> {
+        slot.capabilities()
+            .find(|cap| cap.id == id::MSIX)
+            .map(|cap| Self { slot, offset: cap.offset as usize })
+    }
+
+    fn control(&self) -> u16 {
+        unsafe { self.slot.read_config(self.offset + 2) }
+    }
+
+    fn set_control(&self, control: u16) {
+        unsafe { self.slot.write_config(self.offset + 2, control) }
+    }
+
+    /// Number of entries in the table
+    pub fn table_size(&self) -> usize {
+        (self.control() & 0x7ff) as usize + 1
+    }
+
+    /// Returns the BAR the table lives in and its offset into it
+    pub fn table_location(&self) -> (usize, u64) {
+        let reg: u32 = unsafe { self.slot.read_config(self.offset + 4) };
+
+        ((reg & 0x7) as usize, (reg & !0x7) as u64)
+    }
+
+    /// Returns the BAR the pending bit array lives in and its offset into it
+    pub fn pba_location(&self) -> (usize, u64) {
+        let reg: u32 = unsafe { self.slot.read_config(self.offset + 8) };
+
+        ((reg & 0x7) as usize, (reg & !0x7) as u64)
+    }
+
+    /// Returns the table, if the BAR holding it was assigned an address
+    pub fn table(&self, bars: &[Option<Bar>; 6]) -> Option<&'a [MsixEntry]> {
+        let (bir, offset) = self.table_location();
+        let bar = bars.get(bir).copied().flatten()?;
+
+        let len = self.table_size();
+        if offset + (len * core::mem::size_of::<MsixEntry>()) as u64 > bar.size {
+            return None;
+        }
+
+        // SAFETY: The table is inside the BAR, which is mapped
+        unsafe { Some(core::slice::from_raw_parts(bar.virt().add(offset as usize).cast(), len)) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.control() & (1 << 15) != 0
+    }
+
+    /// Enables MSI-X, with every vector masked until it is programmed
+    pub fn enable(&self, bars: &[Option<Bar>; 6]) -> Option<()> {
+        let table = self.table(bars)?;
+
+        for entry in table.iter() {
+            entry.control.write(1);
+        }
+
+        // Enable with the function mask set, so nothing fires while the table is inconsistent
+        self.set_control(self.control() | (1 << 15) | (1 << 14));
+        self.set_control(self.control() & !(1 << 14));
+
+        Some(())
+    }
+
+    pub fn disable(&self) {
+        self.set_control(self.control() & !(1 << 15));
+    }
+
+    /// Points a vector at `address` and unmasks it
+    pub fn set_vector(&self, bars: &[Option<Bar>; 6], index: usize, address: u64, data: u32) -> Option<()> {
+        let entry = self.table(bars)?.get(index)?;
+
+        entry.control.write(1);
+        entry.address_low.write(address as u32);
+        entry.address_high.write((address >> 32) as u32);
+        entry.data.write(data);
+        entry.control.write(0);
+
+        Some(())
+    }
+}
@@ -0,0 +1,108 @@
+use alloc::boxed::Box;
+
+use super::{nvme, slot::DeviceIdent, PCIHost, PciDevice};
+use crate::drivers::virtio::transport::pci::VirtioPciDriver;
+
+/// Every PCI driver, the first one to match and accept a function is attached to it
+pub static DRIVERS: &[&dyn PciDriver] = &[
+    &nvme::NvmeDriver,
+    &VirtioPciDriver,
+];
+
+/// Which functions a driver handles, fields left as `None` match anything
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl DeviceMatch {
+    /// Matches a specific vendor and device
+    pub const fn id(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// Matches every function from a vendor
+    pub const fn vendor(vendor_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: None,
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// Matches every function of a class, subclass, and programming interface
+    pub const fn class(class: u8, subclass: u8, prog_if: u8) -> Self {
+        Self {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: Some(prog_if),
+        }
+    }
+
+    pub fn matches(&self, ident: &DeviceIdent) -> bool {
+        fn field<T: PartialEq>(want: Option<T>, have: T) -> bool {
+            want.is_none_or(|want| want == have)
+        }
+
+        field(self.vendor_id, ident.vendor_id)
+            && field(self.device_id, ident.device_id)
+            && field(self.class, ident.class)
+            && field(self.subclass, ident.subclass)
+            && field(self.prog_if, ident.prog_if)
+    }
+}
+
+/// Why a driver couldn't take a function it matched
+#[derive(Debug)]
+pub enum AttachError {
+    /// The BAR the driver needs wasn't implemented, or didn't fit in a window
+    MissingBar(usize),
+    /// The driver's own error
+    Driver(Box<dyn core::fmt::Debug + Send>),
+}
+
+impl AttachError {
+    pub fn driver(err: impl core::fmt::Debug + Send + 'static) -> Self {
+        Self::Driver(Box::new(err))
+    }
+}
+
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The functions this driver may handle
+    fn matches(&self) -> &'static [DeviceMatch];
+
+    /// Takes a closer look at a matching function, returning whether the driver wants it
+    fn probe(&self, _device: &PciDevice) -> bool {
+        true
+    }
+
+    /// Brings up the function
+    ///
+    /// # Safety
+    /// The function must have had its BARs assigned, and not be attached to any other driver
+    unsafe fn attach(&self, host: &PCIHost, device: &PciDevice) -> Result<(), AttachError>;
+}
+
+/// Returns the first driver that matches and accepts the function
+pub fn find(device: &PciDevice) -> Option<&'static dyn PciDriver> {
+    DRIVERS.iter()
+        .copied()
+        .filter(|driver| driver.matches().iter().any(|matcher| matcher.matches(&device.ident)))
+        .find(|driver| driver.probe(device))
+}
@@ -0,0 +1,91 @@
+use core::sync::atomic::Ordering;
+
+use super::{capability::{Msi, MsiX}, slot, PCIHost, PciDevice};
+use crate::traps::{imsic, plic};
+
+/// How a function's interrupt is delivered, and the identity its handler is called with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    /// Legacy pin routed through the PLIC
+    Intx(usize),
+    /// MSI delivered to an IMSIC interrupt file
+    Msi(usize),
+    /// MSI-X vector 0 delivered to an IMSIC interrupt file
+    MsiX(usize),
+}
+
+impl Interrupt {
+    pub fn id(&self) -> usize {
+        match self {
+            Self::Intx(id) | Self::Msi(id) | Self::MsiX(id) => *id,
+        }
+    }
+}
+
+/// Routes the function's first interrupt to `handler`
+///
+/// MSI-X, then MSI, are used when there is an IMSIC to deliver them to. Otherwise the legacy pin is routed
+/// through the PLIC. Returns `None` if the function can't interrupt this hart.
+///
+/// # Safety
+/// The device must have had its BARs assigned, and not have interrupts configured already
+pub unsafe fn enable(host: &PCIHost, device: &PciDevice, handler: fn(usize)) -> Option<Interrupt> {
+    let slot = device.slot();
+
+    if imsic::present() {
+        if let Some(interrupt) = enable_msi(device, handler) {
+            slot.set_command(slot.command() | slot::Command::INTX_DISABLE);
+
+            return Some(interrupt);
+        }
+    }
+
+    let plic = plic::PLIC_ADDR.load(Ordering::Relaxed);
+    if plic.is_null() {
+        return None;
+    }
+
+    let irq = host.intx_irq(device.bus, device.dev, device.func, slot.interrupt_pin())?;
+
+    (*plic).enable_interrupt(crate::current_context(), irq);
+    (*plic).set_interrupt_priority(irq, 0x2);
+
+    plic::add_handler(irq, handler);
+
+    slot.set_command(slot.command() - slot::Command::INTX_DISABLE);
+
+    Some(Interrupt::Intx(irq))
+}
+
+fn enable_msi(device: &PciDevice, handler: fn(usize)) -> Option<Interrupt> {
+    let slot = device.slot();
+    let msix = MsiX::find(slot);
+    let msi = Msi::find(slot);
+
+    if msix.is_none() && msi.is_none() {
+        return None;
+    }
+
+    let id = imsic::alloc(handler)?;
+    let address = imsic::message_address(crate::HART_ID.load(Ordering::Relaxed));
+
+    if let Some(msix) = msix {
+        if msix.enable(&device.bars).is_some() {
+            if msix.set_vector(&device.bars, 0, address, id as u32).is_some() {
+                return Some(Interrupt::MsiX(id));
+            }
+
+            msix.disable();
+        }
+    }
+
+    let Some(msi) = msi else {
+        imsic::free(id);
+        return None;
+    };
+
+    msi.set_message(address, id as u16);
+    msi.enable();
+
+    Some(Interrupt::Msi(id))
+}
@@ -1,10 +1,20 @@
 pub mod slot;
 pub mod nvme;
+pub mod window;
+pub mod capability;
+pub mod interrupt;
+pub mod driver;
+
+use alloc::vec::Vec;
+use spin::Mutex;
 
 use crate::println;
 
 pub static PCI_HOST: crate::SetOnce<PCIHost> = crate::SetOnce::new(PCIHost::null());
 
+/// Every function found while enumerating the host's buses
+pub static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
 /// # Safety
 /// Only run ocne after the PCI_HOST variable is set
 pub unsafe fn init() {
@@ -17,55 +27,79 @@ pub unsafe fn init() {
 
     println!("PCI host found {:#x?}", pci_host);
 
-    for bus in 0..=255 {
-        for dev in 0..=255 {
-            let ecam_offset = pci_host.ecam_offset(bus, dev, 0);
+    for range in pci_host.ranges.iter().filter(|range| matches!(range.space, PciSpace::Memory32 | PciSpace::Memory64)) {
+        window::map(range);
+    }
 
-            let new_slot = pci_host.ecam_region.start.add(ecam_offset) as *mut slot::Slot;
+    let mut windows = window::Windows::new(&pci_host.ranges);
+    let mut devices = pci_host.enumerate();
 
-            let dev_id = (*new_slot).dev_id.read();
+    for device in devices.iter_mut() {
+        device.bars = windows.assign(device.slot());
+    }
 
-            if dev_id != 0xffff {
-                println!("Device type: {:?}", (*new_slot).ident().dev_type());
+    for device in devices.iter() {
+        println!(
+            "PCI {:02x}:{:02x}.{} {:04x}:{:04x} {:?}", 
+            device.bus, 
+            device.dev, 
+            device.func, 
+            device.ident.vendor_id, 
+            device.ident.device_id, 
+            device.ident.dev_type()
+        );
+    }
 
-                let bar_type = (*new_slot).bars.bar_kind(0);
-                let bar_val = match bar_type {
-                    slot::BarKind::Bits32 => (*new_slot).bars.read_u32(0) as u64,
-                    slot::BarKind::Bits64 => (*new_slot).bars.read_u64(0),
-                    _ => panic!()
-                };
-                
-                let bar_virt = bar_val + crate::memory::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
-                match (*new_slot).ident().dev_type() {
-                    slot::DeviceType::Nvme => {
-                        use crate::memory::{vmm, self};
-                        vmm::map(
-                            vmm::current_table().cast_mut(), 
-                            memory::VirtualAddress(bar_virt), 
-                            memory::PhysicalAddress(bar_val), 
-                            vmm::PageLevel::from_usize(vmm::LEVELS.load(core::sync::atomic::Ordering::Relaxed) as usize), 
-                            vmm::PageLevel::Level3, 
-                            &mut crate::memory::pmm::REGION_LIST.lock(), 
-                            vmm::PageFlags::READ | vmm::PageFlags::WRITE
-                        );
-
-                        let bar_virt = bar_virt as *mut nvme::controller_raw::RawController;
-
-                        nvme::init(bar_virt);
-                    },
-                    dev => println!("Unprepared to handle device type {:?}", dev)
-                }
-            } else {
-                break;
-            }
-        }
+    *DEVICES.lock() = devices.clone();
+
+    for device in devices {
+        attach(pci_host, &device);
+    }
+}
+
+/// Returns every enumerated function matching `filter`
+pub fn find_devices(filter: impl Fn(&PciDevice) -> bool) -> Vec<PciDevice> {
+    DEVICES.lock().iter().filter(|device| filter(device)).copied().collect()
+}
+
+/// Hands the function to the first driver that wants it
+unsafe fn attach(pci_host: &PCIHost, device: &PciDevice) {
+    let Some(driver) = driver::find(device) else {
+        println!("PCI {:02x}:{:02x}.{} has no driver", device.bus, device.dev, device.func);
+        return;
+    };
+
+    match driver.attach(pci_host, device) {
+        Ok(()) => println!("PCI {:02x}:{:02x}.{} attached to {}", device.bus, device.dev, device.func, driver.name()),
+        Err(e) => println!("PCI {:02x}:{:02x}.{} failed to attach to {}: {:?}", device.bus, device.dev, device.func, driver.name(), e),
+    }
+}
+
+/// A single function on the bus
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+    pub ident: slot::DeviceIdent,
+    /// Memory BARs with the addresses they were assigned, indexed by BAR number
+    pub bars: [Option<slot::Bar>; 6],
+    slot: *mut slot::Slot,
+}
+
+unsafe impl Send for PciDevice {}
+
+impl PciDevice {
+    pub fn slot(&self) -> &'static slot::Slot {
+        unsafe { &*self.slot }
     }
 }
 
 #[derive(Debug)]
 pub struct PCIHost {
-    pub interrupt_map_mask: usize,
-    pub interrupt_map: usize,
+    pub interrupt_map_mask: InterruptMapMask,
+    pub interrupt_map: Vec<InterruptMapEntry>,
+    pub ranges: Vec<PciRange>,
     pub ecam_region: EcamRegion,
     pub bus_range: core::ops::RangeInclusive<u8>,
 }
@@ -73,8 +107,9 @@ pub struct PCIHost {
 impl PCIHost {
     pub const fn null() -> Self {
         Self { 
-            interrupt_map_mask: 0, 
-            interrupt_map: 0, 
+            interrupt_map_mask: InterruptMapMask { addr_hi: 0, pin: 0 }, 
+            interrupt_map: Vec::new(), 
+            ranges: Vec::new(),
             ecam_region: EcamRegion { 
                 start: core::ptr::null_mut(), 
                 size: 0
@@ -83,11 +118,216 @@ impl PCIHost {
         }
     }
 
+    /// Reads a `pci-host-ecam-generic` node
+    pub fn from_fdt(fdt: &fdt::Fdt, node: &fdt::node::FdtNode) -> Self {
+        assert!(node.property("device_type").and_then(|prop| prop.as_str()) == Some("pci"), "Not PCI bus");
+
+        let ecam = node.reg().unwrap().next().unwrap();
+
+        let bus_range = node.property("bus-range").map(|prop| cells(prop.value));
+        let bus_range = match bus_range.as_deref() {
+            Some([start, end]) => (*start as u8)..=(*end as u8),
+            _ => 0..=255,
+        };
+
+        // Both `ranges` and `interrupt-map` use the three cell PCI address format for the child bus
+        let parent_address_cells = parent_address_cells(fdt);
+
+        let mut ranges = Vec::new();
+        if let Some(prop) = node.property("ranges") {
+            let cells = cells(prop.value);
+
+            for entry in cells.chunks_exact(3 + parent_address_cells + 2) {
+                let space = match (entry[0] >> 24) & 0x3 {
+                    0 => PciSpace::Config,
+                    1 => PciSpace::Io,
+                    2 => PciSpace::Memory32,
+                    _ => PciSpace::Memory64,
+                };
+
+                ranges.push(PciRange {
+                    space,
+                    prefetchable: entry[0] & (1 << 30) != 0,
+                    pci_addr: join_cells(&entry[1..3]),
+                    cpu_addr: join_cells(&entry[3..3 + parent_address_cells]),
+                    size: join_cells(&entry[3 + parent_address_cells..]),
+                });
+            }
+        }
+
+        let interrupt_map_mask = match node.property("interrupt-map-mask").map(|prop| cells(prop.value)).as_deref() {
+            Some([addr_hi, _, _, pin]) => InterruptMapMask { addr_hi: *addr_hi, pin: *pin },
+            _ => InterruptMapMask { addr_hi: !0, pin: !0 },
+        };
+
+        let mut interrupt_map = Vec::new();
+        if let Some(prop) = node.property("interrupt-map") {
+            let cells = cells(prop.value);
+            let mut rest = &cells[..];
+
+            // Entries vary in length depending on the interrupt controller they point at
+            while rest.len() >= 5 {
+                let phandle = rest[4];
+                let parent = fdt.find_phandle(phandle);
+
+                let address_cells = parent.as_ref()
+                    .and_then(|parent| parent.property("#address-cells"))
+                    .and_then(|prop| prop.as_usize())
+                    .unwrap_or(0);
+                let interrupt_cells = parent.as_ref()
+                    .and_then(|parent| parent.property("#interrupt-cells"))
+                    .and_then(|prop| prop.as_usize())
+                    .unwrap_or(1);
+
+                let len = 5 + address_cells + interrupt_cells;
+                if rest.len() < len {
+                    break;
+                }
+
+                interrupt_map.push(InterruptMapEntry {
+                    addr_hi: rest[0],
+                    pin: rest[3],
+                    parent: phandle,
+                    irq: rest[5 + address_cells] as usize,
+                });
+
+                rest = &rest[len..];
+            }
+        }
+
+        Self {
+            interrupt_map_mask,
+            interrupt_map,
+            ranges,
+            ecam_region: EcamRegion {
+                start: unsafe { ecam.starting_address.add(crate::IO_OFFSET as usize) as *mut u8 },
+                size: ecam.size.unwrap(),
+            },
+            bus_range,
+        }
+    }
+
+    /// Returns the PLIC interrupt a function's legacy interrupt pin is routed to
+    pub fn intx_irq(&self, bus: u8, dev: u8, func: u8, pin: u8) -> Option<usize> {
+        if pin == 0 {
+            return None;
+        }
+
+        let addr_hi = ((bus as u32) << 16) | ((dev as u32) << 11) | ((func as u32) << 8);
+        let addr_hi = addr_hi & self.interrupt_map_mask.addr_hi;
+        let pin = pin as u32 & self.interrupt_map_mask.pin;
+
+        self.interrupt_map.iter()
+            .find(|entry| entry.addr_hi == addr_hi && entry.pin == pin)
+            .map(|entry| entry.irq)
+    }
+
     pub fn ecam_offset(&self, bus: u8, dev: u8, func: u8) -> usize {
         (((bus - self.bus_range.start()) as usize) << 20)
         | ((dev as usize) << 15)
         | ((func as usize) << 12)
     }
+
+    fn slot(&self, bus: u8, dev: u8, func: u8) -> *mut slot::Slot {
+        unsafe { self.ecam_region.start.add(self.ecam_offset(bus, dev, func)) as *mut slot::Slot }
+    }
+
+    /// Walks every bus, device, and function the ECAM region covers
+    pub fn enumerate(&self) -> Vec<PciDevice> {
+        let mut devices = Vec::new();
+
+        for bus in self.bus_range.clone() {
+            // Don't walk off the end of the ECAM region if the bus range claims more than it covers
+            if ((bus - self.bus_range.start()) as usize) << 20 >= self.ecam_region.size {
+                break;
+            }
+
+            for dev in 0..32 {
+                for func in 0..8 {
+                    let slot = self.slot(bus, dev, func);
+
+                    // SAFETY: The ECAM region is mapped, and every function has a vendor ID register
+                    let slot_ref = unsafe { &*slot };
+
+                    if slot_ref.vendor_id.read() == 0xffff {
+                        // Functions other than 0 are allowed to be missing on multifunction devices
+                        match func {
+                            0 => break,
+                            _ => continue,
+                        }
+                    }
+
+                    devices.push(PciDevice {
+                        bus,
+                        dev,
+                        func,
+                        ident: slot_ref.ident(),
+                        bars: [None; 6],
+                        slot,
+                    });
+
+                    if func == 0 && !slot_ref.head_type.is_multifunction() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        devices
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptMapMask {
+    pub addr_hi: u32,
+    pub pin: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptMapEntry {
+    /// High cell of the child unit address, holding the bus, device, and function
+    pub addr_hi: u32,
+    pub pin: u32,
+    /// Phandle of the interrupt controller
+    pub parent: u32,
+    pub irq: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciSpace {
+    Config,
+    Io,
+    Memory32,
+    Memory64,
+}
+
+/// A window translating between PCI and CPU addresses
+#[derive(Debug, Clone, Copy)]
+pub struct PciRange {
+    pub space: PciSpace,
+    pub prefetchable: bool,
+    pub pci_addr: u64,
+    pub cpu_addr: u64,
+    pub size: u64,
+}
+
+fn cells(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4)
+        .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+        .collect()
+}
+
+fn join_cells(cells: &[u32]) -> u64 {
+    cells.iter().fold(0, |acc, cell| (acc << 32) | *cell as u64)
+}
+
+/// The host bridge sits under `/soc` on the machines we run on, so its parent bus is described there
+fn parent_address_cells(fdt: &fdt::Fdt) -> usize {
+    fdt.find_node("/soc")
+        .and_then(|soc| soc.property("#address-cells"))
+        .or_else(|| fdt.find_node("/").and_then(|root| root.property("#address-cells")))
+        .and_then(|prop| prop.as_usize())
+        .unwrap_or(2)
 }
 
 #[derive(Debug)]
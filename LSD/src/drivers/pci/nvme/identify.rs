@@ -0,0 +1,136 @@
+use alloc::string::String;
+
+/// Controller or Namespace Structure values for the Identify command
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum Cns {
+    Namespace = 0x00,
+    Controller = 0x01,
+    ActiveNamespaces = 0x02,
+}
+
+/// The fields we care about from the Identify Controller data structure
+#[derive(Debug)]
+pub struct ControllerInfo {
+    pub vendor_id: u16,
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+
+    /// Maximum Data Transfer Size, in units of the minimum page size as a power of two.
+    /// Zero means there is no limit.
+    pub mdts: u8,
+
+    /// Number of Namespaces
+    pub namespaces: u32,
+}
+
+impl ControllerInfo {
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            vendor_id: u16::from_le_bytes([data[0], data[1]]),
+            serial: ascii_field(&data[4..24]),
+            model: ascii_field(&data[24..64]),
+            firmware: ascii_field(&data[64..72]),
+            mdts: data[77],
+            namespaces: u32::from_le_bytes([data[516], data[517], data[518], data[519]]),
+        }
+    }
+}
+
+/// The fields we care about from the Identify Namespace data structure
+#[derive(Debug, Clone, Copy)]
+pub struct NamespaceInfo {
+    pub id: u32,
+
+    /// Namespace Size, in logical blocks
+    pub size: u64,
+
+    /// Namespace Capacity, in logical blocks
+    pub capacity: u64,
+
+    /// Size of a logical block in bytes, for the currently formatted LBA format
+    pub lba_size: usize,
+}
+
+impl NamespaceInfo {
+    /// Returns `None` if the block size in use is one we can't work with
+    pub fn parse(id: u32, data: &[u8]) -> Option<Self> {
+        let size = u64::from_le_bytes(data[0..8].try_into().unwrap());
+        let capacity = u64::from_le_bytes(data[8..16].try_into().unwrap());
+
+        // The low nibble of FLBAS selects the format in use out of the LBA Format list at byte 128
+        let format = (data[26] & 0xf) as usize;
+        let lbaf = 128 + format * 4;
+        let lbads = data[lbaf + 2];
+
+        // LBADS is a power of two, anything under 512 bytes isn't supported
+        if lbads < 9 {
+            return None;
+        }
+
+        Some(Self {
+            id,
+            size,
+            capacity,
+            lba_size: 1usize.checked_shl(lbads as u32)?,
+        })
+    }
+}
+
+fn ascii_field(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|byte| *byte as char)
+        .collect::<String>()
+        .trim_end()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespace(size: u64, capacity: u64, flbas: u8, formats: &[u8]) -> [u8; 4096] {
+        let mut data = [0; 4096];
+
+        data[0..8].copy_from_slice(&size.to_le_bytes());
+        data[8..16].copy_from_slice(&capacity.to_le_bytes());
+        data[26] = flbas;
+
+        for (index, lbads) in formats.iter().enumerate() {
+            data[128 + index * 4 + 2] = *lbads;
+        }
+
+        data
+    }
+
+    #[test]
+    fn parses_the_format_in_use() {
+        let data = namespace(0x1000, 0x800, 1, &[9, 12]);
+        let info = NamespaceInfo::parse(1, &data).unwrap();
+
+        assert_eq!(info.id, 1);
+        assert_eq!(info.size, 0x1000);
+        assert_eq!(info.capacity, 0x800);
+        assert_eq!(info.lba_size, 4096);
+    }
+
+    #[test]
+    fn ignores_the_high_bits_of_flbas() {
+        let data = namespace(1, 1, 0x10, &[9]);
+
+        assert_eq!(NamespaceInfo::parse(1, &data).unwrap().lba_size, 512);
+    }
+
+    #[test]
+    fn rejects_blocks_under_512_bytes() {
+        assert!(NamespaceInfo::parse(1, &namespace(1, 1, 0, &[0])).is_none());
+        assert!(NamespaceInfo::parse(1, &namespace(1, 1, 0, &[8])).is_none());
+    }
+
+    #[test]
+    fn rejects_block_sizes_that_overflow() {
+        assert!(NamespaceInfo::parse(1, &namespace(1, 1, 0, &[64])).is_none());
+        assert!(NamespaceInfo::parse(1, &namespace(1, 1, 0, &[255])).is_none());
+    }
+}
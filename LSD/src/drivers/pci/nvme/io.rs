@@ -0,0 +1,156 @@
+use alloc::vec::Vec;
+
+use super::{CommandStatus, CONTROLLERS, IoOpcode, NvmeError, queue::CompletionQueueEntry};
+use crate::{
+    drivers::block,
+    memory::{DmaRegion, PhysicalAddress},
+    traps::{task, TrapFrame},
+};
+
+/// A transfer made on behalf of a user thread, which is parked until the controller completes it
+pub struct PendingIo {
+    task_id: usize,
+    thread_id: usize,
+    write: bool,
+
+    /// Index of the controller's bounce buffer the transfer goes through
+    pub(super) bounce: usize,
+
+    /// Physical pieces of the user's buffer, in order
+    segments: Vec<(PhysicalAddress, usize)>,
+}
+
+impl PendingIo {
+    /// Copies read data out of `bounce` to the user's buffer and wakes the thread waiting on it
+    pub(super) fn finish(self, entry: &CompletionQueueEntry, bounce: &DmaRegion<[u8]>) {
+        let result = match CommandStatus::from_completion(entry) {
+            None => 0,
+            Some(status) => NvmeError::Command(status).code(),
+        };
+
+        let mut lock = task::THREADS.lock();
+
+        // Processes are only freed with the lock held, so while the thread is still waiting its frames can't be.
+        // If it was removed since, its process may be gone and the data has nowhere to go.
+        let Some(thread) = lock.wake(self.task_id, self.thread_id, task::WaitSrc::Disk) else {
+            return;
+        };
+
+        if result == 0 && !self.write {
+            block::scatter(bounce, &self.segments);
+        }
+
+        thread.trap_frame.a0 = result;
+    }
+}
+
+/// Backs the disk read and write syscalls, `a2` holds the LBA, `a3` the block count, `a4` a pointer to the buffer,
+/// and `a5` its length
+///
+/// If the controller delivers interrupts the calling thread is parked until the transfer completes, otherwise
+/// the transfer is polled. Either way the result ends up in `a0`.
+pub fn user_request(trap_frame: &mut TrapFrame, write: bool) {
+    match submit_user_request(trap_frame, write) {
+        Ok(true) => task::switch_to_next(trap_frame),
+        Ok(false) => trap_frame.a0 = 0,
+        Err(e) => trap_frame.a0 = e.code(),
+    }
+}
+
+/// Returns whether the calling thread was parked to wait on the request
+fn submit_user_request(trap_frame: &TrapFrame, write: bool) -> Result<bool, NvmeError> {
+    let lba = trap_frame.a2 as u64;
+    let count = u16::try_from(trap_frame.a3).map_err(|_| NvmeError::TransferTooLarge)?;
+    let buffer = trap_frame.a4;
+    let len = trap_frame.a5;
+
+    let mut controllers = CONTROLLERS.lock();
+    let controller = controllers.first_mut().ok_or(NvmeError::NoController)?;
+
+    if count == 0 {
+        return Ok(false);
+    }
+
+    let bytes = controller.transfer_len(lba, count, len)?;
+
+    if bytes > controller.bounce.buffer_len() {
+        return Err(NvmeError::TransferTooLarge);
+    }
+
+    let segments = block::user_segments(buffer, bytes, !write).map_err(|_| NvmeError::BadBuffer)?;
+
+    let bounce = controller.bounce.take().ok_or(NvmeError::Busy)?;
+
+    if write {
+        block::gather(&segments, controller.bounce.get_mut(bounce));
+    }
+
+    let opcode = match write {
+        true => IoOpcode::Write,
+        false => IoOpcode::Read,
+    };
+
+    let io_queue = controller.io_queue.id();
+    let cid = match controller.io_queue.reserve() {
+        Ok(cid) => cid,
+        Err(e) => {
+            controller.bounce.release(bounce);
+            return Err(e);
+        },
+    };
+
+    let prps = controller.prp_lists.describe(cid, controller.bounce.get(bounce).physical_address(), bytes);
+    let cmd = controller.transfer_cmd(opcode, lba, count, &prps);
+
+    if controller.irq.is_none() {
+        let result = controller.execute(io_queue, cid, cmd);
+
+        if result.is_ok() && !write {
+            block::scatter(controller.bounce.get(bounce), &segments);
+        }
+
+        // A command that timed out may still be transferring, so its buffer can't be handed out again
+        if !matches!(result, Err(NvmeError::Timeout)) {
+            controller.bounce.release(bounce);
+        }
+
+        return result.map(|_| false);
+    }
+
+    // Park the thread before the controller can possibly complete the request, so a completion handled on another
+    // hart finds its frame saved
+    let (task_id, thread_id) = task::park(trap_frame, task::WaitSrc::Disk);
+
+    controller.pending.insert(cid, PendingIo {
+        task_id,
+        thread_id,
+        write,
+        bounce,
+        segments,
+    });
+
+    controller.submit(io_queue, cid, cmd);
+
+    Ok(true)
+}
+
+/// Interrupt handler for every way a controller's interrupt can be delivered
+pub fn handle_int(id: usize) {
+    let mut controllers = CONTROLLERS.lock();
+
+    for controller in controllers.iter_mut().filter(|controller| controller.irq.map(|irq| irq.id()) == Some(id)) {
+        let can_mask = controller.can_mask();
+
+        // Mask the vector while draining, so the pin deasserts once the queue is empty
+        if can_mask {
+            controller.raw().intms.write(1);
+        }
+
+        let io_queue = controller.io_queue.id();
+        controller.process_completions(io_queue);
+
+        if can_mask {
+            controller.raw().intmc.write(1);
+        }
+    }
+}
@@ -1,34 +1,129 @@
 pub mod queue;
+pub mod identify;
+pub mod prp;
+pub mod io;
 pub mod controller_raw;
 
 use core::time::Duration;
 
+use alloc::{collections::BTreeMap, vec::Vec};
 use libsa::endian::u32_le;
+use spin::Mutex;
 
 use controller_raw::RawController;
+use identify::{Cns, ControllerInfo, NamespaceInfo};
+use prp::Prps;
+use queue::{QueuePair, SubmissionQueueEntry, CompletionQueueEntry};
+use super::{driver::{AttachError, DeviceMatch, PciDriver}, interrupt::Interrupt, PCIHost, PciDevice};
 
 use crate::{
+    drivers::block::{BlockDevice, BlockError, BouncePool, BOUNCE_BUFFERS, BOUNCE_LEN},
     println, 
     size_of,
-    memory::vmm::PAGE_SHIFT,
+    memory::{vmm::PAGE_SHIFT, DmaRegion, PhysicalAddress},
     volatile::Volatile
 };
 
+pub static CONTROLLERS: Mutex<Vec<Controller>> = Mutex::new(Vec::new());
+
+const ADMIN_QUEUE_LEN: usize = 32;
+const IO_QUEUE_LEN: usize = 64;
+
+/// Admin command opcodes
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum AdminOpcode {
+    CreateIoSubmissionQueue = 0x01,
+    CreateIoCompletionQueue = 0x05,
+    Identify = 0x06,
+}
+
+/// NVM command set opcodes
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum IoOpcode {
+    Flush = 0x00,
+    Write = 0x01,
+    Read = 0x02,
+}
+
+/// A page of PRP list for every command identifier of a queue, reused by whichever command has that identifier
+pub struct PrpLists {
+    lists: Vec<DmaRegion<[u64]>>,
+}
+
+impl PrpLists {
+    pub fn new(count: usize) -> Self {
+        Self {
+            lists: (0..count).map(|_| unsafe { DmaRegion::new_raw(prp::ENTRIES_PER_LIST, true) }).collect(),
+        }
+    }
+
+    /// Describes a transfer for the command with identifier `cid`, filling in its list if it needs one
+    pub fn describe(&mut self, cid: u16, phys: PhysicalAddress, len: usize) -> Prps {
+        let list = &mut self.lists[cid as usize];
+        let list_phys = list.physical_address().0;
+
+        Prps::new(phys, len, list.get_mut(), list_phys)
+    }
+}
+
 /// An NVM Express Host Controller
 pub struct Controller {
     caps: Capabilities,
     reg_base: *mut u32_le,
-    doorbell_base: *mut Volatile<u32_le>,
+    doorbell_base: *mut Volatile<u32>,
     admin_queue: QueuePair,
     io_queue: QueuePair,
+    info: Option<ControllerInfo>,
+    namespace: Option<NamespaceInfo>,
+
+    /// Where the controller's interrupt is delivered, if completions are interrupt driven
+    irq: Option<Interrupt>,
+
+    /// Requests made by user tasks, waiting on their completion, indexed by command identifier
+    pending: BTreeMap<u16, io::PendingIo>,
+
+    /// Completions drained from a queue before whoever is polling for them got to them
+    finished: BTreeMap<(u16, u16), CompletionQueueEntry>,
+
+    /// PRP lists for transfers on the I/O queue, by command identifier
+    prp_lists: PrpLists,
+    /// Where Identify commands put their data structure, parsed before the next one is run
+    identify_data: DmaRegion<[u8]>,
+    /// Buffers user requests are staged in
+    bounce: BouncePool,
 }
 
+unsafe impl Send for Controller {}
+
 impl Controller {
     pub fn status(&self) -> Status {
         let bits = unsafe { self.reg_base.byte_add(0x1c).read_volatile().get() };
         Status::new(bits)
     }
 
+    pub fn info(&self) -> Option<&ControllerInfo> {
+        self.info.as_ref()
+    }
+
+    pub fn namespace(&self) -> Option<&NamespaceInfo> {
+        self.namespace.as_ref()
+    }
+
+    pub fn irq(&self) -> Option<Interrupt> {
+        self.irq
+    }
+
+    /// INTMS and INTMC only apply to pin based and MSI interrupts
+    fn can_mask(&self) -> bool {
+        !matches!(self.irq, Some(Interrupt::MsiX(_)))
+    }
+
+    fn raw(&self) -> &RawController {
+        unsafe { &*self.reg_base.cast::<RawController>() }
+    }
+
     fn wait_ready(&mut self) -> Option<()> {
         let timeout = crate::timing::Timeout::start(self.caps.timeout());
 
@@ -49,13 +144,286 @@ impl Controller {
         }
     }
 
-    fn admin_cmd(&mut self) {
+    /// Returns the doorbell at the given index, even indices are submission queue tail doorbells,
+    /// odd indices are completion queue head doorbells
+    fn doorbell(&self, index: usize) -> &Volatile<u32> {
+        unsafe { &*self.doorbell_base.byte_add(index * self.caps.doorbell_stride()) }
+    }
+
+    fn queue_mut(&mut self, qid: u16) -> &mut QueuePair {
+        match qid {
+            0 => &mut self.admin_queue,
+            _ => &mut self.io_queue,
+        }
+    }
+
+    /// Places a command on the given queue under a command identifier reserved from it, and rings its doorbell
+    fn submit(&mut self, qid: u16, cid: u16, cmd: SubmissionQueueEntry) {
+        // A late completion of a command `execute` gave up on could have been left under the same identifier
+        self.finished.remove(&(qid, cid));
+
+        let tail = self.queue_mut(qid).submit(cid, cmd);
+
+        unsafe {
+            // Make sure the entry is visible before the controller is told about it
+            core::arch::asm!("fence");
+        }
+        self.doorbell(2 * qid as usize).write(tail as u32);
+    }
+
+    /// Drains every completion posted to the given queue
+    ///
+    /// Completions belonging to a user request finish that request, the rest are kept for whoever polls for them.
+    fn process_completions(&mut self, qid: u16) {
+        while let Some((entry, head)) = self.queue_mut(qid).complete() {
+            self.doorbell(2 * qid as usize + 1).write(head as u32);
+
+            let pending = match qid == self.io_queue.id() {
+                true => self.pending.remove(&entry.cid),
+                false => None,
+            };
+
+            match pending {
+                Some(pending) => {
+                    let bounce = pending.bounce;
+
+                    pending.finish(&entry, self.bounce.get(bounce));
+                    self.bounce.release(bounce);
+                },
+                None => {
+                    self.finished.insert((qid, entry.cid), entry);
+                },
+            }
+        }
+    }
+
+    /// Submits a command to the given queue under a command identifier reserved from it, and polls for its
+    /// completion
+    fn execute(&mut self, qid: u16, cid: u16, cmd: SubmissionQueueEntry) -> Result<CompletionQueueEntry, NvmeError> {
+        // The interrupt handler drains the same queue, keep it from running while we're polling
+        crate::traps::without_interrupts(|| {
+            self.submit(qid, cid, cmd);
+
+            let timeout = crate::timing::Timeout::start(self.caps.timeout());
+
+            loop {
+                self.process_completions(qid);
+
+                if let Some(entry) = self.finished.remove(&(qid, cid)) {
+                    return match CommandStatus::from_completion(&entry) {
+                        None => Ok(entry),
+                        Some(status) => Err(NvmeError::Command(status)),
+                    };
+                }
+
+                if timeout.expired() {
+                    println!("NVMe command 0x{:x} on queue {} timed out", cmd.opcode(), qid);
+                    return Err(NvmeError::Timeout);
+                }
+
+                core::hint::spin_loop();
+            }
+        })
+    }
+
+    fn admin_cmd(&mut self, cmd: SubmissionQueueEntry) -> Result<CompletionQueueEntry, NvmeError> {
+        let cid = self.admin_queue.reserve()?;
+        self.execute(0, cid, cmd)
+    }
+
+    fn io_cmd(&mut self, cmd: SubmissionQueueEntry) -> Result<CompletionQueueEntry, NvmeError> {
+        let cid = self.io_queue.reserve()?;
+        self.execute(self.io_queue.id(), cid, cmd)
+    }
+
+    /// Runs an Identify command, returning the 4KiB data structure it produced
+    fn identify(&mut self, cns: Cns, nsid: u32) -> Result<&[u8], NvmeError> {
+        let mut cmd = SubmissionQueueEntry::new(AdminOpcode::Identify as u8);
+        cmd.nsid = nsid;
+        cmd.prp1 = self.identify_data.physical_address().0;
+        cmd.cdw10 = cns as u32;
+
+        self.admin_cmd(cmd)?;
+
+        Ok(&self.identify_data[..])
+    }
+
+    pub fn identify_controller(&mut self) -> Result<ControllerInfo, NvmeError> {
+        let data = self.identify(Cns::Controller, 0)?;
+
+        Ok(ControllerInfo::parse(data))
+    }
+
+    /// Returns the first active namespace ID, if there is one
+    pub fn first_namespace(&mut self) -> Result<Option<u32>, NvmeError> {
+        let data = self.identify(Cns::ActiveNamespaces, 0)?;
+
+        Ok(
+            data.chunks_exact(4)
+                .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+                .find(|id| *id != 0)
+        )
+    }
+
+    pub fn identify_namespace(&mut self, nsid: u32) -> Result<NamespaceInfo, NvmeError> {
+        let data = self.identify(Cns::Namespace, nsid)?;
+
+        NamespaceInfo::parse(nsid, data).ok_or(NvmeError::Unsupported)
+    }
+
+    /// Creates the I/O completion queue, then the I/O submission queue that posts to it
+    fn create_io_queues(&mut self) -> Result<(), NvmeError> {
+        let qid = self.io_queue.id() as u32;
+        let qsize = (self.io_queue.size() as u32 - 1) << 16;
+
+        let mut cmd = SubmissionQueueEntry::new(AdminOpcode::CreateIoCompletionQueue as u8);
+        cmd.prp1 = self.io_queue.comq.addr();
+        cmd.cdw10 = qsize | qid;
+        // Physically Contiguous, and Interrupts Enabled on vector 0 if we have somewhere to deliver them
+        cmd.cdw11 = 1 | ((self.irq.is_some() as u32) << 1);
+        self.admin_cmd(cmd)?;
+
+        let mut cmd = SubmissionQueueEntry::new(AdminOpcode::CreateIoSubmissionQueue as u8);
+        cmd.prp1 = self.io_queue.subq.addr();
+        cmd.cdw10 = qsize | qid;
+        // Completion queue to post to, and Physically Contiguous
+        cmd.cdw11 = (qid << 16) | 1;
+        self.admin_cmd(cmd)?;
+
+        Ok(())
+    }
+
+    /// Returns the largest transfer a single command may perform, the controller's limit or what a single PRP
+    /// list can describe, whichever is smaller
+    pub fn max_transfer(&self) -> usize {
+        match self.info.as_ref().map_or(0, |info| info.mdts) {
+            0 => prp::MAX_TRANSFER,
+            // Anything past this is larger than a PRP list covers anyway, and would shift out of range
+            mdts => (self.caps.min_page_size() << mdts.min(20)).min(prp::MAX_TRANSFER),
+        }
+    }
+
+    /// Reads `count` blocks starting at `lba` into `buffer`
+    pub fn read_blocks(&mut self, lba: u64, count: u16, buffer: &mut DmaRegion<[u8]>) -> Result<(), NvmeError> {
+        self.transfer(IoOpcode::Read, lba, count, buffer.physical_address(), buffer.len())
+    }
+
+    /// Writes `count` blocks from `buffer` starting at `lba`
+    pub fn write_blocks(&mut self, lba: u64, count: u16, buffer: &DmaRegion<[u8]>) -> Result<(), NvmeError> {
+        self.transfer(IoOpcode::Write, lba, count, buffer.physical_address(), buffer.len())
+    }
+
+    /// Commits any data and metadata held in the controller's volatile write cache
+    pub fn flush(&mut self) -> Result<(), NvmeError> {
+        let namespace = self.namespace.ok_or(NvmeError::NoNamespace)?;
+
+        let mut cmd = SubmissionQueueEntry::new(IoOpcode::Flush as u8);
+        cmd.nsid = namespace.id;
+
+        self.io_cmd(cmd).map(|_| ())
+    }
+
+    /// Returns the number of bytes `count` blocks take up, making sure they can be transferred in one command
+    fn transfer_len(&self, lba: u64, count: u16, len: usize) -> Result<usize, NvmeError> {
+        let namespace = self.namespace.ok_or(NvmeError::NoNamespace)?;
+        let bytes = count as usize * namespace.lba_size;
+
+        if bytes > len {
+            return Err(NvmeError::BufferTooSmall);
+        }
+
+        match lba.checked_add(count as u64) {
+            Some(end) if end <= namespace.size => {},
+            _ => return Err(NvmeError::OutOfRange),
+        }
+
+        if bytes > self.max_transfer() {
+            return Err(NvmeError::TransferTooLarge);
+        }
+
+        Ok(bytes)
+    }
+
+    fn transfer_cmd(&self, opcode: IoOpcode, lba: u64, count: u16, prps: &Prps) -> SubmissionQueueEntry {
+        let mut cmd = SubmissionQueueEntry::new(opcode as u8);
+        // Callers check `transfer_len` first, so there is a namespace
+        cmd.nsid = self.namespace.map_or(0, |namespace| namespace.id);
+        cmd.prp1 = prps.prp1;
+        cmd.prp2 = prps.prp2;
+        cmd.cdw10 = lba as u32;
+        cmd.cdw11 = (lba >> 32) as u32;
+        // Number of Logical Blocks, zero based
+        cmd.cdw12 = count as u32 - 1;
+
+        cmd
+    }
+
+    fn transfer(&mut self, opcode: IoOpcode, lba: u64, count: u16, phys: PhysicalAddress, len: usize) -> Result<(), NvmeError> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        let bytes = self.transfer_len(lba, count, len)?;
+
+        let cid = self.io_queue.reserve()?;
+        let prps = self.prp_lists.describe(cid, phys, bytes);
+        let cmd = self.transfer_cmd(opcode, lba, count, &prps);
+
+        self.execute(self.io_queue.id(), cid, cmd).map(|_| ())
+    }
+}
+
+impl BlockDevice for Controller {
+    fn block_size(&self) -> usize {
+        self.namespace.map_or(0, |namespace| namespace.lba_size)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.namespace.map_or(0, |namespace| namespace.size)
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u16, buffer: &mut DmaRegion<[u8]>) -> Result<(), BlockError> {
+        Controller::read_blocks(self, lba, count, buffer).map_err(BlockError::from)
+    }
+
+    fn write_blocks(&mut self, lba: u64, count: u16, buffer: &DmaRegion<[u8]>) -> Result<(), BlockError> {
+        Controller::write_blocks(self, lba, count, buffer).map_err(BlockError::from)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Controller::flush(self).map_err(BlockError::from)
+    }
+}
+
+/// Binds NVM Express controllers found on the PCI bus
+pub struct NvmeDriver;
+
+impl PciDriver for NvmeDriver {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+
+    fn matches(&self) -> &'static [DeviceMatch] {
+        // Mass storage, Non-Volatile Memory controller, NVM Express
+        &[DeviceMatch::class(0x01, 0x08, 0x02)]
+    }
+
+    unsafe fn attach(&self, host: &PCIHost, device: &PciDevice) -> Result<(), AttachError> {
+        let bar = device.bars[0].ok_or(AttachError::MissingBar(0))?;
+        let irq = super::interrupt::enable(host, device, io::handle_int);
+
+        let controller = init(bar.virt().cast(), irq).map_err(AttachError::driver)?;
+        CONTROLLERS.lock().push(controller);
+
+        Ok(())
     }
 }
 
 /// # Safety
 /// Only call once per controller
-pub unsafe fn init(controller: *mut RawController) -> Controller {
+///
+/// `irq` is where the controller's interrupt was routed, if it is `None` completions are polled.
+pub unsafe fn init(controller: *mut RawController, irq: Option<Interrupt>) -> Result<Controller, NvmeError> {
     let caps = Capabilities::new((*controller).cap.read());
 
     println!(
@@ -67,13 +435,25 @@ pub unsafe fn init(controller: *mut RawController) -> Controller {
     println!("Max page size 0x{:x}", caps.max_page_size());
     println!("Min page size 0x{:x}", caps.min_page_size());
 
-    assert!(caps.min_page_size() <= crate::memory::vmm::PageSize::Small as usize, "Smallest page size is too small for controller");
-    assert!(caps.max_page_size() >= crate::memory::vmm::PageSize::Small as usize, "Smallest page size is too big for controller");
-    assert!(caps.contains(Capabilities::NVM_COMMAND_SET), "No command set available");
+    if caps.min_page_size() > crate::memory::vmm::PageSize::Small as usize
+        || caps.max_page_size() < crate::memory::vmm::PageSize::Small as usize
+        || !caps.contains(Capabilities::NVM_COMMAND_SET)
+    {
+        return Err(NvmeError::Unsupported);
+    }
 
-    // Disable so we can reconfigure it
+    // Disable so we can reconfigure it, and wait for the controller to notice
     (*controller).cc.write(0);
 
+    let timeout = crate::timing::Timeout::start(caps.timeout());
+    while (*controller).csts.read() & Status::RDY.bits() != 0 {
+        if timeout.expired() {
+            return Err(NvmeError::Timeout);
+        }
+
+        core::hint::spin_loop();
+    }
+
     // Options set with value 0:
     //  - Controller Ready Independent of Media: no
     //      CSTS.RDY will not be set until the connected devices are also ready.
@@ -88,13 +468,14 @@ pub unsafe fn init(controller: *mut RawController) -> Controller {
     conf |= size_of!(SubmissionQueueEntry).ilog2() << 16;
     conf |= size_of!(CompletionQueueEntry).ilog2() << 20;
 
-    // Allocate the queues
-    let admin_queue = QueuePair::new();
-    let io_queue = QueuePair::new();
+    // Allocate the queues, keeping them within what the controller supports
+    let admin_queue = QueuePair::new(0, ADMIN_QUEUE_LEN.min(caps.max_queue_entries()));
+    let io_queue_len = IO_QUEUE_LEN.min(caps.max_queue_entries());
+    let io_queue = QueuePair::new(1, io_queue_len);
 
-    (*controller).aqa.write((admin_queue.len() - 1) as u32 * 0x00010001);
-    (*controller).asq.write(admin_queue.subq.addr() as u64);
-    (*controller).acq.write(admin_queue.comq.addr() as u64);
+    (*controller).aqa.write((admin_queue.size() - 1) as u32 * 0x00010001);
+    (*controller).asq.write(admin_queue.subq.addr());
+    (*controller).acq.write(admin_queue.comq.addr());
 
     let mut ctlr = Controller {
         caps,
@@ -102,22 +483,158 @@ pub unsafe fn init(controller: *mut RawController) -> Controller {
         doorbell_base: unsafe { controller.byte_add(0x1000).cast() },
         admin_queue,
         io_queue,
+        info: None,
+        namespace: None,
+        irq,
+        pending: BTreeMap::new(),
+        finished: BTreeMap::new(),
+        prp_lists: PrpLists::new(io_queue_len - 1),
+        identify_data: DmaRegion::new_raw(0x1000, true),
+        bounce: BouncePool::new(BOUNCE_BUFFERS, BOUNCE_LEN),
     };
 
     // Enable the controller and wait for it to be ready.
     conf |= 0x1;
     (*controller).cc.write(conf);
-    ctlr.wait_ready().unwrap();
+    ctlr.wait_ready().ok_or(NvmeError::Timeout)?;
 
     // Mask all interrupts.
-    (*controller).intms.write(!0);
+    if ctlr.can_mask() {
+        (*controller).intms.write(!0);
+    }
 
     // Query the Identify command for the controller and the NVM command set
     // determine optimal block size
+    let info = ctlr.identify_controller()?;
+    println!("NVMe model {:?}, serial {:?}, {} namespace(s)", info.model, info.serial, info.namespaces);
+    ctlr.info = Some(info);
+
+    match ctlr.first_namespace()? {
+        Some(nsid) => {
+            let namespace = ctlr.identify_namespace(nsid)?;
+            println!(
+                "NVMe namespace {} has {} blocks of {} bytes", 
+                namespace.id, 
+                namespace.capacity, 
+                namespace.lba_size
+            );
+
+            ctlr.namespace = Some(namespace);
+        },
+        None => println!("NVMe controller has no active namespaces"),
+    }
+
+    ctlr.create_io_queues()?;
+    println!("NVMe I/O queues created");
 
-    let ioq_len = ctlr.io_queue.len() as u32 - 1;
-    let comq_addr = ctlr.io_queue.comq.addr() as u64;
-    todo!("Load queues, return ctlr")
+    if irq.is_some() && ctlr.can_mask() {
+        // Only the I/O completion queue was created with interrupts enabled, unmask its vector
+        ctlr.raw().intmc.write(1);
+    }
+
+    Ok(ctlr)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeError {
+    /// The controller has no active namespace to perform I/O on
+    NoNamespace,
+    /// The buffer is smaller than the blocks being transferred
+    BufferTooSmall,
+    /// The blocks requested extend past the end of the namespace
+    OutOfRange,
+    /// The transfer is larger than the controller's Maximum Data Transfer Size
+    TransferTooLarge,
+    /// The controller did not respond in the time it reported in `CAP.TO`
+    Timeout,
+    /// The controller completed the command with an error status
+    Command(CommandStatus),
+    /// There is no controller to perform the request
+    NoController,
+    /// The buffer given isn't mapped in the requesting task
+    BadBuffer,
+    /// The controller doesn't support our page size or the NVM command set, or the namespace's block size
+    Unsupported,
+    /// The queue has no room for another command
+    Busy,
+}
+
+impl NvmeError {
+    /// The value handed back to userspace for this error, success is reported as 0
+    ///
+    /// These line up with the codes of [`BlockError`], 10 is a read only disk which NVMe doesn't report.
+    pub fn code(&self) -> usize {
+        match self {
+            Self::NoNamespace => 1,
+            Self::BufferTooSmall => 2,
+            Self::OutOfRange => 3,
+            Self::TransferTooLarge => 4,
+            Self::Timeout => 5,
+            Self::Command(_) => 6,
+            Self::NoController => 7,
+            Self::BadBuffer => 8,
+            Self::Unsupported => 9,
+            Self::Busy => 11,
+        }
+    }
+}
+
+/// The status a command completed with, when it did not complete successfully
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandStatus {
+    InvalidOpcode,
+    InvalidField,
+    CommandIdConflict,
+    DataTransferError,
+    AbortedPowerLoss,
+    InternalError,
+    AbortRequested,
+    InvalidNamespace,
+    CommandSequenceError,
+    InvalidPrpOffset,
+    LbaOutOfRange,
+    CapacityExceeded,
+    NamespaceNotReady,
+    WriteFault,
+    UnrecoveredReadError,
+    AccessDenied,
+    /// A status we don't have a name for, with its Status Code Type and Status Code
+    Other { status_type: u8, code: u8 },
+}
+
+impl CommandStatus {
+    /// Returns the error status of a completion, or `None` if it completed successfully
+    pub fn from_completion(entry: &CompletionQueueEntry) -> Option<Self> {
+        if entry.is_success() {
+            return None;
+        }
+
+        let status = match (entry.status_type(), entry.status_code()) {
+            // Generic Command Status
+            (0x0, 0x01) => Self::InvalidOpcode,
+            (0x0, 0x02) => Self::InvalidField,
+            (0x0, 0x03) => Self::CommandIdConflict,
+            (0x0, 0x04) => Self::DataTransferError,
+            (0x0, 0x05) => Self::AbortedPowerLoss,
+            (0x0, 0x06) => Self::InternalError,
+            (0x0, 0x07) => Self::AbortRequested,
+            (0x0, 0x0b) => Self::InvalidNamespace,
+            (0x0, 0x0c) => Self::CommandSequenceError,
+            (0x0, 0x13) => Self::InvalidPrpOffset,
+            (0x0, 0x80) => Self::LbaOutOfRange,
+            (0x0, 0x81) => Self::CapacityExceeded,
+            (0x0, 0x82) => Self::NamespaceNotReady,
+
+            // Media and Data Integrity Errors
+            (0x2, 0x80) => Self::WriteFault,
+            (0x2, 0x81) => Self::UnrecoveredReadError,
+            (0x2, 0x86) => Self::AccessDenied,
+
+            (status_type, code) => Self::Other { status_type, code },
+        };
+
+        Some(status)
+    }
 }
 
 bitflags::bitflags! {
@@ -149,6 +666,11 @@ impl Capabilities {
         Duration::from_millis(ms * 500)
     }
 
+    /// Returns the maximum number of entries an individual queue may hold
+    pub fn max_queue_entries(&self) -> usize {
+        (self.bits() & 0xffff) as usize + 1
+    }
+
     pub fn doorbell_stride(&self) -> usize {
         let dstrd = (self.bits() >> 32) & 0xf;
         4 << dstrd
@@ -0,0 +1,120 @@
+use crate::memory::{PhysicalAddress, vmm::PAGE_SIZE};
+
+/// Number of entries that fit in a single page of a PRP list
+pub const ENTRIES_PER_LIST: usize = PAGE_SIZE / core::mem::size_of::<u64>();
+
+/// Largest transfer a single page of PRP list can describe, wherever in a page the transfer starts
+pub const MAX_TRANSFER: usize = ENTRIES_PER_LIST * PAGE_SIZE;
+
+/// The Physical Region Page entries describing a single data transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prps {
+    pub prp1: u64,
+    pub prp2: u64,
+}
+
+impl Prps {
+    /// Describes `len` bytes of physically contiguous memory starting at `phys`
+    ///
+    /// Transfers spanning more than two pages have their remaining pages written to `list`, a PRP list at
+    /// `list_phys`, which must be left alone until the command using it completes. `len` can't be more than
+    /// `MAX_TRANSFER`.
+    pub fn new(phys: PhysicalAddress, len: usize, list: &mut [u64], list_phys: u64) -> Self {
+        let offset = phys.0 as usize % PAGE_SIZE;
+        let page_base = phys.0 - offset as u64;
+
+        // PRP1 may start anywhere in a page, every following entry must be page aligned
+        let first_len = PAGE_SIZE - offset;
+        let remaining = len.saturating_sub(first_len);
+        let pages = remaining.div_ceil(PAGE_SIZE);
+
+        assert!(pages <= list.len().min(ENTRIES_PER_LIST), "Transfer of 0x{:x} bytes needs more than one PRP list", len);
+
+        let page = |index: usize| page_base + (index * PAGE_SIZE) as u64;
+
+        let prp2 = match pages {
+            0 => 0,
+            1 => page(1),
+            _ => {
+                for (index, entry) in list.iter_mut().take(pages).enumerate() {
+                    *entry = page(index + 1);
+                }
+
+                list_phys
+            }
+        };
+
+        Self { prp1: phys.0, prp2 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST_PHYS: u64 = 0x9000_0000;
+
+    fn describe(phys: u64, len: usize) -> (Prps, [u64; ENTRIES_PER_LIST]) {
+        let mut list = [0; ENTRIES_PER_LIST];
+        let prps = Prps::new(PhysicalAddress(phys), len, &mut list, LIST_PHYS);
+
+        (prps, list)
+    }
+
+    #[test]
+    fn single_page() {
+        let (prps, _) = describe(0x1000, PAGE_SIZE);
+        assert_eq!(prps, Prps { prp1: 0x1000, prp2: 0 });
+
+        let (prps, _) = describe(0x1800, 0x800);
+        assert_eq!(prps, Prps { prp1: 0x1800, prp2: 0 });
+    }
+
+    #[test]
+    fn two_pages_point_prp2_at_the_second() {
+        let (prps, _) = describe(0x1000, 2 * PAGE_SIZE);
+        assert_eq!(prps, Prps { prp1: 0x1000, prp2: 0x2000 });
+
+        // Straddling a page boundary takes two pages however short the transfer is
+        let (prps, _) = describe(0x1ff0, 0x20);
+        assert_eq!(prps, Prps { prp1: 0x1ff0, prp2: 0x2000 });
+    }
+
+    #[test]
+    fn longer_transfers_use_the_list() {
+        let (prps, list) = describe(0x1000, 4 * PAGE_SIZE);
+
+        assert_eq!(prps, Prps { prp1: 0x1000, prp2: LIST_PHYS });
+        assert_eq!(list[..3], [0x2000, 0x3000, 0x4000]);
+        assert_eq!(list[3], 0);
+    }
+
+    #[test]
+    fn list_entries_are_page_aligned_after_an_offset_start() {
+        let (prps, list) = describe(0x1200, 3 * PAGE_SIZE);
+
+        assert_eq!(prps, Prps { prp1: 0x1200, prp2: LIST_PHYS });
+        assert_eq!(list[..3], [0x2000, 0x3000, 0x4000]);
+    }
+
+    #[test]
+    fn max_transfer_fits_wherever_it_starts() {
+        let (prps, list) = describe(0x1000, MAX_TRANSFER);
+
+        assert_eq!(prps.prp2, LIST_PHYS);
+        assert_eq!(list[ENTRIES_PER_LIST - 2], 0x1000 + ((ENTRIES_PER_LIST - 1) * PAGE_SIZE) as u64);
+        assert_eq!(list[ENTRIES_PER_LIST - 1], 0);
+
+        // Starting part way into a page needs every entry
+        let (prps, list) = describe(0x1200, MAX_TRANSFER);
+
+        assert_eq!(prps.prp2, LIST_PHYS);
+        assert_eq!(list[ENTRIES_PER_LIST - 1], 0x1000 + (ENTRIES_PER_LIST * PAGE_SIZE) as u64);
+    }
+
+    #[test]
+    #[should_panic]
+    fn refuses_more_than_one_list() {
+        describe(0x1200, MAX_TRANSFER + PAGE_SIZE);
+    }
+}
@@ -1,4 +1,247 @@
-bitfield::bitfield! {
-    pub struct SubmissionQueueEntry([u64; 8]);
-    
-}
\ No newline at end of file
+use alloc::collections::BTreeSet;
+
+use crate::memory::DmaRegion;
+
+use super::NvmeError;
+
+/// A 64 byte command submitted to a submission queue
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SubmissionQueueEntry {
+    /// Command Dword 0
+    ///
+    /// Holds the opcode in bits 0-7, and the command identifier in bits 16-31
+    pub cdw0: u32,
+
+    /// Namespace Identifier
+    pub nsid: u32,
+    _reserved: [u32; 2],
+
+    /// Metadata Pointer
+    pub mptr: u64,
+
+    /// PRP Entry 1
+    pub prp1: u64,
+
+    /// PRP Entry 2
+    pub prp2: u64,
+
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+impl SubmissionQueueEntry {
+    pub fn new(opcode: u8) -> Self {
+        Self {
+            cdw0: opcode as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.cdw0 as u8
+    }
+
+    pub fn set_cid(&mut self, cid: u16) {
+        self.cdw0 = (self.cdw0 & 0xffff) | ((cid as u32) << 16);
+    }
+}
+
+/// A 16 byte completion posted by the controller to a completion queue
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CompletionQueueEntry {
+    /// Command specific result
+    pub dw0: u32,
+    _reserved: u32,
+
+    /// Submission queue head pointer at the time of completion
+    pub sq_head: u16,
+
+    /// Submission queue the command was submitted to
+    pub sq_id: u16,
+
+    /// Command Identifier
+    pub cid: u16,
+
+    /// Phase tag in bit 0, status field in bits 1-15
+    pub status: u16,
+}
+
+impl CompletionQueueEntry {
+    pub fn phase(&self) -> bool {
+        self.status & 1 == 1
+    }
+
+    /// Status Code
+    pub fn status_code(&self) -> u8 {
+        (self.status >> 1) as u8
+    }
+
+    /// Status Code Type
+    pub fn status_type(&self) -> u8 {
+        ((self.status >> 9) & 0x7) as u8
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status_code() == 0 && self.status_type() == 0
+    }
+}
+
+pub struct SubmissionQueue {
+    entries: DmaRegion<[SubmissionQueueEntry]>,
+    tail: u16,
+    /// The last head the controller reported in a completion, entries from here to the tail may not be fetched yet
+    head: u16,
+}
+
+impl SubmissionQueue {
+    fn new(len: usize) -> Self {
+        Self {
+            entries: unsafe { DmaRegion::zeroed_many(len).assume_init() },
+            tail: 0,
+            head: 0,
+        }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.entries.physical_address().0
+    }
+
+    pub fn tail(&self) -> u16 {
+        self.tail
+    }
+
+    /// Whether another entry would run the tail into the head, overwriting one the controller hasn't fetched
+    pub fn is_full(&self) -> bool {
+        (self.tail + 1) % self.entries.len() as u16 == self.head
+    }
+
+    /// Writes an entry at the tail of the queue, returning the new tail to be written to the doorbell
+    fn push(&mut self, entry: SubmissionQueueEntry) -> u16 {
+        let len = self.entries.len() as u16;
+
+        unsafe {
+            core::ptr::write_volatile(&mut self.entries.get_mut()[self.tail as usize], entry);
+        }
+
+        self.tail = (self.tail + 1) % len;
+        self.tail
+    }
+}
+
+pub struct CompletionQueue {
+    entries: DmaRegion<[CompletionQueueEntry]>,
+    head: u16,
+    phase: bool,
+}
+
+impl CompletionQueue {
+    fn new(len: usize) -> Self {
+        Self {
+            entries: unsafe { DmaRegion::zeroed_many(len).assume_init() },
+            head: 0,
+            // The queue memory starts zeroed, so the first pass of the controller sets the phase tag
+            phase: true,
+        }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.entries.physical_address().0
+    }
+
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// Takes the entry at the head of the queue if the controller has posted it
+    fn pop(&mut self) -> Option<CompletionQueueEntry> {
+        let entry = unsafe { core::ptr::read_volatile(&self.entries[self.head as usize]) };
+
+        if entry.phase() != self.phase {
+            return None;
+        }
+
+        self.head += 1;
+        if self.head as usize == self.entries.len() {
+            self.head = 0;
+            self.phase = !self.phase;
+        }
+
+        Some(entry)
+    }
+}
+
+/// A submission queue and the completion queue it posts to
+pub struct QueuePair {
+    id: u16,
+    len: usize,
+    /// Command identifiers not in use, a full queue still has one empty entry so one less than its length
+    free_cids: BTreeSet<u16>,
+    pub subq: SubmissionQueue,
+    pub comq: CompletionQueue,
+}
+
+impl QueuePair {
+    pub fn new(id: u16, len: usize) -> Self {
+        Self {
+            id,
+            len,
+            free_cids: (0..len as u16 - 1).collect(),
+            subq: SubmissionQueue::new(len),
+            comq: CompletionQueue::new(len),
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// Takes a command identifier for a command about to be submitted
+    ///
+    /// Returns `Busy` if the queue can't take another command until some complete.
+    pub fn reserve(&mut self) -> Result<u16, NvmeError> {
+        if self.subq.is_full() {
+            return Err(NvmeError::Busy);
+        }
+
+        self.free_cids.pop_first().ok_or(NvmeError::Busy)
+    }
+
+    /// Gives back a command identifier from `reserve` that ended up not being submitted
+    pub fn release(&mut self, cid: u16) {
+        self.free_cids.insert(cid);
+    }
+
+    /// Places an entry in the submission queue under a command identifier from `reserve`
+    ///
+    /// Returns the tail to be written to the submission queue doorbell
+    pub fn submit(&mut self, cid: u16, mut entry: SubmissionQueueEntry) -> u16 {
+        entry.set_cid(cid);
+        self.subq.push(entry)
+    }
+
+    /// Returns the next posted completion, and the head to be written to the completion queue doorbell
+    ///
+    /// The completion's command identifier is free to be reserved again.
+    pub fn complete(&mut self) -> Option<(CompletionQueueEntry, u16)> {
+        let entry = self.comq.pop()?;
+
+        // Everything before the reported head has been fetched, so those entries can be reused
+        self.subq.head = entry.sq_head % self.len as u16;
+
+        if (entry.cid as usize) < self.len - 1 {
+            self.free_cids.insert(entry.cid);
+        }
+
+        Some((entry, self.comq.head()))
+    }
+}
@@ -5,8 +5,8 @@ use crate::volatile::{Volatile, Read, ReadWrite};
 pub struct Slot {
     pub vendor_id: Volatile<u16, Read>,
     pub dev_id: Volatile<u16, Read>,
-    pub command: Volatile<u16, Read>,
-    status: Volatile<u16, Read>,
+    pub command: Volatile<u16, ReadWrite>,
+    pub status: Volatile<u16, Read>,
     rev_id: Volatile<u8, Read>,
     pub class_code: ClassCode,
     cacheline_size: Volatile<u8, Read>,
@@ -49,6 +49,40 @@ impl Slot {
         }
     }
 
+    pub fn command(&self) -> Command {
+        Command::from_bits_retain(self.command.read())
+    }
+
+    pub fn set_command(&self, command: Command) {
+        self.command.write(command.bits());
+    }
+
+    /// Returns how many BARs the header has
+    pub fn bar_count(&self) -> usize {
+        match self.head_type.head_type() {
+            0x0 => 6,
+            // PCI-to-PCI bridges only have two
+            0x1 => 2,
+            _ => 0,
+        }
+    }
+
+    /// Walks the capability list, empty if the function doesn't implement one
+    pub fn capabilities(&self) -> super::capability::Capabilities<'_> {
+        // Bit 4 of the status register says whether the capabilities pointer is valid
+        let next = match self.status.read() & (1 << 4) != 0 {
+            true => self.cap_ptr.read() & !0b11,
+            false => 0,
+        };
+
+        super::capability::Capabilities::new(self, next)
+    }
+
+    /// Returns the legacy interrupt pin the function uses, 1 through 4 for INTA# through INTD#, or 0 for none
+    pub fn interrupt_pin(&self) -> u8 {
+        self.int_pin.read()
+    }
+
     pub unsafe fn read_reg(&self, index: usize) -> u32 {
         assert!(index < 1024);
         let ecam: *const Self = self;
@@ -56,6 +90,43 @@ impl Slot {
 
         ecam.add(index).read_volatile()
     }
+
+    /// Reads a value at a byte offset into configuration space
+    ///
+    /// # Safety
+    /// The offset must be aligned for `T` and inside the 4KiB configuration space
+    pub unsafe fn read_config<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= 4096);
+        let ecam: *const Self = self;
+
+        ecam.cast::<u8>().add(offset).cast::<T>().read_volatile()
+    }
+
+    /// Writes a value at a byte offset into configuration space
+    ///
+    /// # Safety
+    /// The offset must be aligned for `T` and inside the 4KiB configuration space
+    pub unsafe fn write_config<T: Copy>(&self, offset: usize, val: T) {
+        assert!(offset + core::mem::size_of::<T>() <= 4096);
+        let ecam: *const Self = self;
+
+        ecam.cast::<u8>().cast_mut().add(offset).cast::<T>().write_volatile(val)
+    }
+}
+
+bitflags::bitflags! {
+    /// Command register
+    #[derive(Clone, Copy, Debug)]
+    #[repr(transparent)]
+    pub struct Command: u16 {
+        /// Respond to I/O space accesses
+        const IO_SPACE          = 1 << 0;
+        /// Respond to memory space accesses
+        const MEMORY_SPACE      = 1 << 1;
+        /// Allow the function to issue memory requests, needed for DMA
+        const BUS_MASTER        = 1 << 2;
+        const INTX_DISABLE      = 1 << 10;
+    }
 }
 
 #[derive(Debug)]
@@ -98,17 +169,63 @@ pub struct Bars([Volatile<u32, ReadWrite>; 6]);
 
 impl Bars {
     pub fn read_u32(&self, index: usize) -> u32 {
-        self.0[index].read() & !0b111
+        self.0[index].read() & !0xf
     }
 
     pub fn read_u64(&self, index: usize) -> u64 {
-        let high = self.0[index].read() as u64;
-        let low = self.0[index + 1].read() as u64;
-        let low = low & !0b111;
+        let low = self.0[index].read() as u64;
+        let high = self.0[index + 1].read() as u64;
+        let low = low & !0xf;
 
         (high << 32) | low
     }
 
+    pub fn write_u32(&self, index: usize, val: u32) {
+        let flags = self.0[index].read() & 0xf;
+        self.0[index].write((val & !0xf) | flags);
+    }
+
+    pub fn write_u64(&self, index: usize, val: u64) {
+        self.write_u32(index, val as u32);
+        self.0[index + 1].write((val >> 32) as u32);
+    }
+
+    pub fn prefetchable(&self, index: usize) -> bool {
+        self.0[index].read() & 0b1000 != 0
+    }
+
+    /// Returns the size of the region the BAR decodes, zero if it is unimplemented
+    ///
+    /// The size is found by writing all ones and seeing which address bits stick.
+    /// # Safety
+    /// Decoding must be disabled in the command register while the BAR is being probed
+    pub unsafe fn size(&self, index: usize) -> u64 {
+        let kind = self.bar_kind(index);
+
+        let original = self.0[index].read();
+        self.0[index].write(!0);
+        let low = self.0[index].read();
+        self.0[index].write(original);
+
+        let mask = match kind {
+            BarKind::IOSpace => (low & !0b11) as u64 | 0xffff_ffff_0000_0000,
+            BarKind::Bits32 => (low & !0xf) as u64 | 0xffff_ffff_0000_0000,
+            BarKind::Bits64 => {
+                let original = self.0[index + 1].read();
+                self.0[index + 1].write(!0);
+                let high = self.0[index + 1].read();
+                self.0[index + 1].write(original);
+
+                ((high as u64) << 32) | (low & !0xf) as u64
+            }
+        };
+
+        match mask {
+            0 | 0xffff_ffff_0000_0000 => 0,
+            mask => (!mask).wrapping_add(1),
+        }
+    }
+
     pub fn bar_kind(&self, index: usize) -> BarKind {
         let bar = self.0[index].read();
         
@@ -124,13 +241,31 @@ impl Bars {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BarKind {
     IOSpace,
     Bits64,
     Bits32,
 }
 
+/// A memory BAR that has been assigned an address
+#[derive(Debug, Clone, Copy)]
+pub struct Bar {
+    pub kind: BarKind,
+    pub prefetchable: bool,
+    /// Address the CPU reaches the region at
+    pub cpu_addr: u64,
+    pub size: u64,
+}
+
+impl Bar {
+    /// Returns the virtual address the region is mapped at
+    pub fn virt(&self) -> *mut u8 {
+        (self.cpu_addr + crate::memory::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed)) as *mut u8
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct DeviceIdent {
     pub vendor_id: u16,
     pub device_id: u16,
@@ -169,7 +304,7 @@ impl DeviceIdent {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceType {
     Unknown,
     SCSIBusController,
@@ -0,0 +1,173 @@
+use core::sync::atomic::Ordering;
+
+use super::{PciRange, PciSpace, slot};
+use crate::memory::{self, vmm::{self, PageFlags, PageLevel}};
+use crate::println;
+
+/// Hands out bus addresses from one of the host bridge's memory windows
+#[derive(Debug)]
+struct Window {
+    range: PciRange,
+    next: u64,
+}
+
+impl Window {
+    fn new(range: PciRange) -> Self {
+        Self {
+            range,
+            next: range.pci_addr,
+        }
+    }
+
+    /// Returns the PCI and CPU addresses of `size` bytes, aligned to their size as BARs require
+    fn alloc(&mut self, size: u64) -> Option<(u64, u64)> {
+        let base = self.next.checked_next_multiple_of(size)?;
+        let end = base.checked_add(size)?;
+
+        if end > self.range.pci_addr + self.range.size {
+            return None;
+        }
+
+        self.next = end;
+
+        Some((base, base - self.range.pci_addr + self.range.cpu_addr))
+    }
+}
+
+/// The memory windows of a host bridge
+#[derive(Debug)]
+pub struct Windows {
+    mem32: Option<Window>,
+    mem64: Option<Window>,
+    prefetch: Option<Window>,
+}
+
+impl Windows {
+    pub fn new(ranges: &[PciRange]) -> Self {
+        let find = |space: PciSpace, prefetchable: bool| {
+            ranges.iter()
+                .find(|range| range.space == space && range.prefetchable == prefetchable)
+                .copied()
+                .map(Window::new)
+        };
+
+        Self {
+            mem32: find(PciSpace::Memory32, false).or_else(|| find(PciSpace::Memory32, true)),
+            mem64: find(PciSpace::Memory64, false),
+            prefetch: find(PciSpace::Memory64, true),
+        }
+    }
+
+    /// 64 bit BARs prefer the 64 bit windows, and fall back to the 32 bit one when there are none
+    fn alloc(&mut self, kind: slot::BarKind, prefetchable: bool, size: u64) -> Option<(u64, u64)> {
+        if kind == slot::BarKind::Bits64 {
+            if prefetchable {
+                if let Some(addrs) = self.prefetch.as_mut().and_then(|window| window.alloc(size)) {
+                    return Some(addrs);
+                }
+            }
+
+            if let Some(addrs) = self.mem64.as_mut().and_then(|window| window.alloc(size)) {
+                return Some(addrs);
+            }
+        }
+
+        self.mem32.as_mut().and_then(|window| window.alloc(size))
+    }
+
+    /// Sizes every memory BAR of the function, gives it an address, and turns on decoding
+    ///
+    /// # Safety
+    /// The slot must be a function's configuration space, not yet used by a driver
+    pub unsafe fn assign(&mut self, slot: &slot::Slot) -> [Option<slot::Bar>; 6] {
+        let mut bars = [None; 6];
+
+        let command = slot.command();
+        slot.set_command(command - (slot::Command::IO_SPACE | slot::Command::MEMORY_SPACE));
+
+        let mut index = 0;
+        while index < slot.bar_count() {
+            let kind = slot.bars.bar_kind(index);
+            let next = match kind {
+                slot::BarKind::Bits64 => index + 2,
+                _ => index + 1,
+            };
+
+            let size = slot.bars.size(index);
+
+            // We don't support port IO, and a size of zero means the BAR isn't implemented
+            if kind == slot::BarKind::IOSpace || size == 0 {
+                index = next;
+                continue;
+            }
+
+            let prefetchable = slot.bars.prefetchable(index);
+
+            match self.alloc(kind, prefetchable, size) {
+                Some((pci_addr, cpu_addr)) => {
+                    match kind {
+                        slot::BarKind::Bits64 => slot.bars.write_u64(index, pci_addr),
+                        _ => slot.bars.write_u32(index, pci_addr as u32),
+                    }
+
+                    bars[index] = Some(slot::Bar {
+                        kind,
+                        prefetchable,
+                        cpu_addr,
+                        size,
+                    });
+                },
+                None => println!("No room for BAR{} of {:#x} bytes", index, size),
+            }
+
+            index = next;
+        }
+
+        slot.set_command(command | slot::Command::MEMORY_SPACE | slot::Command::BUS_MASTER);
+
+        bars
+    }
+}
+
+/// Maps a memory window into the higher half direct map, using the largest pages that fit
+///
+/// # Safety
+/// Must be called before any task tables are cloned from the kernel table
+pub unsafe fn map(range: &PciRange) {
+    let hhdm = memory::HHDM_OFFSET.load(Ordering::Relaxed);
+    let levels = PageLevel::from_usize(vmm::LEVELS.load(Ordering::Relaxed) as usize);
+
+    let flags = if crate::CPU_DATA.get().contains(crate::CpuData::SVPBMT) {
+        PageFlags::GLOBAL | PageFlags::READ | PageFlags::WRITE | PageFlags::IO
+    } else {
+        PageFlags::GLOBAL | PageFlags::READ | PageFlags::WRITE
+    };
+
+    let mut offset = 0;
+    while offset < range.size {
+        let phys = range.cpu_addr + offset;
+        let remaining = range.size - offset;
+
+        let level = [PageLevel::Level3, PageLevel::Level2]
+            .into_iter()
+            .find(|level| {
+                let size = level.as_page_size() as u64;
+                phys % size == 0 && remaining >= size
+            })
+            .unwrap_or(PageLevel::Level1);
+
+        vmm::map(
+            vmm::current_table().cast_mut(),
+            memory::VirtualAddress(phys + hhdm),
+            memory::PhysicalAddress(phys),
+            levels,
+            level,
+            &mut memory::pmm::REGION_LIST.lock(),
+            flags,
+        );
+
+        offset += level.as_page_size() as u64;
+    }
+
+    vmm::flush_tlb(None, None);
+}
@@ -0,0 +1,483 @@
+use core::time::Duration;
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    drivers::block::{self, BlockDevice, BlockError, BouncePool, BOUNCE_BUFFERS, BOUNCE_LEN},
+    memory::{DmaRegion, PhysicalAddress},
+    println,
+    traps::{task, TrapFrame},
+};
+
+use super::{
+    queue::{Buffer, BufferDirection, Token, Virtqueue},
+    setup::{features::{RING_INDIRECT_DESC, RING_PACKED}, init_device, VirtioInitError},
+    transport::VirtioTransport,
+};
+
+pub static BLOCK_DEVS: Mutex<Vec<Block>> = Mutex::new(Vec::new());
+
+/// Queue requests are placed on
+const REQUEST_QUEUE: u16 = 0;
+
+/// Requests take three descriptors each, unless the device takes indirect tables
+const QUEUE_SIZE: usize = 64;
+
+/// Size of the sectors request addresses are in, whatever the device's block size
+const SECTOR_SIZE: usize = 512;
+
+/// How long a polled request may take before we give up on it
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Feature bits specific to block devices
+pub mod features {
+    /// `size_max` holds the largest size of a single data descriptor
+    pub const SIZE_MAX: u64 = 1 << 1;
+    /// The device is read only
+    pub const RO: u64 = 1 << 5;
+    /// `blk_size` holds the device's optimal block size
+    pub const BLK_SIZE: u64 = 1 << 6;
+    /// The device has a write cache, and takes flush requests
+    pub const FLUSH: u64 = 1 << 9;
+}
+
+/// Request types
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+enum RequestType {
+    In = 0,
+    Out = 1,
+    Flush = 4,
+}
+
+/// Request status values
+mod status {
+    pub const OK: u8 = 0;
+    pub const IOERR: u8 = 1;
+    pub const UNSUPP: u8 = 2;
+}
+
+/// The header the device reads and the status it writes, on either side of the data
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct RequestBuffer {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+    status: u8,
+}
+
+/// A transfer made on behalf of a user thread, which is parked until the device completes it
+struct PendingIo {
+    task_id: usize,
+    thread_id: usize,
+    write: bool,
+
+    /// Index of the device's bounce buffer the transfer goes through
+    bounce: usize,
+
+    /// Physical pieces of the user's buffer, in order
+    segments: Vec<(PhysicalAddress, usize)>,
+}
+
+impl PendingIo {
+    /// Copies read data out of `bounce` to the user's buffer and wakes the thread waiting on it
+    fn finish(self, result: Result<(), BlockError>, bounce: &DmaRegion<[u8]>) {
+        let mut lock = task::THREADS.lock();
+
+        // Processes are only freed with the lock held, so while the thread is still waiting its frames can't be.
+        // If it was removed since, its process may be gone and the data has nowhere to go.
+        let Some(thread) = lock.wake(self.task_id, self.thread_id, task::WaitSrc::Disk) else {
+            return;
+        };
+
+        if result.is_ok() && !self.write {
+            block::scatter(bounce, &self.segments);
+        }
+
+        thread.trap_frame.a0 = result.map_or_else(|e| e.code(), |_| 0);
+    }
+}
+
+pub struct Block {
+    transport: Box<dyn VirtioTransport>,
+    queue: Virtqueue,
+    features: u64,
+
+    /// Capacity of the device, in 512 byte sectors
+    capacity: u64,
+    block_size: usize,
+    /// Largest data descriptor the device takes, if it has a limit
+    size_max: Option<usize>,
+
+    /// Headers and statuses for as many requests as the queue could hold
+    requests: DmaRegion<[RequestBuffer]>,
+    /// Entries of `requests` not in use
+    free_requests: Vec<usize>,
+
+    /// Which entry of `requests` each chain handed to the device uses
+    chains: BTreeMap<Token, usize>,
+
+    /// Requests made by user threads
+    pending: BTreeMap<Token, PendingIo>,
+
+    /// Statuses of completed requests nobody has collected yet
+    finished: BTreeMap<Token, u8>,
+
+    /// Buffers user requests are staged in
+    bounce: BouncePool,
+}
+
+unsafe impl Send for Block {}
+
+impl Block {
+    /// # Safety
+    /// Only call once per device
+    pub unsafe fn init(transport: Box<dyn VirtioTransport>) -> Result<Block, VirtioInitError> {
+        // With indirect tables each request takes a single descriptor, rather than three
+        let (features, mut queues) = init_device(
+            &*transport,
+            features::SIZE_MAX | features::RO | features::BLK_SIZE | features::FLUSH | RING_INDIRECT_DESC | RING_PACKED,
+            0,
+            &[QUEUE_SIZE],
+        )?;
+        let queue = queues.remove(0);
+
+        let read_u32 = |offset| {
+            let mut bytes = [0; 4];
+            transport.read_config_consistent(offset, &mut bytes);
+            u32::from_le_bytes(bytes)
+        };
+
+        let mut capacity = [0; 8];
+        transport.read_config_consistent(0, &mut capacity);
+
+        let size_max = match features & features::SIZE_MAX != 0 {
+            true => Some(read_u32(8) as usize),
+            false => None,
+        };
+
+        // Addresses are always in 512 byte sectors, so only use block sizes made up of whole sectors
+        let block_size = match features & features::BLK_SIZE != 0 {
+            true => Some(read_u32(20) as usize).filter(|size| *size != 0 && size % SECTOR_SIZE == 0),
+            false => None,
+        };
+
+        Ok(Self {
+            transport,
+            queue,
+            features,
+            capacity: u64::from_le_bytes(capacity),
+            block_size: block_size.unwrap_or(SECTOR_SIZE),
+            size_max,
+            requests: DmaRegion::zeroed_many(QUEUE_SIZE).assume_init(),
+            free_requests: (0..QUEUE_SIZE).collect(),
+            chains: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            finished: BTreeMap::new(),
+            bounce: BouncePool::new(BOUNCE_BUFFERS, BOUNCE_LEN),
+        })
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.features & features::RO != 0
+    }
+
+    fn sectors_per_block(&self) -> u64 {
+        (self.block_size / SECTOR_SIZE) as u64
+    }
+
+    /// Returns the number of bytes `count` blocks take up, making sure they can be transferred in one request
+    fn transfer_len(&self, lba: u64, count: u16, len: usize) -> Result<usize, BlockError> {
+        let bytes = count as usize * self.block_size;
+
+        if bytes > len {
+            return Err(BlockError::BufferTooSmall);
+        }
+
+        match lba.checked_add(count as u64) {
+            Some(end) if end <= self.block_count() => {},
+            _ => return Err(BlockError::OutOfRange),
+        }
+
+        if self.size_max.is_some_and(|max| bytes > max) {
+            return Err(BlockError::TransferTooLarge);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Places a request on the queue as a header, data and status chain, returning the chain's token
+    ///
+    /// `data` is the buffer to transfer, flush requests have none.
+    fn submit(&mut self, kind: RequestType, lba: u64, data: Option<(PhysicalAddress, usize)>) -> Result<Token, BlockError> {
+        let slot = self.free_requests.pop().ok_or(BlockError::Busy)?;
+
+        self.requests.get_mut()[slot] = RequestBuffer {
+            kind: kind as u32,
+            reserved: 0,
+            sector: lba * self.sectors_per_block(),
+            status: 0xff,
+        };
+
+        let header = self.requests.physical_address().add((slot * core::mem::size_of::<RequestBuffer>()) as u64);
+        let status = header.add(core::mem::offset_of!(RequestBuffer, status) as u64);
+
+        let mut buffers = Vec::with_capacity(3);
+        buffers.push(Buffer::new(header, core::mem::offset_of!(RequestBuffer, status), BufferDirection::DeviceReads));
+
+        if let Some((address, len)) = data {
+            // The device writes into the buffer when reading from the disk
+            let direction = match kind {
+                RequestType::In => BufferDirection::DeviceWrites,
+                _ => BufferDirection::DeviceReads,
+            };
+
+            buffers.push(Buffer::new(address, len, direction));
+        }
+
+        buffers.push(Buffer::new(status, 1, BufferDirection::DeviceWrites));
+
+        let token = match self.queue.add_chain(&buffers) {
+            Ok(token) => token,
+            Err(_) => {
+                self.free_requests.push(slot);
+                return Err(BlockError::Busy);
+            },
+        };
+
+        self.chains.insert(token, slot);
+        self.transport.notify(REQUEST_QUEUE);
+
+        Ok(token)
+    }
+
+    /// Collects every completed request
+    ///
+    /// Requests belonging to a user thread finish that request, the rest are kept for whoever polls for them.
+    fn complete(&mut self) {
+        while let Some((token, _)) = self.queue.pop_used() {
+            let Some(slot) = self.chains.remove(&token) else {
+                continue;
+            };
+
+            let status = unsafe { core::ptr::read_volatile(&self.requests[slot].status) };
+            self.free_requests.push(slot);
+
+            match self.pending.remove(&token) {
+                Some(pending) => {
+                    let bounce = pending.bounce;
+
+                    pending.finish(status_result(status), self.bounce.get(bounce));
+                    self.bounce.release(bounce);
+                },
+                None => {
+                    self.finished.insert(token, status);
+                },
+            }
+        }
+    }
+
+    /// Resets the device so it lets go of every buffer it was handed, failing the requests still on it
+    fn reset(&mut self) {
+        // A reset device no longer touches its old queue, or anything on it
+        self.transport.reset();
+
+        for (_, pending) in core::mem::take(&mut self.pending) {
+            let bounce = pending.bounce;
+
+            pending.finish(Err(BlockError::Timeout), self.bounce.get(bounce));
+            self.bounce.release(bounce);
+        }
+
+        self.chains.clear();
+        self.finished.clear();
+        self.free_requests = (0..QUEUE_SIZE).collect();
+
+        // Offering only what was negotiated before gets the same features back
+        match unsafe { init_device(&*self.transport, self.features, 0, &[QUEUE_SIZE]) } {
+            Ok((_, mut queues)) => self.queue = queues.remove(0),
+            Err(e) => println!("virtio-blk failed to come back from a reset: {:?}", e),
+        }
+    }
+
+    /// Submits a request and polls for its completion
+    fn execute(&mut self, kind: RequestType, lba: u64, data: Option<(PhysicalAddress, usize)>) -> Result<(), BlockError> {
+        // The interrupt handler drains the same queue, keep it from running while we're polling
+        crate::traps::without_interrupts(|| {
+            let token = self.submit(kind, lba, data)?;
+            let timeout = crate::timing::Timeout::start(TIMEOUT);
+
+            loop {
+                self.complete();
+
+                if let Some(status) = self.finished.remove(&token) {
+                    return status_result(status);
+                }
+
+                if timeout.expired() {
+                    println!("virtio-blk {:?} request for block {} timed out", kind, lba);
+
+                    // The caller's buffer goes away once we return, so the device can't be left holding it
+                    self.reset();
+                    return Err(BlockError::Timeout);
+                }
+
+                core::hint::spin_loop();
+            }
+        })
+    }
+
+    fn transfer(&mut self, kind: RequestType, lba: u64, count: u16, phys: PhysicalAddress, len: usize) -> Result<(), BlockError> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        let bytes = self.transfer_len(lba, count, len)?;
+
+        self.execute(kind, lba, Some((phys, bytes)))
+    }
+}
+
+impl BlockDevice for Block {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity / self.sectors_per_block()
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u16, buffer: &mut DmaRegion<[u8]>) -> Result<(), BlockError> {
+        self.transfer(RequestType::In, lba, count, buffer.physical_address(), buffer.len())
+    }
+
+    fn write_blocks(&mut self, lba: u64, count: u16, buffer: &DmaRegion<[u8]>) -> Result<(), BlockError> {
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+
+        self.transfer(RequestType::Out, lba, count, buffer.physical_address(), buffer.len())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        // Without a write cache every completed write is already on the disk
+        if self.features & features::FLUSH == 0 {
+            return Ok(());
+        }
+
+        self.execute(RequestType::Flush, 0, None)
+    }
+}
+
+fn status_result(status: u8) -> Result<(), BlockError> {
+    match status {
+        status::OK => Ok(()),
+        status::IOERR => Err(BlockError::Io),
+        status::UNSUPP => Err(BlockError::Unsupported),
+        // Anything else is a device that isn't following the spec
+        _ => Err(BlockError::Io),
+    }
+}
+
+/// # Safety
+/// Only call once per block device
+pub unsafe fn init(transport: Box<dyn VirtioTransport>) {
+    match Block::init(transport) {
+        Ok(device) => {
+            println!(
+                "virtio-blk has {} blocks of {} bytes{}",
+                device.block_count(),
+                device.block_size,
+                if device.read_only() { ", read only" } else { "" },
+            );
+
+            // The interrupt handler walks the list
+            crate::traps::without_interrupts(|| BLOCK_DEVS.lock().push(device));
+        },
+        Err(e) => println!("Failed to initialize block device: {:?}", e),
+    }
+}
+
+/// Backs the disk read and write syscalls for the first virtio block device, with the same arguments as the
+/// NVMe path
+///
+/// The calling thread is parked until the transfer completes, with the result ending up in `a0`.
+pub fn user_request(trap_frame: &mut TrapFrame, write: bool) {
+    match submit_user_request(trap_frame, write) {
+        Ok(true) => task::switch_to_next(trap_frame),
+        Ok(false) => trap_frame.a0 = 0,
+        Err(e) => trap_frame.a0 = e.code(),
+    }
+}
+
+/// Returns whether the calling thread was parked to wait on the request
+fn submit_user_request(trap_frame: &TrapFrame, write: bool) -> Result<bool, BlockError> {
+    let lba = trap_frame.a2 as u64;
+    let count = u16::try_from(trap_frame.a3).map_err(|_| BlockError::TransferTooLarge)?;
+    let buffer = trap_frame.a4;
+    let len = trap_frame.a5;
+
+    let mut devices = BLOCK_DEVS.lock();
+    let device = devices.first_mut().ok_or(BlockError::NoDevice)?;
+
+    if write && device.read_only() {
+        return Err(BlockError::ReadOnly);
+    }
+
+    if count == 0 {
+        return Ok(false);
+    }
+
+    let bytes = device.transfer_len(lba, count, len)?;
+
+    if bytes > device.bounce.buffer_len() {
+        return Err(BlockError::TransferTooLarge);
+    }
+
+    let segments = block::user_segments(buffer, bytes, !write)?;
+
+    let bounce = device.bounce.take().ok_or(BlockError::Busy)?;
+
+    if write {
+        block::gather(&segments, device.bounce.get_mut(bounce));
+    }
+
+    let kind = match write {
+        true => RequestType::Out,
+        false => RequestType::In,
+    };
+
+    // Park the thread before the device can possibly complete the request, so a completion handled on another hart
+    // finds its frame saved. The device lock is held until the request is recorded, so it can't be finished before.
+    let (task_id, thread_id) = task::park(trap_frame, task::WaitSrc::Disk);
+
+    let address = device.bounce.get(bounce).physical_address();
+    let token = match device.submit(kind, lba, Some((address, bytes))) {
+        Ok(token) => token,
+        Err(e) => {
+            device.bounce.release(bounce);
+            task::unpark((task_id, thread_id), task::WaitSrc::Disk, e.code());
+
+            return Ok(true);
+        },
+    };
+
+    device.pending.insert(token, PendingIo {
+        task_id,
+        thread_id,
+        write,
+        bounce,
+        segments,
+    });
+
+    Ok(true)
+}
+
+pub fn handle_int(_id: usize) {
+    for device in BLOCK_DEVS.lock().iter_mut() {
+        device.transport.ack_interrupt();
+        device.complete();
+    }
+}
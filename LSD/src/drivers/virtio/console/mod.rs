@@ -0,0 +1,533 @@
+//! virtio-console, mirroring kernel output and taking console input, with extra ports when the device has them
+
+use core::{fmt, time::Duration};
+
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, string::String, vec::Vec};
+use spin::Mutex;
+
+use crate::{memory::DmaRegion, println};
+
+use super::{
+    queue::{Buffer, BufferDirection, Token, Virtqueue},
+    setup::{features::RING_PACKED, init_device, VirtioInitError},
+    transport::VirtioTransport,
+};
+
+pub static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+const QUEUE_SIZE: usize = 16;
+
+/// Size of each buffer handed to the device, in either direction
+const SLOT_LEN: usize = 256;
+
+/// Ports past this many are ignored, they'd each need their own pair of queues set up front
+const MAX_PORTS: u32 = 8;
+
+/// How long a write waits for the device to free up room before the rest of it is dropped
+const TX_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Feature bits specific to console devices
+pub mod features {
+    /// `cols` and `rows` hold the size of the console
+    pub const SIZE: u64 = 1 << 0;
+    /// The device has more than one port, managed through the control queues
+    pub const MULTIPORT: u64 = 1 << 1;
+}
+
+/// Control message events
+mod control {
+    pub const DEVICE_READY: u16 = 0;
+    pub const DEVICE_ADD: u16 = 1;
+    pub const DEVICE_REMOVE: u16 = 2;
+    pub const PORT_READY: u16 = 3;
+    pub const CONSOLE_PORT: u16 = 4;
+    pub const RESIZE: u16 = 5;
+    pub const PORT_OPEN: u16 = 6;
+    pub const PORT_NAME: u16 = 7;
+}
+
+/// Queue indexes of the control queues, when the device is multiport
+const CONTROL_RX: u16 = 2;
+const CONTROL_TX: u16 = 3;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ControlMsg {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+impl ControlMsg {
+    const LEN: usize = core::mem::size_of::<Self>();
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        Some(Self {
+            id: u32::from_le_bytes(data.get(0..4)?.try_into().unwrap()),
+            event: u16::from_le_bytes(data.get(4..6)?.try_into().unwrap()),
+            value: u16::from_le_bytes(data.get(6..8)?.try_into().unwrap()),
+        })
+    }
+
+    fn bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..4].copy_from_slice(&self.id.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.event.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+}
+
+/// A queue along with one buffer per descriptor
+struct SlotQueue {
+    index: u16,
+    queue: Virtqueue,
+    slots: DmaRegion<[u8]>,
+
+    /// Which slot each chain handed to the device holds
+    in_flight: BTreeMap<Token, usize>,
+    /// Slots the device doesn't have, only used for sending
+    free_slots: Vec<usize>,
+}
+
+impl SlotQueue {
+    fn new(index: u16, queue: Virtqueue) -> Self {
+        Self {
+            index,
+            queue,
+            slots: unsafe { DmaRegion::new_raw(QUEUE_SIZE * SLOT_LEN, true) },
+            in_flight: BTreeMap::new(),
+            free_slots: (0..QUEUE_SIZE).collect(),
+        }
+    }
+
+    /// Hands a slot to the device, to read `len` bytes from or to write into
+    fn post(&mut self, slot: usize, len: usize, direction: BufferDirection) {
+        let address = self.slots.physical_address().add((slot * SLOT_LEN) as u64);
+
+        // There's a descriptor for every slot, so this only fails if the queue is broken
+        if let Ok(token) = self.queue.add_chain(&[Buffer::new(address, len, direction)]) {
+            self.in_flight.insert(token, slot);
+        }
+    }
+
+    /// Hands every buffer to the device to write into
+    fn fill_rx(&mut self, transport: &dyn VirtioTransport) {
+        while let Some(slot) = self.free_slots.pop() {
+            self.post(slot, SLOT_LEN, BufferDirection::DeviceWrites);
+        }
+
+        transport.notify(self.index);
+    }
+
+    /// Copies out every buffer the device has written, and gives them back to it
+    fn drain_rx(&mut self, transport: &dyn VirtioTransport) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+
+        while let Some((token, written)) = self.queue.pop_used() {
+            let Some(slot) = self.in_flight.remove(&token) else {
+                continue;
+            };
+
+            let start = slot * SLOT_LEN;
+            let len = (written as usize).min(SLOT_LEN);
+
+            received.push(self.slots[start..start + len].to_vec());
+            self.post(slot, SLOT_LEN, BufferDirection::DeviceWrites);
+        }
+
+        if !received.is_empty() {
+            transport.notify(self.index);
+        }
+
+        received
+    }
+
+    /// Takes back every buffer the device has finished sending
+    fn reclaim_tx(&mut self) {
+        while let Some((token, _)) = self.queue.pop_used() {
+            if let Some(slot) = self.in_flight.remove(&token) {
+                self.free_slots.push(slot);
+            }
+        }
+    }
+
+    /// Queues `data` to be sent, waiting up to `TX_TIMEOUT` for room, and returns how many bytes were queued
+    fn send(&mut self, transport: &dyn VirtioTransport, data: &[u8]) -> usize {
+        let mut sent = 0;
+        let mut timeout = None;
+
+        for chunk in data.chunks(SLOT_LEN) {
+            let slot = loop {
+                self.reclaim_tx();
+
+                if let Some(slot) = self.free_slots.pop() {
+                    break Some(slot);
+                }
+
+                let timeout = timeout.get_or_insert_with(|| crate::timing::Timeout::start(TX_TIMEOUT));
+                if timeout.expired() {
+                    break None;
+                }
+
+                core::hint::spin_loop();
+            };
+
+            let Some(slot) = slot else {
+                break;
+            };
+
+            let start = slot * SLOT_LEN;
+            self.slots.get_mut()[start..start + chunk.len()].copy_from_slice(chunk);
+
+            self.post(slot, chunk.len(), BufferDirection::DeviceReads);
+            sent += chunk.len();
+        }
+
+        if sent != 0 {
+            transport.notify(self.index);
+        }
+
+        sent
+    }
+}
+
+pub struct Port {
+    pub id: u32,
+    /// The device has told us the port exists, always the case for port 0 of a single port device
+    pub added: bool,
+    /// Something on the host side has the port open
+    pub open: bool,
+    /// The port should be treated as a console
+    pub console: bool,
+    pub name: Option<String>,
+    /// Columns and rows, if the device reported them
+    pub size: Option<(u16, u16)>,
+
+    rx: SlotQueue,
+    tx: SlotQueue,
+
+    /// Input received on a port that isn't the kernel console, until someone reads it
+    input: VecDeque<u8>,
+}
+
+impl Port {
+    /// Queue index of the port's receive queue, its transmit queue comes right after
+    fn rx_queue(id: u32) -> u16 {
+        // Port 0 uses queues 0 and 1, the control queues sit between it and port 1
+        match id {
+            0 => 0,
+            id => 2 * id as u16 + 2,
+        }
+    }
+
+    fn new(id: u32, rx: SlotQueue, tx: SlotQueue) -> Self {
+        Self {
+            id,
+            added: false,
+            open: false,
+            console: false,
+            name: None,
+            size: None,
+            rx,
+            tx,
+            input: VecDeque::new(),
+        }
+    }
+}
+
+pub struct Console {
+    transport: Box<dyn VirtioTransport>,
+    features: u64,
+    ports: Vec<Port>,
+    /// Receive and transmit control queues, if the device is multiport
+    control: Option<(SlotQueue, SlotQueue)>,
+}
+
+unsafe impl Send for Console {}
+
+impl Console {
+    /// # Safety
+    /// Only call once per device
+    pub unsafe fn init(transport: Box<dyn VirtioTransport>) -> Result<Console, VirtioInitError> {
+        // The number of ports decides how many queues there are, so it's needed before the queues are set up
+        let multiport = transport.device_features() & features::MULTIPORT != 0;
+        let port_count = match multiport {
+            true => {
+                let mut max = [0; 4];
+                transport.read_config_consistent(4, &mut max);
+                u32::from_le_bytes(max).clamp(1, MAX_PORTS)
+            },
+            false => 1,
+        };
+
+        // Port 0's queues come first, then the control queues, then every other port's
+        let queue_count = match multiport {
+            true => 2 * port_count as usize + 2,
+            false => 2,
+        };
+
+        let (features, queues) = init_device(
+            &*transport,
+            features::SIZE | features::MULTIPORT | RING_PACKED,
+            0,
+            &alloc::vec![QUEUE_SIZE; queue_count],
+        )?;
+
+        let mut queues: Vec<Option<Virtqueue>> = queues.into_iter().map(Some).collect();
+        let mut slot_queue = |index: u16| SlotQueue::new(index, queues[index as usize].take().unwrap());
+
+        let control = multiport.then(|| (slot_queue(CONTROL_RX), slot_queue(CONTROL_TX)));
+        let ports: Vec<Port> = (0..port_count)
+            .map(|id| {
+                let rx = Port::rx_queue(id);
+                Port::new(id, slot_queue(rx), slot_queue(rx + 1))
+            })
+            .collect();
+
+        let mut new_self = Self {
+            transport,
+            features,
+            ports,
+            control,
+        };
+
+        new_self.read_size();
+
+        match new_self.control.as_mut() {
+            Some((rx, _)) => {
+                rx.fill_rx(&*new_self.transport);
+
+                // Ports are added once the device sees we're ready for them
+                new_self.send_control(0, control::DEVICE_READY, 1);
+            },
+            None => {
+                let port = &mut new_self.ports[0];
+                port.added = true;
+                port.open = true;
+                port.console = true;
+                port.rx.fill_rx(&*new_self.transport);
+            },
+        }
+
+        Ok(new_self)
+    }
+
+    /// Updates the size of port 0 from the configuration space
+    fn read_size(&mut self) {
+        if self.features & features::SIZE == 0 {
+            return;
+        }
+
+        let mut size = [0; 4];
+        self.transport.read_config_consistent(0, &mut size);
+
+        let cols = u16::from_le_bytes([size[0], size[1]]);
+        let rows = u16::from_le_bytes([size[2], size[3]]);
+
+        self.ports[0].size = Some((cols, rows));
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        if let Some((_, tx)) = self.control.as_mut() {
+            tx.send(&*self.transport, &ControlMsg { id, event, value }.bytes());
+        }
+    }
+
+    fn port_mut(&mut self, id: u32) -> Option<&mut Port> {
+        self.ports.iter_mut().find(|port| port.id == id)
+    }
+
+    /// Returns the port kernel output goes to and input comes from
+    pub fn console_port(&self) -> Option<&Port> {
+        self.ports.iter().find(|port| port.added && port.console)
+    }
+
+    pub fn ports(&self) -> &[Port] {
+        &self.ports
+    }
+
+    fn handle_control(&mut self, data: &[u8]) {
+        let Some(msg) = ControlMsg::parse(data) else {
+            return;
+        };
+        let extra = &data[ControlMsg::LEN..];
+
+        match msg.event {
+            control::DEVICE_ADD => {
+                let transport = &*self.transport;
+                let ready = match self.ports.iter_mut().find(|port| port.id == msg.id) {
+                    Some(port) => {
+                        port.added = true;
+                        port.rx.fill_rx(transport);
+                        1
+                    },
+                    // Past the ports we set up queues for
+                    None => 0,
+                };
+
+                self.send_control(msg.id, control::PORT_READY, ready);
+            },
+            control::DEVICE_REMOVE => {
+                if let Some(port) = self.port_mut(msg.id) {
+                    port.added = false;
+                    port.open = false;
+                }
+            },
+            control::CONSOLE_PORT => {
+                if let Some(port) = self.port_mut(msg.id) {
+                    port.console = true;
+                    self.send_control(msg.id, control::PORT_OPEN, 1);
+                }
+            },
+            control::RESIZE if extra.len() >= 4 => {
+                let cols = u16::from_le_bytes([extra[0], extra[1]]);
+                let rows = u16::from_le_bytes([extra[2], extra[3]]);
+
+                if let Some(port) = self.port_mut(msg.id) {
+                    port.size = Some((cols, rows));
+                }
+            },
+            control::PORT_OPEN => {
+                if let Some(port) = self.port_mut(msg.id) {
+                    port.open = msg.value != 0;
+                }
+            },
+            control::PORT_NAME => {
+                if let Some(port) = self.port_mut(msg.id) {
+                    port.name = Some(extra.iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect());
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Queues bytes to be sent out of a port, returning how many were queued
+    pub fn write(&mut self, id: u32, data: &[u8]) -> usize {
+        let transport = &*self.transport;
+
+        match self.ports.iter_mut().find(|port| port.id == id && port.added) {
+            Some(port) => port.tx.send(transport, data),
+            None => 0,
+        }
+    }
+
+    /// Takes input received on a port, returning how many bytes were read
+    pub fn read(&mut self, id: u32, buf: &mut [u8]) -> usize {
+        let Some(port) = self.port_mut(id) else {
+            return 0;
+        };
+
+        let len = buf.len().min(port.input.len());
+        for (byte, input) in buf.iter_mut().zip(port.input.drain(..len)) {
+            *byte = input;
+        }
+
+        len
+    }
+
+    /// Handles everything the device has sent, returning input for the kernel console
+    fn process(&mut self) -> Vec<u8> {
+        let transport = &*self.transport;
+
+        let messages = match self.control.as_mut() {
+            Some((rx, tx)) => {
+                tx.reclaim_tx();
+                rx.drain_rx(transport)
+            },
+            None => Vec::new(),
+        };
+
+        for message in messages {
+            self.handle_control(&message);
+        }
+
+        let transport = &*self.transport;
+        let mut console_input = Vec::new();
+
+        for port in self.ports.iter_mut().filter(|port| port.added) {
+            port.tx.reclaim_tx();
+
+            for data in port.rx.drain_rx(transport) {
+                match port.console {
+                    true => console_input.extend_from_slice(&data),
+                    false => port.input.extend(data),
+                }
+            }
+        }
+
+        console_input
+    }
+}
+
+/// # Safety
+/// Only call once per console device
+pub unsafe fn init(transport: Box<dyn VirtioTransport>) {
+    match Console::init(transport) {
+        Ok(console) => {
+            println!(
+                "virtio-console with {} port(s){}",
+                console.ports.len(),
+                match console.ports[0].size {
+                    Some((cols, rows)) => alloc::format!(", {}x{}", cols, rows),
+                    None => String::new(),
+                },
+            );
+
+            // The interrupt handler takes the lock too
+            crate::traps::without_interrupts(|| *CONSOLE.lock() = Some(console));
+        },
+        Err(e) => println!("Failed to initialize console device: {:?}", e),
+    }
+}
+
+struct ConsoleWriter<'a>(&'a mut Console, u32);
+
+impl fmt::Write for ConsoleWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(self.1, s.as_bytes());
+
+        Ok(())
+    }
+}
+
+/// Mirrors kernel output to the console port, if there is one
+///
+/// Output printed while the console is locked, such as from its own interrupt handler, is skipped.
+pub fn log(args: fmt::Arguments) {
+    use fmt::Write;
+
+    crate::traps::without_interrupts(|| {
+        let Some(mut lock) = CONSOLE.try_lock() else {
+            return;
+        };
+        let Some(console) = lock.as_mut() else {
+            return;
+        };
+        let Some(id) = console.console_port().map(|port| port.id) else {
+            return;
+        };
+
+        let _ = ConsoleWriter(console, id).write_fmt(args);
+    });
+}
+
+pub fn handle_int(_id: usize) {
+    let input = {
+        let mut lock = CONSOLE.lock();
+        let Some(console) = lock.as_mut() else {
+            return;
+        };
+
+        // Bit 1 is a configuration change, which is how a single port device reports a resize
+        if console.transport.ack_interrupt() & 0b10 != 0 {
+            console.read_size();
+        }
+
+        console.process()
+    };
+
+    for char in input {
+        crate::uart::deliver_char(char);
+    }
+}
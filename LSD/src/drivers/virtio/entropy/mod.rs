@@ -1,74 +1,125 @@
-use crate::println;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+
+use crate::{memory::DmaRegion, println};
+
+use super::{
+    queue::{Buffer, BufferDirection, Token, Virtqueue},
+    setup::{features::{RING_EVENT_IDX, RING_PACKED}, init_device, VirtioInitError},
+    transport::VirtioTransport,
+};
+
+pub static ENTROPY_DEV: Mutex<Option<Entropy>> = Mutex::new(None);
+
+/// Bytes asked of the device at a time
+const REQUEST_LEN: usize = 32;
 
 pub struct Entropy {
-    header: *mut super::VirtIOHeader,
-    req: super::splitqueue::SplitVirtqueue,
+    transport: Box<dyn VirtioTransport>,
+    req: Virtqueue,
+
+    /// Buffers handed to the device, by the chain they're in
+    in_flight: BTreeMap<Token, DmaRegion<[u8]>>,
 }
 
+unsafe impl Send for Entropy {}
+
 impl Entropy {
     /// # Safety
     /// Only call once per device
-    pub unsafe fn init(head: *mut super::VirtIOHeader) -> &'static mut Entropy {
-        let queue = super::splitqueue::SplitVirtqueue::new(8).unwrap();
-
-        let new_self = Self { 
-            header: head, 
-            req: queue
-        };
-
-        (*new_self.header).status.reset();
-        (*new_self.header).status.set_flag(super::StatusFlag::Acknowledge);
-        (*new_self.header).status.set_flag(super::StatusFlag::Driver);
+    pub unsafe fn init(transport: Box<dyn VirtioTransport>) -> Result<Entropy, VirtioInitError> {
+        let (_, mut queues) = init_device(&*transport, RING_EVENT_IDX | RING_PACKED, 0, &[8])?;
+
+        Ok(Self {
+            transport,
+            req: queues.remove(0),
+            in_flight: BTreeMap::new(),
+        })
+    }
 
-        (*new_self.header).dev_feat_sel.write(0);
-        (*new_self.header).driver_feat_sel.write(0);
-        (*new_self.header).driver_feat.write(0);
+    /// Asks the device for `byte_len` bytes in every free descriptor, which are added to the kernel pool as they
+    /// complete
+    ///
+    /// The requests are handed over in one batch, so the device is notified at most once.
+    pub fn request(&mut self, byte_len: usize) {
+        let buffers: Vec<DmaRegion<[u8]>> = (0..self.req.free_count())
+            .map(|_| unsafe { DmaRegion::new_raw(byte_len, true) })
+            .collect();
 
-        (*new_self.header).status.set_flag(super::StatusFlag::FeaturesOk);
-        if !(*new_self.header).status.is_set(super::StatusFlag::FeaturesOk) {
-            println!("Features not accepted");
+        if buffers.is_empty() {
+            return;
         }
 
-        (*new_self.header).queue_sel.write(0);
-        (*new_self.header).queue_avail.set(new_self.req.available.physical_address());
-        (*new_self.header).queue_desc.set(new_self.req.descriptors.physical_address());
-        (*new_self.header).queue_used.set(new_self.req.used.physical_address());
-        (*new_self.header).queue_size.write(new_self.req.queue_size());
-        (*new_self.header).queue_ready.ready();
+        let chains: Vec<[Buffer; 1]> = buffers.iter()
+            .map(|dma| [Buffer::from_region(dma, BufferDirection::DeviceWrites)])
+            .collect();
+        let chains: Vec<&[Buffer]> = chains.iter().map(|chain| &chain[..]).collect();
 
-        (*new_self.header).status.set_flag(super::StatusFlag::DriverOk);
+        let tokens = self.req.submit_batch(&*self.transport, 0, &chains);
 
-        let boxed = alloc::boxed::Box::new(new_self);
-        let dev_ref = alloc::boxed::Box::leak(boxed);
+        // Buffers past the last chain that fit are dropped
+        self.in_flight.extend(tokens.into_iter().zip(buffers));
+    }
+
+    /// Feeds every completed request into the kernel pool
+    fn complete(&mut self) {
+        while let Some((token, written)) = self.req.pop_used() {
+            if let Some(dma) = self.in_flight.remove(&token) {
+                let len = (written as usize).min(dma.len());
+                crate::random::POOL.lock().add_entropy(&dma[..len]);
+            }
+        }
+    }
+}
 
-        dev_ref
+/// # Safety
+/// Only call once per entropy device
+pub unsafe fn init(transport: Box<dyn VirtioTransport>) {
+    match Entropy::init(transport) {
+        Ok(entropy) => {
+            *ENTROPY_DEV.lock() = Some(entropy);
+            request_entropy();
+        },
+        Err(e) => println!("Failed to initialize entropy device: {:?}", e),
     }
+}
 
-    pub fn request(&mut self, byte_len: usize) {
-        println!("Requesting random number");
-        let mut dma: crate::memory::DmaRegion<[u8]> = unsafe {crate::memory::DmaRegion::new_many(byte_len).assume_init()};
+/// Whether there is a device to seed the kernel pool from
+pub fn present() -> bool {
+    ENTROPY_DEV.lock().is_some()
+}
 
-        let desc = super::splitqueue::VirtqueueDescriptor {
-            address: dma.physical_address(),
-            length: byte_len as u32,
-            flags: super::splitqueue::DescriptorFlags::WRITE,
-            next: super::splitqueue::SplitqueueIndex::new(0),
-        };
+/// Asks the device for more entropy for the kernel pool
+pub fn request_entropy() {
+    // The interrupt handler takes the device lock too
+    crate::traps::without_interrupts(|| {
+        if let Some(entropy) = ENTROPY_DEV.lock().as_mut() {
+            entropy.request(REQUEST_LEN);
+        }
+    });
+}
 
-        println!("{:#x?}", desc);
+pub fn handle_int(_id: usize) {
+    let mut lock = ENTROPY_DEV.lock();
+    let Some(entropy) = lock.as_mut() else {
+        return;
+    };
 
-        unsafe {
-            for entry in (*dma).iter_mut() {
-                *entry = 0; 
-            }
+    entropy.transport.ack_interrupt();
+    entropy.complete();
+
+    let (seeded, wants_more) = {
+        let pool = crate::random::POOL.lock();
+        (pool.is_seeded(), pool.wants_entropy())
+    };
 
-            let index = self.req.alloc_descriptor().unwrap();
-            self.req.descriptors.write(index, desc);
+    if wants_more {
+        entropy.request(REQUEST_LEN);
+    }
 
-            let index = super::splitqueue::SplitqueueIndex::new(0);
-            self.req.available.push(index);
+    drop(lock);
 
-            (*self.header).queue_notify.notify(0);
-        }
+    if seeded {
+        crate::random::wake_waiters();
     }
-}
\ No newline at end of file
+}
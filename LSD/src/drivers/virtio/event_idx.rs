@@ -0,0 +1,44 @@
+/// Whether the other side asked to be notified once the entry at `event` was published, given the index moved from
+/// `old` to `new` since it was last told
+///
+/// Indexes wrap, so this works on the distances back from `new` rather than comparing them directly.
+pub fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies_when_the_event_was_published() {
+        assert!(need_event(0, 1, 0));
+        assert!(need_event(5, 8, 3));
+        assert!(need_event(7, 8, 3));
+    }
+
+    #[test]
+    fn skips_events_not_reached_yet() {
+        assert!(!need_event(8, 8, 3));
+        assert!(!need_event(20, 8, 3));
+    }
+
+    #[test]
+    fn skips_events_already_notified() {
+        assert!(!need_event(2, 8, 3));
+        assert!(!need_event(0, 8, 3));
+    }
+
+    #[test]
+    fn nothing_published_needs_nothing() {
+        assert!(!need_event(3, 3, 3));
+    }
+
+    #[test]
+    fn handles_the_index_wrapping() {
+        assert!(need_event(0xffff, 2, 0xfffe));
+        assert!(need_event(1, 2, 0xfffe));
+        assert!(!need_event(0xfffd, 2, 0xfffe));
+        assert!(!need_event(2, 2, 0xfffe));
+    }
+}
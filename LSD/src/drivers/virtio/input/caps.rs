@@ -0,0 +1,151 @@
+use alloc::{string::String, vec::Vec};
+
+use super::structs::{self, InputABSInfo, InputConfigSelect, InputDevIDs};
+
+/// Axes of `EV_REL` and `EV_ABS` events
+pub const AXIS_X: u16 = 0x00;
+pub const AXIS_Y: u16 = 0x01;
+/// Relative scroll wheel
+pub const REL_WHEEL: u16 = 0x08;
+
+/// Key codes of the first letter and first mouse button
+const KEY_A: u16 = 30;
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+/// What sort of device the capabilities describe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    Keyboard,
+    /// Reports relative movement
+    Mouse,
+    /// Reports absolute positions
+    Tablet,
+    Unknown,
+}
+
+/// Everything the device reports about itself through its configuration space
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    pub name: String,
+    pub serial: String,
+    pub ids: Option<InputDevIDs>,
+    /// Bitmap of `INPUT_PROP_*` device properties
+    pub props: Bitmap,
+    pub keys: Bitmap,
+    pub rel: Bitmap,
+    pub abs: Bitmap,
+    /// Range of each absolute axis the device reports
+    pub abs_info: Vec<(u16, InputABSInfo)>,
+}
+
+impl Capabilities {
+    /// Runs every query through `query`, which returns the size and contents of a configuration field
+    pub fn discover(query: impl Fn(InputConfigSelect, u8) -> (u8, [u8; 128])) -> Self {
+        let string = |select| {
+            let (size, data) = query(select, 0);
+            data[..size as usize].iter().map(|byte| *byte as char).collect()
+        };
+        let bitmap = |select, subsel| {
+            let (size, data) = query(select, subsel);
+            Bitmap { data, len: size }
+        };
+
+        let ids = match query(InputConfigSelect::IDDevIDs, 0) {
+            (size, data) if size as usize >= core::mem::size_of::<InputDevIDs>() => {
+                let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+
+                Some(InputDevIDs {
+                    bustype: word(0),
+                    vendor: word(1),
+                    product: word(2),
+                    version: word(3),
+                })
+            },
+            _ => None,
+        };
+
+        let abs = bitmap(InputConfigSelect::EVBits, structs::EV_ABS as u8);
+
+        let abs_info = abs.iter()
+            .filter_map(|axis| {
+                let (size, data) = query(InputConfigSelect::ABSInfo, axis as u8);
+                if (size as usize) < core::mem::size_of::<InputABSInfo>() {
+                    return None;
+                }
+
+                let word = |index: usize| u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap());
+
+                Some((axis, InputABSInfo {
+                    min: word(0),
+                    max: word(1),
+                    fuzzd: word(2),
+                    flat: word(3),
+                    res: word(4),
+                }))
+            })
+            .collect();
+
+        Self {
+            name: string(InputConfigSelect::IDName),
+            serial: string(InputConfigSelect::IDSerial),
+            ids,
+            props: bitmap(InputConfigSelect::PropBits, 0),
+            keys: bitmap(InputConfigSelect::EVBits, structs::EV_KEY as u8),
+            rel: bitmap(InputConfigSelect::EVBits, structs::EV_REL as u8),
+            abs,
+            abs_info,
+        }
+    }
+
+    pub fn kind(&self) -> InputKind {
+        if self.abs.contains(AXIS_X) && self.abs.contains(AXIS_Y) {
+            InputKind::Tablet
+        } else if self.rel.contains(AXIS_X) && self.rel.contains(AXIS_Y) {
+            InputKind::Mouse
+        } else if self.keys.contains(KEY_A) {
+            InputKind::Keyboard
+        } else {
+            InputKind::Unknown
+        }
+    }
+
+    pub fn abs_info(&self, axis: u16) -> Option<&InputABSInfo> {
+        self.abs_info.iter().find(|(index, _)| *index == axis).map(|(_, info)| info)
+    }
+}
+
+/// A bitmap of supported codes, as returned by the device
+#[derive(Clone, Copy)]
+pub struct Bitmap {
+    data: [u8; 128],
+    len: u8,
+}
+
+impl Default for Bitmap {
+    fn default() -> Self {
+        Self {
+            data: [0; 128],
+            len: 0,
+        }
+    }
+}
+
+impl core::fmt::Debug for Bitmap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Bitmap {
+    pub fn contains(&self, code: u16) -> bool {
+        let byte = code as usize / 8;
+
+        byte < self.len as usize && self.data[byte] & (1 << (code % 8)) != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.len as u16 * 8).filter(|code| self.contains(*code))
+    }
+}
@@ -0,0 +1,115 @@
+//! Translation of Linux input key codes into characters, for a US layout
+
+/// Key codes we track as modifiers
+mod code {
+    pub const LEFT_CTRL: u16 = 29;
+    pub const LEFT_SHIFT: u16 = 42;
+    pub const RIGHT_SHIFT: u16 = 54;
+    pub const LEFT_ALT: u16 = 56;
+    pub const CAPS_LOCK: u16 = 58;
+    pub const RIGHT_CTRL: u16 = 97;
+    pub const RIGHT_ALT: u16 = 100;
+}
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Modifiers: u8 {
+        const SHIFT     = 1 << 0;
+        const CTRL      = 1 << 1;
+        const ALT       = 1 << 2;
+        const CAPS_LOCK = 1 << 3;
+    }
+}
+
+/// A key changing state, after modifiers have been applied
+#[derive(Clone, Copy, Debug)]
+pub struct KeyEvent {
+    pub code: u16,
+    pub pressed: bool,
+    pub modifiers: Modifiers,
+    /// The character the key produces, if it produces one and was pressed
+    pub char: Option<u8>,
+}
+
+/// Tracks modifier state across key events
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    modifiers: Modifiers,
+}
+
+impl Keyboard {
+    pub const fn new() -> Self {
+        Self {
+            modifiers: Modifiers::empty(),
+        }
+    }
+
+    /// Handles an `EV_KEY` event, `value` is 0 for a release, 1 for a press, and 2 for autorepeat
+    pub fn key(&mut self, code: u16, value: u32) -> KeyEvent {
+        let pressed = value != 0;
+
+        match code {
+            code::LEFT_SHIFT | code::RIGHT_SHIFT => self.modifiers.set(Modifiers::SHIFT, pressed),
+            code::LEFT_CTRL | code::RIGHT_CTRL => self.modifiers.set(Modifiers::CTRL, pressed),
+            code::LEFT_ALT | code::RIGHT_ALT => self.modifiers.set(Modifiers::ALT, pressed),
+            // Caps lock toggles on the initial press only
+            code::CAPS_LOCK if value == 1 => self.modifiers.toggle(Modifiers::CAPS_LOCK),
+            _ => {},
+        }
+
+        let char = match pressed {
+            true => translate(code, self.modifiers),
+            false => None,
+        };
+
+        KeyEvent {
+            code,
+            pressed,
+            modifiers: self.modifiers,
+            char,
+        }
+    }
+}
+
+/// Unshifted and shifted characters, indexed by key code
+const KEYMAP: [(u8, u8); 58] = [
+    (0, 0), (0x1b, 0x1b),
+    (b'1', b'!'), (b'2', b'@'), (b'3', b'#'), (b'4', b'$'), (b'5', b'%'),
+    (b'6', b'^'), (b'7', b'&'), (b'8', b'*'), (b'9', b'('), (b'0', b')'),
+    (b'-', b'_'), (b'=', b'+'), (0x08, 0x08), (b'\t', b'\t'),
+    (b'q', b'Q'), (b'w', b'W'), (b'e', b'E'), (b'r', b'R'), (b't', b'T'),
+    (b'y', b'Y'), (b'u', b'U'), (b'i', b'I'), (b'o', b'O'), (b'p', b'P'),
+    (b'[', b'{'), (b']', b'}'), (b'\r', b'\r'), (0, 0),
+    (b'a', b'A'), (b's', b'S'), (b'd', b'D'), (b'f', b'F'), (b'g', b'G'),
+    (b'h', b'H'), (b'j', b'J'), (b'k', b'K'), (b'l', b'L'),
+    (b';', b':'), (b'\'', b'"'), (b'`', b'~'), (0, 0), (b'\\', b'|'),
+    (b'z', b'Z'), (b'x', b'X'), (b'c', b'C'), (b'v', b'V'), (b'b', b'B'),
+    (b'n', b'N'), (b'm', b'M'), (b',', b'<'), (b'.', b'>'), (b'/', b'?'),
+    (0, 0), (b'*', b'*'), (0, 0), (b' ', b' '),
+];
+
+/// Returns the character a key produces with the given modifiers held
+pub fn translate(code: u16, modifiers: Modifiers) -> Option<u8> {
+    let (lower, upper) = *KEYMAP.get(code as usize)?;
+
+    if lower == 0 {
+        return None;
+    }
+
+    // Caps lock only affects letters, and shift undoes it
+    let shift = match lower.is_ascii_lowercase() && modifiers.contains(Modifiers::CAPS_LOCK) {
+        true => !modifiers.contains(Modifiers::SHIFT),
+        false => modifiers.contains(Modifiers::SHIFT),
+    };
+
+    let char = match shift {
+        true => upper,
+        false => lower,
+    };
+
+    // Control maps letters and a few symbols onto the C0 control codes
+    match modifiers.contains(Modifiers::CTRL) {
+        true if char.is_ascii_alphabetic() || (b'@'..=b'_').contains(&char) => Some(char.to_ascii_uppercase() & 0x1f),
+        _ => Some(char),
+    }
+}
@@ -1,191 +1,240 @@
-use core::sync::atomic::AtomicPtr;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use spin::Mutex;
 
-use crate::{println, print};
+use crate::{memory::DmaRegion, println};
+
+use super::{
+    queue::{Buffer, BufferDirection, Token, Virtqueue},
+    setup::{features::{RING_EVENT_IDX, RING_PACKED}, init_device, VirtioInitError},
+    transport::VirtioTransport,
+};
 
 pub mod structs;
+pub mod keymap;
+pub mod caps;
+
+pub static INPUT_DEVS: Mutex<Vec<Input>> = Mutex::new(Vec::new());
+
+/// Where the pointing devices have moved the pointer, shared between all of them
+pub static POINTER: Mutex<PointerState> = Mutex::new(PointerState::new());
 
-pub static INPUT_DEV: AtomicPtr<Input> = AtomicPtr::new(core::ptr::null_mut());
+/// Queue the device posts input events to
+const EVENT_QUEUE: u16 = 0;
+
+/// Absolute positions are scaled to `0..=POINTER_MAX` on each axis
+pub const POINTER_MAX: i32 = 0xffff;
 
 /// # Safety
 /// Only called once per input device
-pub unsafe fn init(device_ptr: *mut super::VirtIOHeader) {
-    let device = Input::new(device_ptr, 8);
+pub unsafe fn init(transport: Box<dyn VirtioTransport>) {
+    let mut device = match Input::new(transport, 64) {
+        Ok(device) => device,
+        Err(e) => {
+            println!("Failed to initialize input device: {:?}", e);
+            return;
+        }
+    };
 
-    let boxed = alloc::boxed::Box::new(device);
-    let dev_ref = alloc::boxed::Box::leak(boxed);
+    println!("Input device {:?} is a {:?}", device.caps.name, device.kind);
+    if let Some(ids) = device.caps.ids {
+        println!("    bus {:#x} vendor {:#x} product {:#x} version {:#x}", ids.bustype, ids.vendor, ids.product, ids.version);
+    }
 
-    INPUT_DEV.store(dev_ref, core::sync::atomic::Ordering::Relaxed);
+    device.fill_events();
 
-    println!("Name command sending");
-    dev_ref.command(structs::InputConfigSelect::IDName, 0);
+    // The interrupt handler walks the list
+    crate::traps::without_interrupts(|| INPUT_DEVS.lock().push(device));
+}
 
-    /*for i in 0..8 {
-    
-        let flag = if i != 7 {
-            super::splitqueue::DescriptorFlags::NEXT |
-            super::splitqueue::DescriptorFlags::WRITE
-        } else {
-            super::splitqueue::DescriptorFlags::WRITE
-        };
+#[derive(Debug, Clone, Copy)]
+pub struct PointerState {
+    /// For a mouse this is the sum of its movement, for a tablet it's scaled to `0..=POINTER_MAX`
+    pub x: i32,
+    pub y: i32,
+    pub wheel: i32,
+    pub buttons: Buttons,
+}
+
+impl PointerState {
+    const fn new() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            wheel: 0,
+            buttons: Buttons::empty(),
+        }
+    }
+}
 
-        let section = crate::memory::DmaRegion::<structs::InputEvent>::new_raw(
-            (), 
-            true
-        );
-
-        dev_ref.eventqueue.descriptors.write(index, super::splitqueue::VirtqueueDescriptor {
-            address: section.physical_address(),
-            length: core::mem::size_of::<structs::InputEvent>() as u32,
-            flags: flag,
-            next: super::splitqueue::SplitqueueIndex::new(i + 1),
-        });
-    }*/
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Buttons: u8 {
+        const LEFT   = 1 << 0;
+        const RIGHT  = 1 << 1;
+        const MIDDLE = 1 << 2;
+    }
 }
 
 pub struct Input {
-    pub header: *mut super::VirtIOHeader,
-    pub eventqueue: super::splitqueue::SplitVirtqueue,
-    pub statusqueue: super::splitqueue::SplitVirtqueue,
+    pub transport: Box<dyn VirtioTransport>,
+    pub eventqueue: Virtqueue,
+    pub statusqueue: Virtqueue,
+    pub caps: caps::Capabilities,
+    pub kind: caps::InputKind,
+
+    /// One event buffer per event queue descriptor
+    events: DmaRegion<[structs::InputEvent]>,
+    /// Which event buffer each chain handed to the device holds
+    in_flight: BTreeMap<Token, usize>,
+    keyboard: keymap::Keyboard,
 }
 
+unsafe impl Send for Input {}
+
 impl Input {
     /// # Safety
     /// Only call once per virtio device
-    pub unsafe fn new(header: *mut super::VirtIOHeader, queue_size: usize) -> Self {
-        use super::StatusFlag;
-
-        let event = super::splitqueue::SplitVirtqueue::new(queue_size).unwrap();
-        let status = super::splitqueue::SplitVirtqueue::new(queue_size).unwrap();
+    pub unsafe fn new(transport: Box<dyn VirtioTransport>, queue_size: usize) -> Result<Self, VirtioInitError> {
+        let (_, mut queues) = init_device(&*transport, RING_EVENT_IDX | RING_PACKED, 0, &[queue_size, queue_size])?;
+        let status = queues.remove(1);
+        let event = queues.remove(0);
 
-        let new_self = Self {
-            header,
+        let mut new_self = Self {
+            transport,
             eventqueue: event,
             statusqueue: status,
+            caps: caps::Capabilities::default(),
+            kind: caps::InputKind::Unknown,
+            events: DmaRegion::zeroed_many(queue_size).assume_init(),
+            in_flight: BTreeMap::new(),
+            keyboard: keymap::Keyboard::new(),
         };
 
-        (*new_self.header).status.reset();
-        (*new_self.header).status.set_flag(StatusFlag::Acknowledge);
-        (*new_self.header).status.set_flag(StatusFlag::Driver);
-
-        //Negotiate features
-        (*new_self.header).driver_feat_sel.write(0);
-        (*new_self.header).dev_feat_sel.write(0);
-        (*new_self.header).driver_feat.write(0);
+        let caps = caps::Capabilities::discover(|select, subsel| new_self.query(select, subsel));
+        new_self.kind = caps.kind();
+        new_self.caps = caps;
 
-        //check that device likes the features
-        (*new_self.header).status.set_flag(StatusFlag::FeaturesOk);
-        if !(*new_self.header).status.is_set(StatusFlag::FeaturesOk) {
-            println!("Features not accepted");
-        }
-
-        (*new_self.header).queue_sel.write(0);
-        (*new_self.header).queue_desc.set(new_self.eventqueue.descriptors.physical_address());
-        (*new_self.header).queue_avail.set(new_self.eventqueue.available.physical_address());
-        (*new_self.header).queue_used.set(new_self.eventqueue.used.physical_address());
-        (*new_self.header).queue_size.write(new_self.eventqueue.queue_size());
-        (*new_self.header).queue_ready.ready();
+        Ok(new_self)
+    }
 
+    /// Reads a configuration field, returning its size and contents
+    pub fn query(&self, select: structs::InputConfigSelect, subsel: u8) -> (u8, [u8; 128]) {
+        self.transport.write_config(0, &[select as u8, subsel]);
 
-        (*new_self.header).queue_sel.write(1);
-        (*new_self.header).queue_desc.set(new_self.statusqueue.descriptors.physical_address());
-        (*new_self.header).queue_avail.set(new_self.statusqueue.available.physical_address());
-        (*new_self.header).queue_used.set(new_self.statusqueue.used.physical_address());
-        (*new_self.header).queue_size.write(new_self.statusqueue.queue_size());
-        (*new_self.header).queue_ready.ready();
+        let mut size = [0];
+        let mut data = [0; 128];
 
-        (*new_self.header).status.set_flag(StatusFlag::DriverOk);
+        self.transport.read_config_consistent(2, &mut size);
+        self.transport.read_config_consistent(8, &mut data[..size[0] as usize]);
 
-        if (*new_self.header).status.failed() {
-            println!("Input Error during queue setup");
-        }
+        (size[0], data)
+    }
 
-        new_self
+    /// Hands every event buffer to the device
+    fn fill_events(&mut self) {
+        let slots: Vec<usize> = (0..self.events.len()).collect();
+        self.post_events(&slots);
     }
 
-    pub fn command(&mut self, select: structs::InputConfigSelect, subsel: u8) {
-        let command = structs::InputConfig {
-            select,
-            subsel,
-            size: 0,
-            _reserved: [0; 5],
-            union: structs::InputConfigUnion {
-                bitmap: [0; 128]
-            }
-        };
+    /// Hands event buffers to the device in one batch, notifying it at most once
+    fn post_events(&mut self, slots: &[usize]) {
+        let size = core::mem::size_of::<structs::InputEvent>();
+
+        let chains: Vec<[Buffer; 1]> = slots.iter()
+            .map(|slot| {
+                let address = self.events.physical_address().add((slot * size) as u64);
+                [Buffer::new(address, size, BufferDirection::DeviceWrites)]
+            })
+            .collect();
+        let chains: Vec<&[Buffer]> = chains.iter().map(|chain| &chain[..]).collect();
+
+        // There's a descriptor for every event buffer, so they all fit unless the queue is broken
+        let tokens = self.eventqueue.submit_batch(&*self.transport, EVENT_QUEUE, &chains);
+        self.in_flight.extend(tokens.into_iter().zip(slots.iter().copied()));
+    }
 
-        unsafe {
-            println!("Storing command");
-            let mut dma_region: crate::memory::DmaRegion<structs::InputConfig> = crate::memory::DmaRegion::new_raw((), true);
-            *dma_region = command;
+    /// Handles the events the device has posted, and gives their buffers back to it
+    fn process_events(&mut self) {
+        let mut recycled = Vec::new();
 
-            let descriptor = super::splitqueue::VirtqueueDescriptor {
-                address: dma_region.physical_address(),
-                length: core::mem::size_of::<structs::InputConfig>() as u32,
-                flags: super::splitqueue::DescriptorFlags::WRITE,
-                next: super::splitqueue::SplitqueueIndex::new(0),
+        while let Some((token, _)) = self.eventqueue.pop_used() {
+            let Some(slot) = self.in_flight.remove(&token) else {
+                continue;
             };
 
-            println!("Allocating descriptor");
-            let idx = self.statusqueue.alloc_descriptor().unwrap();
-            self.statusqueue.descriptors.write(idx, descriptor);
+            let event = unsafe { core::ptr::read_volatile(&self.events[slot]) };
 
-            println!("Storing available");
-            self.statusqueue.available.push(super::splitqueue::SplitqueueIndex::new(0));
+            self.event(event);
 
-            println!("Notifying");
-            (*self.header).queue_notify.notify(1);
+            recycled.push(slot);
         }
-    }
-}
-
-pub fn handle_int(_id: usize) {
-    use structs::InputConfigSelect;
 
-    let input = INPUT_DEV.load(core::sync::atomic::Ordering::Relaxed);
-    let input = unsafe {&mut *input};
-
-    unsafe {
-        println!("Input int");
-        let status = (*input.header).int_status.read();
+        if !recycled.is_empty() {
+            self.post_events(&recycled);
+        }
+    }
 
-        let index = input.statusqueue.used.pop();
+    fn event(&mut self, event: structs::InputEvent) {
+        match event.event_type {
+            structs::EV_KEY => {
+                let button = match event.code {
+                    caps::BTN_LEFT => Buttons::LEFT,
+                    caps::BTN_RIGHT => Buttons::RIGHT,
+                    caps::BTN_MIDDLE => Buttons::MIDDLE,
+                    _ => Buttons::empty(),
+                };
+
+                if !button.is_empty() {
+                    POINTER.lock().buttons.set(button, event.val != 0);
+                    return;
+                }
 
-        let desc = match index {
-            Some(index) => {
-                let index = super::splitqueue::SplitqueueIndex::new(index.start_index as u16);
+                let key = self.keyboard.key(event.code, event.val);
 
-                input.statusqueue.descriptors.read(index)
+                if let Some(char) = key.char {
+                    crate::uart::deliver_char(char);
+                }
             },
-            None => {
-                let index = input.eventqueue.used.pop().unwrap_or_else(|| {panic!("No used entry")});
-                let index = super::splitqueue::SplitqueueIndex::new(index.start_index as u16);
-
-                input.eventqueue.descriptors.read(index)
-            }
-        };
-
-        let base = desc.address.0 + crate::memory::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
-        let ptr = base as *mut structs::InputConfig;
-
-        println!("Handling input int on config {:?}", (*ptr).select);
-
-        match (*ptr).select {
-            InputConfigSelect::IDName => {
-                if (*ptr).size != 0 {
-                    println!("Reading name");
-        
-                    for index in 0..(*ptr).size {
-                        print!("{}", (*ptr).union.string[index as usize] as char);
-                    }
-        
-                    println!();
-                } else {
-                    println!("No data sent for name");
+            structs::EV_REL => {
+                let delta = event.val as i32;
+                let mut pointer = POINTER.lock();
+
+                match event.code {
+                    caps::AXIS_X => pointer.x = pointer.x.wrapping_add(delta),
+                    caps::AXIS_Y => pointer.y = pointer.y.wrapping_add(delta),
+                    caps::REL_WHEEL => pointer.wheel = pointer.wheel.wrapping_add(delta),
+                    _ => {},
+                }
+            },
+            structs::EV_ABS => {
+                let Some(info) = self.caps.abs_info(event.code) else {
+                    return;
+                };
+
+                // Scale into the shared range so tablets of any resolution agree
+                let min = info.min as i32 as i64;
+                let max = info.max as i32 as i64;
+                let range = (max - min).max(1);
+                let val = ((event.val as i32 as i64 - min).clamp(0, range) * POINTER_MAX as i64 / range) as i32;
+
+                let mut pointer = POINTER.lock();
+                match event.code {
+                    caps::AXIS_X => pointer.x = val,
+                    caps::AXIS_Y => pointer.y = val,
+                    _ => {},
                 }
             },
-            req => {panic!("Cant handle request `{:?}` yet", req)}
+            _ => {},
         }
+    }
+}
 
-        (*input.header).int_ack.ack(status);
+pub fn handle_int(_id: usize) {
+    for input in INPUT_DEVS.lock().iter_mut() {
+        input.transport.ack_interrupt();
+        input.process_events();
+
+        // The device hands status buffers back too, but we never send any
+        while input.statusqueue.pop_used().is_some() {}
     }
-}
\ No newline at end of file
+}
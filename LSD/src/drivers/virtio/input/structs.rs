@@ -11,7 +11,7 @@ pub enum InputConfigSelect {
 }
 
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct InputABSInfo {
     pub min: u32,
     pub max: u32,
@@ -21,7 +21,7 @@ pub struct InputABSInfo {
 }
 
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct InputDevIDs {
     pub bustype: u16,
     pub vendor: u16,
@@ -48,7 +48,17 @@ pub union InputConfigUnion {
     pub ids: InputDevIDs,
 }
 
+/// Synchronization, separates groups of events
+pub const EV_SYN: u16 = 0x00;
+/// Key or button state change
+pub const EV_KEY: u16 = 0x01;
+/// Relative axis movement
+pub const EV_REL: u16 = 0x02;
+/// Absolute axis position
+pub const EV_ABS: u16 = 0x03;
+
 #[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct InputEvent {
     pub event_type: u16,
     pub code: u16,
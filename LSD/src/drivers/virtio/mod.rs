@@ -3,14 +3,24 @@
 
 use core::sync::atomic::AtomicPtr;
 
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec::Vec};
 use spin::Mutex;
 
 use crate::{volatile::*, println, current_context};
 
+use transport::VirtioTransport;
+
+pub mod queue;
+pub mod event_idx;
 pub mod splitqueue;
+pub mod packedqueue;
 pub mod input;
 pub mod entropy;
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod transport;
+pub mod setup;
 
 pub static VIRTIO_LIST: Mutex<Vec<(AtomicPtr<VirtIOHeader>, &mut [usize])>> = Mutex::new(Vec::new());
 
@@ -20,43 +30,70 @@ pub unsafe fn init() {
     for device_atom_ptr in VIRTIO_LIST.lock().iter() {
         let device_ptr = device_atom_ptr.0.load(core::sync::atomic::Ordering::Relaxed);
         let slice = &device_atom_ptr.1;
-        match (*device_ptr).dev_id.read() {
-            DeviceType::Input => {
-                println!("Found input device");
-
-                let plic = crate::traps::plic::PLIC_ADDR.load(core::sync::atomic::Ordering::Relaxed);
-
-                for int in slice.iter() {
-                    println!("Found input interrupt 0x{:x}", int);
-                    (*plic).enable_interrupt(current_context(), *int);
-                    (*plic).set_interrupt_priority(*int, 0x2);
 
-                    crate::traps::plic::INT_HANDLERS.lock()[*int] = input::handle_int;
-                }
+        let transport = transport::mmio::MmioTransport::new(device_ptr);
 
-                input::init(device_ptr);
-            },
-            DeviceType::Entropy => {
-                println!("Found entropy device at {:?}", device_ptr);
+        let plic = crate::traps::plic::PLIC_ADDR.load(core::sync::atomic::Ordering::Relaxed);
 
-                let plic = crate::traps::plic::PLIC_ADDR.load(core::sync::atomic::Ordering::Relaxed);
+        if let Some(handler) = transport.device_type().and_then(int_handler).filter(|_| !plic.is_null()) {
+            for int in slice.iter() {
+                println!("Found virtio interrupt 0x{:x}", int);
+                (*plic).enable_interrupt(current_context(), *int);
+                (*plic).set_interrupt_priority(*int, 0x2);
 
-                for int in slice.iter() {
-                    println!("Found entropy interrupt 0x{:x}", int);
-                    //(*plic).enable_interrupt(current_context(), *int);
-                    //(*plic).set_interrupt_priority(*int, 0x2);
+                crate::traps::plic::add_handler(*int, handler);
+            }
+        }
 
-                    //crate::traps::plic::INT_HANDLERS.lock()[*int] = ;
-                }
+        attach(Box::new(transport));
+    }
+}
 
-                //let entropy = entropy::Entropy::init(device_ptr);
-                //entropy.request(16);
+/// Returns the interrupt handler for the driver of a device type, if it takes interrupts
+pub fn int_handler(dev_type: DeviceType) -> Option<fn(usize)> {
+    match dev_type {
+        DeviceType::Input => Some(input::handle_int),
+        DeviceType::Entropy => Some(entropy::handle_int),
+        DeviceType::Block => Some(block::handle_int),
+        DeviceType::Console => Some(console::handle_int),
+        DeviceType::Network => Some(net::handle_int),
+        _ => None,
+    }
+}
 
-                //panic!("Entropy over")
-            },
-            dev_type => {
-                println!("Unsupported device type {:?}", dev_type);
-            }
+/// Brings up the driver for whichever device is behind the transport
+///
+/// # Safety
+/// Only call once per device, after its interrupt is routed to `int_handler`
+pub unsafe fn attach(transport: Box<dyn VirtioTransport>) {
+    match transport.device_type() {
+        Some(DeviceType::Input) => {
+            println!("Found input device");
+
+            input::init(transport);
+        },
+        Some(DeviceType::Entropy) => {
+            println!("Found entropy device");
+
+            entropy::init(transport);
+        },
+        Some(DeviceType::Block) => {
+            println!("Found block device");
+
+            block::init(transport);
+        },
+        Some(DeviceType::Console) => {
+            println!("Found console device");
+
+            console::init(transport);
+        },
+        Some(DeviceType::Network) => {
+            println!("Found network device");
+
+            net::init(transport);
+        },
+        dev_type => {
+            println!("Unsupported device type {:?}", dev_type);
         }
     }
 }
@@ -135,6 +172,25 @@ pub enum DeviceType {
     Cryptography = 20,
 }
 
+impl DeviceType {
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Self::Reserved),
+            1 => Some(Self::Network),
+            2 => Some(Self::Block),
+            3 => Some(Self::Console),
+            4 => Some(Self::Entropy),
+            5 => Some(Self::TradMemBalloon),
+            8 => Some(Self::SCSIHost),
+            16 => Some(Self::GPU),
+            18 => Some(Self::Input),
+            19 => Some(Self::Socket),
+            20 => Some(Self::Cryptography),
+            _ => None,
+        }
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct QueueSize(Volatile<u32, Write>);
@@ -206,6 +262,14 @@ impl QueueReady {
 pub struct Status(Volatile<u32, ReadWrite>);
 
 impl Status {
+    pub fn read(&self) -> u32 {
+        self.0.read()
+    }
+
+    pub fn write(&self, val: u32) {
+        self.0.write(val);
+    }
+
     pub fn reset(&self) {
         self.0.write(0);
     }
@@ -0,0 +1,245 @@
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+
+use crate::{memory::DmaRegion, println};
+
+use super::{
+    queue::{Buffer, BufferDirection, Token, Virtqueue},
+    setup::{features::RING_PACKED, init_device, VirtioInitError},
+    transport::VirtioTransport,
+};
+
+pub static NET_DEV: Mutex<Option<Net>> = Mutex::new(None);
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+const QUEUE_SIZE: usize = 32;
+
+/// Room for the header and a full sized Ethernet frame, since we don't negotiate mergeable buffers
+const SLOT_LEN: usize = 2048;
+
+/// Feature bits specific to network devices
+pub mod features {
+    /// `mac` holds the device's address
+    pub const MAC: u64 = 1 << 5;
+    /// `status` holds the link state
+    pub const STATUS: u64 = 1 << 16;
+}
+
+/// Bits of the `status` field
+const STATUS_LINK_UP: u16 = 1;
+
+/// Size of the header in front of every frame
+///
+/// This is the virtio 1.0 layout, which always includes `num_buffers`. We don't offload anything, so it's
+/// sent as zeroes and skipped when received.
+const HEADER_LEN: usize = 12;
+
+pub struct Net {
+    transport: Box<dyn VirtioTransport>,
+    features: u64,
+    pub mac: [u8; 6],
+
+    rx: Virtqueue,
+    tx: Virtqueue,
+
+    /// One buffer per descriptor of each queue
+    rx_slots: DmaRegion<[u8]>,
+    tx_slots: DmaRegion<[u8]>,
+
+    /// Which slot each chain handed to the device holds
+    rx_in_flight: BTreeMap<Token, usize>,
+    tx_in_flight: BTreeMap<Token, usize>,
+    /// Transmit slots the device doesn't have
+    tx_free: Vec<usize>,
+}
+
+unsafe impl Send for Net {}
+
+impl Net {
+    /// # Safety
+    /// Only call once per device
+    pub unsafe fn init(transport: Box<dyn VirtioTransport>) -> Result<Net, VirtioInitError> {
+        let (features, mut queues) = init_device(
+            &*transport,
+            features::MAC | features::STATUS | RING_PACKED,
+            0,
+            &[QUEUE_SIZE, QUEUE_SIZE],
+        )?;
+        let tx = queues.remove(1);
+        let rx = queues.remove(0);
+
+        let mut mac = [0; 6];
+        match features & features::MAC != 0 {
+            true => transport.read_config_consistent(0, &mut mac),
+            // Locally administered, so it can't clash with a real address
+            false => mac = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+        }
+
+        let mut new_self = Self {
+            transport,
+            features,
+            mac,
+            rx,
+            tx,
+            rx_slots: DmaRegion::new_raw(QUEUE_SIZE * SLOT_LEN, true),
+            tx_slots: DmaRegion::new_raw(QUEUE_SIZE * SLOT_LEN, true),
+            rx_in_flight: BTreeMap::new(),
+            tx_in_flight: BTreeMap::new(),
+            tx_free: (0..QUEUE_SIZE).collect(),
+        };
+
+        new_self.fill_rx();
+
+        Ok(new_self)
+    }
+
+    /// Whether the link is up, devices that don't report it are assumed to be
+    pub fn link_up(&self) -> bool {
+        if self.features & features::STATUS == 0 {
+            return true;
+        }
+
+        let mut status = [0; 2];
+        self.transport.read_config_consistent(6, &mut status);
+
+        u16::from_le_bytes(status) & STATUS_LINK_UP != 0
+    }
+
+    /// Hands every receive buffer to the device
+    fn fill_rx(&mut self) {
+        for slot in 0..QUEUE_SIZE {
+            self.post_rx(slot);
+        }
+
+        self.transport.notify(RX_QUEUE);
+    }
+
+    fn post_rx(&mut self, slot: usize) {
+        let address = self.rx_slots.physical_address().add((slot * SLOT_LEN) as u64);
+        let buffer = Buffer::new(address, SLOT_LEN, BufferDirection::DeviceWrites);
+
+        // There's a descriptor for every slot, so this only fails if the queue is broken
+        if let Ok(token) = self.rx.add_chain(&[buffer]) {
+            self.rx_in_flight.insert(token, slot);
+        }
+    }
+
+    /// Copies out every frame the device has received, without their headers, and gives the buffers back
+    pub fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+
+        while let Some((token, written)) = self.rx.pop_used() {
+            let Some(slot) = self.rx_in_flight.remove(&token) else {
+                continue;
+            };
+
+            let start = slot * SLOT_LEN;
+            let len = (written as usize).min(SLOT_LEN);
+
+            if len > HEADER_LEN {
+                frames.push(self.rx_slots[start + HEADER_LEN..start + len].to_vec());
+            }
+
+            self.post_rx(slot);
+        }
+
+        if !frames.is_empty() {
+            self.transport.notify(RX_QUEUE);
+        }
+
+        frames
+    }
+
+    /// Takes back every buffer the device has finished sending
+    fn reclaim_tx(&mut self) {
+        while let Some((token, _)) = self.tx.pop_used() {
+            if let Some(slot) = self.tx_in_flight.remove(&token) {
+                self.tx_free.push(slot);
+            }
+        }
+    }
+
+    /// Queues an Ethernet frame to be sent, returning `false` if it's too large or the queue is full
+    pub fn send(&mut self, frame: &[u8]) -> bool {
+        if HEADER_LEN + frame.len() > SLOT_LEN {
+            return false;
+        }
+
+        self.reclaim_tx();
+
+        let Some(slot) = self.tx_free.pop() else {
+            return false;
+        };
+
+        let start = slot * SLOT_LEN;
+        let buffer = &mut self.tx_slots.get_mut()[start..start + HEADER_LEN + frame.len()];
+
+        // No checksum offload or segmentation, so the header is all zeroes
+        buffer[..HEADER_LEN].fill(0);
+        buffer[HEADER_LEN..].copy_from_slice(frame);
+
+        let address = self.tx_slots.physical_address().add(start as u64);
+        let buffer = Buffer::new(address, HEADER_LEN + frame.len(), BufferDirection::DeviceReads);
+
+        match self.tx.add_chain(&[buffer]) {
+            Ok(token) => {
+                self.tx_in_flight.insert(token, slot);
+                self.transport.notify(TX_QUEUE);
+
+                true
+            },
+            Err(_) => {
+                self.tx_free.push(slot);
+
+                false
+            },
+        }
+    }
+}
+
+/// # Safety
+/// Only call once per network device
+pub unsafe fn init(transport: Box<dyn VirtioTransport>) {
+    match Net::init(transport) {
+        Ok(net) => {
+            let mac = net.mac;
+            println!(
+                "virtio-net {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, link {}",
+                mac[0], mac[1], mac[2], mac[3], mac[4], mac[5],
+                if net.link_up() { "up" } else { "down" },
+            );
+
+            // The interrupt handler takes the lock too
+            crate::traps::without_interrupts(|| *NET_DEV.lock() = Some(net));
+            crate::net::interface_up(mac);
+        },
+        Err(e) => println!("Failed to initialize network device: {:?}", e),
+    }
+}
+
+/// Queues an Ethernet frame on the network device, returning `false` if it couldn't be
+pub fn transmit(frame: &[u8]) -> bool {
+    crate::traps::without_interrupts(|| {
+        NET_DEV.lock().as_mut().is_some_and(|net| net.send(frame))
+    })
+}
+
+pub fn handle_int(_id: usize) {
+    let frames = {
+        let mut lock = NET_DEV.lock();
+        let Some(net) = lock.as_mut() else {
+            return;
+        };
+
+        net.transport.ack_interrupt();
+        net.reclaim_tx();
+        net.receive()
+    };
+
+    // Replies go back out through the device, so it can't be locked while the stack runs
+    for frame in frames {
+        crate::net::receive(&frame);
+    }
+}
@@ -0,0 +1,317 @@
+//! Packed virtqueues, where the driver and device share a single ring of descriptors
+//!
+//! Which descriptors are available and which are used is tracked with a wrap counter on each side, flipped
+//! every time that side passes the end of the ring. A descriptor is available when its AVAIL flag matches the
+//! driver's wrap counter and its USED flag doesn't, and used once both match the device's.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::memory::{DmaRegion, PhysicalAddress};
+
+use super::queue::{Buffer, BufferDirection, Token, VirtqueueError};
+
+/// Descriptor flags
+mod flags {
+    pub const NEXT: u16 = 1 << 0;
+    pub const WRITE: u16 = 1 << 1;
+    pub const INDIRECT: u16 = 1 << 2;
+    pub const AVAIL: u16 = 1 << 7;
+    pub const USED: u16 = 1 << 15;
+}
+
+/// Most buffers a chain can have and still go in an indirect table, longer chains take ring entries directly
+const INDIRECT_LEN: usize = 8;
+
+/// Event suppression flags
+pub mod event {
+    /// Send events
+    pub const ENABLE: u16 = 0;
+    /// Don't send events
+    pub const DISABLE: u16 = 1;
+    /// Send an event once the descriptor in `desc` is reached, only with VIRTIO_F_EVENT_IDX
+    pub const DESC: u16 = 2;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct PackedDescriptor {
+    pub address: u64,
+    pub length: u32,
+    pub id: u16,
+    pub flags: u16,
+}
+
+/// Where one side asks the other to hold off on notifications
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct EventSuppression {
+    /// Descriptor offset in the low 15 bits, wrap counter in the top bit
+    pub desc: u16,
+    pub flags: u16,
+}
+
+/// A chain handed to the device
+struct InFlight {
+    /// Ring entries the chain takes up
+    descriptors: u16,
+}
+
+pub struct PackedVirtqueue {
+    queue_size: u16,
+    ring: DmaRegion<[PackedDescriptor]>,
+    /// Written by us, read by the device
+    driver_event: DmaRegion<[EventSuppression]>,
+    /// Written by the device, read by us
+    device_event: DmaRegion<[EventSuppression]>,
+
+    /// Ring entries not in use
+    free: u16,
+    next_avail: u16,
+    avail_wrap: bool,
+    next_used: u16,
+    used_wrap: bool,
+
+    /// Where `next_avail` was when the device was last considered for a notification
+    notified: (u16, bool),
+
+    free_ids: Vec<u16>,
+    in_flight: BTreeMap<u16, InFlight>,
+
+    /// Whether chains may be placed in indirect tables, only if VIRTIO_F_RING_INDIRECT_DESC was negotiated
+    indirect: bool,
+    /// An indirect table for every buffer ID, `INDIRECT_LEN` entries each, used by the chain given that ID
+    ///
+    /// Allocated once when indirect tables are enabled, so chains don't each need memory of their own.
+    tables: Option<DmaRegion<[PackedDescriptor]>>,
+}
+
+impl PackedVirtqueue {
+    /// Packed queues don't have to be a power of two in size
+    pub fn new(queue_size: usize) -> Result<Self, VirtqueueError> {
+        if queue_size == 0 || queue_size > 32768 {
+            return Err(VirtqueueError::TooLarge);
+        }
+
+        Ok(Self {
+            queue_size: queue_size as u16,
+            ring: unsafe { DmaRegion::zeroed_many(queue_size).assume_init() },
+            driver_event: unsafe { DmaRegion::zeroed_many(1).assume_init() },
+            device_event: unsafe { DmaRegion::zeroed_many(1).assume_init() },
+            free: queue_size as u16,
+            next_avail: 0,
+            avail_wrap: true,
+            next_used: 0,
+            used_wrap: true,
+            notified: (0, true),
+            free_ids: (0..queue_size as u16).rev().collect(),
+            in_flight: BTreeMap::new(),
+            indirect: false,
+            tables: None,
+        })
+    }
+
+    pub fn queue_size(&self) -> u32 {
+        self.queue_size as u32
+    }
+
+    /// Physical addresses of the descriptor ring, the driver event suppression and the device event suppression
+    pub fn areas(&self) -> (PhysicalAddress, PhysicalAddress, PhysicalAddress) {
+        (
+            self.ring.physical_address(),
+            self.driver_event.physical_address(),
+            self.device_event.physical_address(),
+        )
+    }
+
+    pub fn set_indirect(&mut self, enabled: bool) {
+        if enabled && self.tables.is_none() {
+            self.tables = Some(unsafe { DmaRegion::zeroed_many(self.queue_size as usize * INDIRECT_LEN).assume_init() });
+        }
+
+        self.indirect = enabled;
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free as usize
+    }
+
+    /// The AVAIL and USED flags marking a descriptor available under the current wrap counter
+    fn avail_flags(&self) -> u16 {
+        match self.avail_wrap {
+            true => flags::AVAIL,
+            false => flags::USED,
+        }
+    }
+
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> Result<Token, VirtqueueError> {
+        if buffers.is_empty() {
+            return Err(VirtqueueError::EmptyChain);
+        }
+
+        let direction = |buffer: &Buffer| match buffer.direction {
+            BufferDirection::DeviceReads => 0,
+            BufferDirection::DeviceWrites => flags::WRITE,
+        };
+
+        let id = self.free_ids.pop().ok_or(VirtqueueError::QueueFull)?;
+
+        let table = self.tables.as_mut().filter(|_| self.indirect && (2..=INDIRECT_LEN).contains(&buffers.len()));
+
+        let descriptors: Vec<_> = match table {
+            Some(tables) => {
+                // The table belonging to the ID, nothing else can be using it while the ID is ours
+                let base = id as usize * INDIRECT_LEN;
+                let table_phys = tables.physical_address().0 + (base * core::mem::size_of::<PackedDescriptor>()) as u64;
+
+                // The device reads every entry of a table in order, so they don't chain with NEXT
+                for (entry, buffer) in tables.get_mut()[base..].iter_mut().zip(buffers) {
+                    *entry = PackedDescriptor {
+                        address: buffer.address.0,
+                        length: buffer.length,
+                        id,
+                        flags: direction(buffer),
+                    };
+                }
+
+                let length = (buffers.len() * core::mem::size_of::<PackedDescriptor>()) as u32;
+
+                alloc::vec![(table_phys, length, flags::INDIRECT)]
+            },
+            None => {
+                buffers.iter().enumerate()
+                    .map(|(position, buffer)| {
+                        let next = match position + 1 == buffers.len() {
+                            true => 0,
+                            false => flags::NEXT,
+                        };
+
+                        (buffer.address.0, buffer.length, direction(buffer) | next)
+                    })
+                    .collect()
+            },
+        };
+
+        if descriptors.len() > self.free as usize {
+            self.free_ids.push(id);
+            return Err(VirtqueueError::QueueFull);
+        }
+
+        let head = self.next_avail;
+        let mut head_flags = 0;
+
+        for (position, (address, length, desc_flags)) in descriptors.iter().enumerate() {
+            let flags = desc_flags | self.avail_flags();
+            let index = self.next_avail as usize;
+
+            // The head is made available last, so the device never sees part of a chain
+            let written_flags = match position {
+                0 => {
+                    head_flags = flags;
+                    0
+                },
+                _ => flags,
+            };
+
+            unsafe {
+                core::ptr::write_volatile(&mut self.ring.get_mut()[index], PackedDescriptor {
+                    address: *address,
+                    length: *length,
+                    id,
+                    flags: written_flags,
+                });
+            }
+
+            self.next_avail += 1;
+            if self.next_avail == self.queue_size {
+                self.next_avail = 0;
+                self.avail_wrap = !self.avail_wrap;
+            }
+        }
+
+        unsafe {
+            core::arch::asm!("fence");
+            core::ptr::write_volatile(&mut self.ring.get_mut()[head as usize].flags, head_flags);
+        }
+
+        self.free -= descriptors.len() as u16;
+        self.in_flight.insert(id, InFlight {
+            descriptors: descriptors.len() as u16,
+        });
+
+        Ok(Token(id))
+    }
+
+    /// Takes the next chain the device has finished with, freeing every descriptor in it
+    pub fn pop_used(&mut self) -> Option<(Token, u32)> {
+        let descriptor = unsafe { core::ptr::read_volatile(&self.ring[self.next_used as usize]) };
+
+        let avail = descriptor.flags & flags::AVAIL != 0;
+        let used = descriptor.flags & flags::USED != 0;
+
+        if avail != used || used != self.used_wrap {
+            return None;
+        }
+
+        unsafe {
+            // Don't read what the device wrote before seeing that it's done
+            core::arch::asm!("fence");
+        }
+
+        let descriptor = unsafe { core::ptr::read_volatile(&self.ring[self.next_used as usize]) };
+        let chain = self.in_flight.remove(&descriptor.id)?;
+
+        // The device writes a single used descriptor for the chain, skip past the rest of it
+        let next = self.next_used + chain.descriptors;
+        self.next_used = match next >= self.queue_size {
+            true => {
+                self.used_wrap = !self.used_wrap;
+                next - self.queue_size
+            },
+            false => next,
+        };
+
+        self.free += chain.descriptors;
+        self.free_ids.push(descriptor.id);
+
+        Some((Token(descriptor.id), descriptor.length))
+    }
+
+    /// Asks the device to hold off on used buffer notifications, or to send them again
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        let flags = match enabled {
+            true => event::ENABLE,
+            false => event::DISABLE,
+        };
+
+        unsafe { core::ptr::write_volatile(&mut self.driver_event.get_mut()[0].flags, flags) };
+    }
+
+    /// Whether the device wants to be notified of the chains made available since this was last asked
+    pub fn needs_notify(&mut self) -> bool {
+        unsafe {
+            // Our descriptors have to be visible before we look at whether the device wants to hear about them
+            core::arch::asm!("fence");
+        }
+
+        let suppression = unsafe { core::ptr::read_volatile(&self.device_event[0]) };
+
+        let old = self.notified;
+        let new = (self.next_avail, self.avail_wrap);
+        self.notified = new;
+
+        match suppression.flags {
+            event::DISABLE => false,
+            event::DESC => {
+                let event = (suppression.desc & 0x7fff, suppression.desc & 0x8000 != 0);
+
+                // Whether the descriptor the device is waiting on was made available since we last checked
+                let position = |(index, wrap): (u16, bool)| index as u32 + if wrap { 0 } else { self.queue_size as u32 };
+                let span = 2 * self.queue_size as u32;
+                let published = (position(new) + span - position(old)) % span;
+
+                (position(event) + span - position(old)) % span < published
+            },
+            _ => true,
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! The interface drivers use for a virtqueue, whichever ring layout was negotiated
+
+use alloc::vec::Vec;
+
+use crate::memory::PhysicalAddress;
+
+use super::{packedqueue::PackedVirtqueue, splitqueue::SplitVirtqueue, transport::VirtioTransport};
+
+/// Which way data in a buffer flows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferDirection {
+    DeviceReads,
+    DeviceWrites,
+}
+
+/// A buffer to place in a chain
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysicalAddress,
+    pub length: u32,
+    pub direction: BufferDirection,
+}
+
+impl Buffer {
+    pub fn new(address: PhysicalAddress, length: usize, direction: BufferDirection) -> Self {
+        Self {
+            address,
+            length: length as u32,
+            direction,
+        }
+    }
+
+    /// The whole of a DMA region
+    pub fn from_region<T: ?Sized>(region: &crate::memory::DmaRegion<T>, direction: BufferDirection) -> Self {
+        Self::new(region.physical_address(), core::mem::size_of_val::<T>(&**region), direction)
+    }
+}
+
+/// Identifies a chain handed to the device, returned again once the device is done with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Token(pub(super) u16);
+
+impl Token {
+    /// Unique among the chains the device has at any one time, the first descriptor of a split queue chain and
+    /// the buffer ID of a packed queue one
+    pub fn index(&self) -> u16 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum VirtqueueError {
+    MemoryAllocationError,
+    NotPowerOfTwo,
+    TooLarge,
+    /// There aren't enough free descriptors for the chain
+    QueueFull,
+    /// A chain needs at least one buffer
+    EmptyChain,
+}
+
+pub enum Virtqueue {
+    Split(SplitVirtqueue),
+    Packed(PackedVirtqueue),
+}
+
+impl Virtqueue {
+    /// Allocates a queue with the layout negotiated, packed if VIRTIO_F_RING_PACKED was
+    pub fn new(queue_size: usize, packed: bool) -> Result<Self, VirtqueueError> {
+        match packed {
+            true => PackedVirtqueue::new(queue_size).map(Self::Packed),
+            false => SplitVirtqueue::new(queue_size).map(Self::Split),
+        }
+    }
+
+    pub fn queue_size(&self) -> u32 {
+        match self {
+            Self::Split(queue) => queue.queue_size(),
+            Self::Packed(queue) => queue.queue_size(),
+        }
+    }
+
+    /// Physical addresses of the descriptor area, the driver area and the device area, in that order
+    pub fn areas(&self) -> (PhysicalAddress, PhysicalAddress, PhysicalAddress) {
+        match self {
+            Self::Split(queue) => (
+                queue.descriptors.physical_address(),
+                queue.available.physical_address(),
+                queue.used.physical_address(),
+            ),
+            Self::Packed(queue) => queue.areas(),
+        }
+    }
+
+    /// Lets chains of more than one buffer use an indirect table, taking a single descriptor in the ring
+    ///
+    /// Only enable this once VIRTIO_F_RING_INDIRECT_DESC has been negotiated.
+    pub fn set_indirect(&mut self, enabled: bool) {
+        match self {
+            Self::Split(queue) => queue.set_indirect(enabled),
+            Self::Packed(queue) => queue.set_indirect(enabled),
+        }
+    }
+
+    /// Suppresses notifications with event indexes rather than flags
+    ///
+    /// Only enable this once VIRTIO_F_EVENT_IDX has been negotiated. Packed queues keep using flags on our side,
+    /// which the device is still bound by, and honour the device's event index either way.
+    pub fn set_event_idx(&mut self, enabled: bool) {
+        if let Self::Split(queue) = self {
+            queue.set_event_idx(enabled);
+        }
+    }
+
+    /// Number of descriptors not in use
+    pub fn free_count(&self) -> usize {
+        match self {
+            Self::Split(queue) => queue.free_count(),
+            Self::Packed(queue) => queue.free_count(),
+        }
+    }
+
+    /// Places a chain of buffers on the queue and makes it available to the device, without notifying it
+    ///
+    /// Buffers the device reads have to come before the buffers it writes.
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> Result<Token, VirtqueueError> {
+        match self {
+            Self::Split(queue) => queue.add_chain(buffers),
+            Self::Packed(queue) => queue.add_chain(buffers),
+        }
+    }
+
+    /// Places every chain on the queue and makes them available to the device, then notifies it once if it
+    /// asks to be
+    ///
+    /// Stops at the first chain that doesn't fit, returning the tokens of the chains before it.
+    pub fn submit_batch(&mut self, transport: &dyn VirtioTransport, index: u16, chains: &[&[Buffer]]) -> Vec<Token> {
+        let tokens = match self {
+            Self::Split(queue) => queue.add_chains(chains),
+            Self::Packed(queue) => chains.iter().map_while(|buffers| queue.add_chain(buffers).ok()).collect(),
+        };
+
+        if !tokens.is_empty() {
+            self.notify(transport, index);
+        }
+
+        tokens
+    }
+
+    /// Notifies the device at queue `index` of the chains made available, unless it has asked not to be
+    pub fn notify(&mut self, transport: &dyn VirtioTransport, index: u16) {
+        if self.needs_notify() {
+            transport.notify(index);
+        }
+    }
+
+    /// Takes the next chain the device has finished with, freeing every descriptor in it
+    ///
+    /// Returns the chain's token and how many bytes the device wrote into it.
+    pub fn pop_used(&mut self) -> Option<(Token, u32)> {
+        match self {
+            Self::Split(queue) => queue.pop_used(),
+            Self::Packed(queue) => queue.pop_used(),
+        }
+    }
+
+    /// Asks the device to hold off on interrupts for this queue, or to send them again
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        match self {
+            Self::Split(queue) => queue.set_interrupts(enabled),
+            Self::Packed(queue) => queue.set_interrupts(enabled),
+        }
+    }
+
+    /// Whether the device wants to be notified of the chains made available since this was last asked
+    pub fn needs_notify(&mut self) -> bool {
+        match self {
+            Self::Split(queue) => queue.needs_notify(),
+            Self::Packed(queue) => queue.needs_notify(),
+        }
+    }
+}
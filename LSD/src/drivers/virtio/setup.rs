@@ -0,0 +1,128 @@
+use alloc::vec::Vec;
+
+use super::{queue::Virtqueue, transport::VirtioTransport, StatusFlag};
+
+/// Feature bits shared by every device type
+pub mod features {
+    /// Descriptors may point at a table of further descriptors
+    pub const RING_INDIRECT_DESC: u64 = 1 << 28;
+    /// The used and available rings carry event indexes to suppress notifications
+    pub const RING_EVENT_IDX: u64 = 1 << 29;
+    /// The device follows the virtio 1.0 specification, rather than the legacy interface
+    pub const VERSION_1: u64 = 1 << 32;
+    /// Queues use the packed layout, a single ring shared by the driver and device
+    pub const RING_PACKED: u64 = 1 << 34;
+}
+
+/// Why a device couldn't be brought up
+#[derive(Debug, Clone, Copy)]
+pub enum VirtioInitError {
+    /// The device doesn't offer features the driver can't work without
+    MissingFeatures(u64),
+    /// The device cleared FEATURES_OK, refusing the features we accepted
+    FeaturesRejected,
+    /// The device has no queue at this index
+    QueueUnavailable(u16),
+    /// The queue is larger than the device supports
+    QueueTooLarge { queue: u16, max: u16 },
+    /// Memory for a queue couldn't be set up
+    QueueAllocationFailed(u16),
+    /// The device couldn't deliver configuration changes on the interrupt vector it was given
+    ConfigVectorRejected,
+    /// The device couldn't deliver a queue's events on the interrupt vector it was given
+    QueueVectorRejected(u16),
+    /// The device reported it needs a reset or failed on its own
+    DeviceFailed,
+}
+
+/// Runs the device initialization sequence, leaving the device live with a queue of each of `queue_sizes` at
+/// indexes 0 onwards
+///
+/// Features are negotiated as whatever the device offers out of `supported`, VIRTIO_F_VERSION_1 is always
+/// accepted if offered. The queues are packed if VIRTIO_F_RING_PACKED was negotiated and split otherwise, use
+/// indirect tables if VIRTIO_F_RING_INDIRECT_DESC was, and event indexes if VIRTIO_F_EVENT_IDX was. On error the
+/// device is marked FAILED. Returns the negotiated features and the queues.
+///
+/// # Safety
+/// Nothing else can be using the device, the reset this starts with takes back any queues it had
+pub unsafe fn init_device(
+    transport: &dyn VirtioTransport,
+    supported: u64,
+    required: u64,
+    queue_sizes: &[usize],
+) -> Result<(u64, Vec<Virtqueue>), VirtioInitError> {
+    let result = negotiate(transport, supported, required, queue_sizes);
+
+    if result.is_err() {
+        transport.add_status(StatusFlag::Failed);
+    }
+
+    result
+}
+
+unsafe fn negotiate(
+    transport: &dyn VirtioTransport,
+    supported: u64,
+    required: u64,
+    queue_sizes: &[usize],
+) -> Result<(u64, Vec<Virtqueue>), VirtioInitError> {
+    transport.reset();
+    transport.add_status(StatusFlag::Acknowledge);
+    transport.add_status(StatusFlag::Driver);
+
+    let offered = transport.device_features();
+    let features = offered & (supported | features::VERSION_1);
+
+    if features & required != required {
+        return Err(VirtioInitError::MissingFeatures(required & !features));
+    }
+
+    transport.set_driver_features(features);
+
+    // The device gets a chance to refuse the features by leaving FEATURES_OK clear
+    transport.add_status(StatusFlag::FeaturesOk);
+    if !transport.has_status(StatusFlag::FeaturesOk) {
+        return Err(VirtioInitError::FeaturesRejected);
+    }
+
+    // The reset cleared any vector the device had
+    if !transport.setup_config_interrupt() {
+        return Err(VirtioInitError::ConfigVectorRejected);
+    }
+
+    // The ring layout depends on what was negotiated, so the queues can only be made now
+    let packed = features & features::RING_PACKED != 0;
+    let mut queues = Vec::with_capacity(queue_sizes.len());
+
+    for (index, &size) in queue_sizes.iter().enumerate() {
+        let index = index as u16;
+
+        let max = transport.max_queue_size(index);
+        if max == 0 {
+            return Err(VirtioInitError::QueueUnavailable(index));
+        }
+
+        if size > max as usize {
+            return Err(VirtioInitError::QueueTooLarge { queue: index, max });
+        }
+
+        let mut queue = Virtqueue::new(size, packed).map_err(|_| VirtioInitError::QueueAllocationFailed(index))?;
+        queue.set_indirect(features & features::RING_INDIRECT_DESC != 0);
+        queue.set_event_idx(features & features::RING_EVENT_IDX != 0);
+
+        let (descriptors, driver, device) = queue.areas();
+        if !transport.setup_queue(index, size as u16, descriptors, driver, device) {
+            return Err(VirtioInitError::QueueVectorRejected(index));
+        }
+
+        queues.push(queue);
+    }
+
+    transport.add_status(StatusFlag::DriverOk);
+
+    if transport.has_status(StatusFlag::DeviceNeedsReset) || transport.has_status(StatusFlag::Failed) {
+        return Err(VirtioInitError::DeviceFailed);
+    }
+
+    Ok((features, queues))
+}
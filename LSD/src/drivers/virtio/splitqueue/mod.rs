@@ -5,23 +5,52 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, vec::Vec};
 use crate::memory::{DmaRegion, PhysicalAddress};
 
+use super::queue::{Buffer, BufferDirection, Token, VirtqueueError};
+
+/// Most buffers a chain can have and still go in an indirect table, longer chains take direct descriptors
+const INDIRECT_LEN: usize = 8;
+
 pub struct SplitVirtqueue {
     queue_size: usize,
     freelist: VecDeque<u16>,
     pub descriptors: DescriptorQueue,
     pub available: AvailableQueue,
     pub used: UsedQueue,
+
+    /// Whether chains may be placed in indirect tables, only if VIRTIO_F_RING_INDIRECT_DESC was negotiated
+    indirect: bool,
+    /// An indirect table for every descriptor, `INDIRECT_LEN` entries each, used by the chain that descriptor heads
+    ///
+    /// Allocated once when indirect tables are enabled, so chains don't each need memory of their own.
+    tables: Option<DmaRegion<[VirtqueueDescriptor]>>,
+
+    /// Whether notifications are suppressed through `used_event` and `avail_event`, only if
+    /// VIRTIO_F_EVENT_IDX was negotiated
+    event_idx: bool,
+    /// The available index when the device was last considered for a notification
+    notified: u16,
+    /// Whether we want interrupts for this queue
+    interrupts: bool,
+}
+
+impl Buffer {
+    fn flags(&self) -> DescriptorFlags {
+        match self.direction {
+            BufferDirection::DeviceReads => DescriptorFlags::NONE,
+            BufferDirection::DeviceWrites => DescriptorFlags::WRITE,
+        }
+    }
 }
 
 impl SplitVirtqueue {
-    pub fn new(queue_size: usize) -> Result<Self, SplitVirtqueueError> {
+    pub fn new(queue_size: usize) -> Result<Self, VirtqueueError> {
         match queue_size {
-            n if !n.is_power_of_two() => return Err(SplitVirtqueueError::NotPowerOfTwo),
+            n if !n.is_power_of_two() => return Err(VirtqueueError::NotPowerOfTwo),
             0..=32768 => {}
-            _ => return Err(SplitVirtqueueError::TooLarge),
+            _ => return Err(VirtqueueError::TooLarge),
         }
 
         let freelist = (0..queue_size as u16).collect();
@@ -30,12 +59,14 @@ impl SplitVirtqueue {
             queue: unsafe { DmaRegion::zeroed_many(queue_size).assume_init() } 
         };
 
+        // Both rings get an extra entry past their end, which holds `used_event` and `avail_event`
         let available = AvailableQueue { 
-            queue: unsafe { DmaRegion::new_raw(queue_size, true) } 
+            queue: unsafe { DmaRegion::new_raw(queue_size + 1, true) },
+            staged: 0,
         };
 
         let used = UsedQueue { 
-            queue: unsafe { DmaRegion::new_raw(queue_size, true) }, 
+            queue: unsafe { DmaRegion::new_raw(queue_size + 1, true) }, 
             last_seen: 0 
         };
 
@@ -45,11 +76,200 @@ impl SplitVirtqueue {
                 freelist, 
                 descriptors, 
                 available, 
-                used 
+                used,
+                indirect: false,
+                tables: None,
+                event_idx: false,
+                notified: 0,
+                interrupts: true,
             }
         )
     }
 
+    /// Lets chains of more than one buffer use an indirect table, taking a single descriptor in the ring
+    ///
+    /// Only enable this once VIRTIO_F_RING_INDIRECT_DESC has been negotiated.
+    pub fn set_indirect(&mut self, enabled: bool) {
+        if enabled && self.tables.is_none() {
+            self.tables = Some(unsafe { DmaRegion::zeroed_many(self.queue_size * INDIRECT_LEN).assume_init() });
+        }
+
+        self.indirect = enabled;
+    }
+
+    /// Suppresses notifications with `used_event` and `avail_event` rather than the ring flags
+    ///
+    /// Only enable this once VIRTIO_F_EVENT_IDX has been negotiated.
+    pub fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+    }
+
+    /// Number of descriptors not in use
+    pub fn free_count(&self) -> usize {
+        self.freelist.len()
+    }
+
+    /// Places a chain of buffers on the queue and makes it available to the device, without notifying it
+    ///
+    /// Buffers the device reads have to come before the buffers it writes.
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> Result<Token, VirtqueueError> {
+        if buffers.is_empty() {
+            return Err(VirtqueueError::EmptyChain);
+        }
+
+        let token = self.stage_chain(buffers)?;
+        self.available.publish();
+
+        Ok(token)
+    }
+
+    /// Places every chain on the queue, making them available to the device together, without notifying it
+    ///
+    /// Stops at the first chain that doesn't fit, returning the tokens of the chains before it.
+    pub fn add_chains(&mut self, chains: &[&[Buffer]]) -> Vec<Token> {
+        let tokens = chains.iter().map_while(|buffers| self.stage_chain(buffers).ok()).collect();
+        self.available.publish();
+
+        tokens
+    }
+
+    /// Writes a chain's descriptors and places its head on the available ring, where the device can't see it
+    /// until the ring is published
+    fn stage_chain(&mut self, buffers: &[Buffer]) -> Result<Token, VirtqueueError> {
+        if buffers.is_empty() {
+            return Err(VirtqueueError::EmptyChain);
+        }
+
+        let head = match self.indirect && (2..=INDIRECT_LEN).contains(&buffers.len()) {
+            true => self.add_indirect(buffers)?,
+            false => self.add_direct(buffers)?,
+        };
+
+        self.available.stage(head);
+
+        Ok(Token(head.0))
+    }
+
+    /// Asks the device to hold off on interrupts for this queue, or to send them again
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        self.available.set_interrupts(enabled);
+        self.interrupts = enabled;
+
+        if enabled && self.event_idx {
+            self.available.set_used_event(self.used.last_seen);
+        }
+    }
+
+    /// Whether the device wants to be notified of the chains made available since this was last asked
+    pub fn needs_notify(&mut self) -> bool {
+        unsafe {
+            // The new available index has to be visible before we look at whether the device wants to hear of it
+            core::arch::asm!("fence");
+        }
+
+        let old = self.notified;
+        let new = self.available.index();
+        self.notified = new;
+
+        match self.event_idx {
+            // Only if `avail_event` is among the entries published since we last checked
+            true => super::event_idx::need_event(self.used.avail_event(), new, old),
+            false => !self.used.no_notify(),
+        }
+    }
+
+    fn add_direct(&mut self, buffers: &[Buffer]) -> Result<SplitqueueIndex<VirtqueueDescriptor>, VirtqueueError> {
+        if self.freelist.len() < buffers.len() {
+            return Err(VirtqueueError::QueueFull);
+        }
+
+        let indexes: alloc::vec::Vec<_> = buffers.iter().filter_map(|_| self.alloc_descriptor()).collect();
+
+        for (position, buffer) in buffers.iter().enumerate() {
+            let next = indexes.get(position + 1).copied();
+
+            self.descriptors.write(indexes[position], VirtqueueDescriptor {
+                address: buffer.address,
+                length: buffer.length,
+                flags: match next {
+                    Some(_) => buffer.flags() | DescriptorFlags::NEXT,
+                    None => buffer.flags(),
+                },
+                next: next.unwrap_or(SplitqueueIndex::new(0)),
+            });
+        }
+
+        Ok(indexes[0])
+    }
+
+    fn add_indirect(&mut self, buffers: &[Buffer]) -> Result<SplitqueueIndex<VirtqueueDescriptor>, VirtqueueError> {
+        let tables = self.tables.as_mut().ok_or(VirtqueueError::MemoryAllocationError)?;
+        let index = self.freelist.pop_back().map(SplitqueueIndex::new).ok_or(VirtqueueError::QueueFull)?;
+
+        // The table belonging to the head descriptor, nothing else can be using it while the descriptor is ours
+        let base = index.0 as usize * INDIRECT_LEN;
+        let table_phys = tables.physical_address().add((base * core::mem::size_of::<VirtqueueDescriptor>()) as u64);
+
+        // Entries of the table chain to each other by their index in it
+        for (position, buffer) in buffers.iter().enumerate() {
+            let last = position + 1 == buffers.len();
+
+            tables.get_mut()[base + position] = VirtqueueDescriptor {
+                address: buffer.address,
+                length: buffer.length,
+                flags: match last {
+                    false => buffer.flags() | DescriptorFlags::NEXT,
+                    true => buffer.flags(),
+                },
+                next: SplitqueueIndex::new(if last { 0 } else { position as u16 + 1 }),
+            };
+        }
+
+        self.descriptors.write(index, VirtqueueDescriptor {
+            address: table_phys,
+            length: (buffers.len() * core::mem::size_of::<VirtqueueDescriptor>()) as u32,
+            flags: DescriptorFlags::INDIRECT,
+            next: SplitqueueIndex::new(0),
+        });
+
+        Ok(index)
+    }
+
+    /// Takes the next chain the device has finished with, freeing every descriptor in it
+    ///
+    /// Returns the chain's token and how many bytes the device wrote into it.
+    pub fn pop_used(&mut self) -> Option<(Token, u32)> {
+        let used = match self.used.pop() {
+            Some(used) => used,
+            None if self.event_idx && self.interrupts => {
+                // Out of used entries, so ask for an interrupt on the next one. The device may have used one
+                // before seeing the new `used_event`, in which case it won't interrupt for it, so look again.
+                self.available.set_used_event(self.used.last_seen);
+
+                unsafe {
+                    core::arch::asm!("fence");
+                }
+
+                self.used.pop()?
+            },
+            None => return None,
+        };
+        let head = used.start_index as u16;
+
+        let mut index = SplitqueueIndex::new(head);
+        loop {
+            let descriptor = self.descriptors.read(index);
+            self.free_descriptor(index);
+
+            match descriptor.flags.contains(DescriptorFlags::NEXT) {
+                true => index = descriptor.next,
+                false => break,
+            }
+        }
+
+        Some((Token(head), used.length))
+    }
+
     pub fn alloc_descriptor(&mut self) -> Option<SplitqueueIndex<VirtqueueDescriptor>> {
         self.freelist.pop_back().map(SplitqueueIndex::new)
     }
@@ -63,13 +283,6 @@ impl SplitVirtqueue {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum SplitVirtqueueError {
-    MemoryAllocationError,
-    NotPowerOfTwo,
-    TooLarge,
-}
-
 #[repr(transparent)]
 pub struct SplitqueueIndex<T>(u16, core::marker::PhantomData<T>);
 
@@ -115,6 +328,10 @@ impl<T> SplitqueueIndex<T> {
     pub fn new(index: u16) -> Self {
         Self(index, core::marker::PhantomData)
     }
+
+    pub fn get(&self) -> u16 {
+        self.0
+    }
 }
 
 pub struct DescriptorQueue {
@@ -157,6 +374,8 @@ impl Default for VirtqueueDescriptor {
 
 pub struct AvailableQueue {
     queue: DmaRegion<VirtqueueAvailable>,
+    /// Entries placed on the ring past `index`, which the device can't see yet
+    staged: u16,
 }
 
 impl AvailableQueue {
@@ -164,12 +383,35 @@ impl AvailableQueue {
         self.queue.physical_address()
     }
 
+    /// Number of entries in the ring, not counting `used_event` after it
+    fn size(&self) -> u16 {
+        (self.queue.ring.len() - 1) as u16
+    }
+
     pub fn push(&mut self, index: SplitqueueIndex<VirtqueueDescriptor>) {
-        let queue_index = self.queue.index;
-        let ring_index = self.queue.index % self.queue.ring.len() as u16;
+        self.stage(index);
+        self.publish();
+    }
+
+    /// Places a chain on the ring without making it available yet
+    pub fn stage(&mut self, index: SplitqueueIndex<VirtqueueDescriptor>) {
+        let ring_index = self.queue.index.wrapping_add(self.staged) % self.size();
         // This is likely overkill, but better to be safe than sorry!
         unsafe {
             core::ptr::write_volatile(&mut self.queue.ring[ring_index as usize], index.0);
+        }
+
+        self.staged += 1;
+    }
+
+    /// Makes every staged chain available to the device at once
+    pub fn publish(&mut self) {
+        if self.staged == 0 {
+            return;
+        }
+
+        let queue_index = self.queue.index;
+        unsafe {
 
             // From the VirtIO spec:
             // > 2.7.13.3.1 Driver Requirements: Updating idx
@@ -178,8 +420,31 @@ impl AvailableQueue {
             // > update, to ensure the device sees the most up-to-date copy.
             core::arch::asm!("fence");
 
-            core::ptr::write_volatile(&mut self.queue.index, queue_index.wrapping_add(1));
+            core::ptr::write_volatile(&mut self.queue.index, queue_index.wrapping_add(self.staged));
         }
+
+        self.staged = 0;
+    }
+
+    /// The index of the next entry the device will see
+    pub fn index(&self) -> u16 {
+        self.queue.index
+    }
+
+    /// Asks the device for an interrupt once it has used the entry at `index`, with VIRTIO_F_EVENT_IDX
+    pub fn set_used_event(&mut self, index: u16) {
+        let size = self.size() as usize;
+        unsafe { core::ptr::write_volatile(&mut self.queue.ring[size], index) };
+    }
+
+    /// Sets or clears VIRTQ_AVAIL_F_NO_INTERRUPT, which the device may ignore
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        let flags = match enabled {
+            true => 0,
+            false => 1,
+        };
+
+        unsafe { core::ptr::write_volatile(&mut self.queue.flags, flags) };
     }
 }
 
@@ -207,7 +472,7 @@ impl UsedQueue {
             true => None,
             false => {
                 let used = unsafe {
-                    core::ptr::read_volatile(&self.queue.ring[self.last_seen as usize % self.queue.ring.len()])
+                    core::ptr::read_volatile(&self.queue.ring[self.last_seen as usize % (self.queue.ring.len() - 1)])
                 };
                 self.last_seen = self.last_seen.wrapping_add(1);
 
@@ -216,6 +481,18 @@ impl UsedQueue {
         }
     }
 
+    /// The available index the device wants to be notified once it passes, with VIRTIO_F_EVENT_IDX
+    pub fn avail_event(&self) -> u16 {
+        // `avail_event` sits right after the ring, in the extra entry
+        let entry = &self.queue.ring[self.queue.ring.len() - 1];
+        unsafe { core::ptr::read_volatile(entry as *const VirtqueueUsedElement as *const u16) }
+    }
+
+    /// Whether the device set VIRTQ_USED_F_NO_NOTIFY, asking not to be notified of new buffers
+    pub fn no_notify(&self) -> bool {
+        unsafe { core::ptr::read_volatile(&self.queue.flags) & 1 != 0 }
+    }
+
     pub fn drain(&mut self) -> impl Iterator<Item = VirtqueueUsedElement> + '_ {
         // FIXME: should this be a fused iterator or no?
         core::iter::from_fn(move || self.pop())
@@ -245,6 +522,10 @@ impl DescriptorFlags {
     pub const NEXT: Self = Self(1);
     pub const WRITE: Self = Self(2);
     pub const INDIRECT: Self = Self(4);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
 }
 
 impl core::ops::BitOr for DescriptorFlags {
@@ -0,0 +1,119 @@
+use crate::memory::PhysicalAddress;
+
+use super::VirtioTransport;
+use super::super::{DeviceType, VirtIOHeader};
+
+/// Offset of the device specific configuration space from the start of the registers
+const CONFIG_OFFSET: usize = 0x100;
+
+/// A device behind the `virtio,mmio` register layout
+pub struct MmioTransport {
+    header: *mut VirtIOHeader,
+}
+
+unsafe impl Send for MmioTransport {}
+
+impl MmioTransport {
+    /// # Safety
+    /// The header must be mapped and valid, and not used by anything else
+    pub unsafe fn new(header: *mut VirtIOHeader) -> Self {
+        Self { header }
+    }
+
+    fn header(&self) -> &VirtIOHeader {
+        unsafe { &*self.header }
+    }
+
+    fn config(&self) -> *mut u8 {
+        unsafe { self.header.cast::<u8>().add(CONFIG_OFFSET) }
+    }
+}
+
+impl VirtioTransport for MmioTransport {
+    fn device_type(&self) -> Option<DeviceType> {
+        let id: u32 = unsafe { self.header.cast::<u32>().add(2).read_volatile() };
+
+        DeviceType::from_id(id)
+    }
+
+    fn device_features(&self) -> u64 {
+        let header = self.header();
+
+        header.dev_feat_sel.write(0);
+        let low = header.dev_feat.read() as u64;
+        header.dev_feat_sel.write(1);
+        let high = header.dev_feat.read() as u64;
+
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        let header = self.header();
+
+        header.driver_feat_sel.write(0);
+        header.driver_feat.write(features as u32);
+        header.driver_feat_sel.write(1);
+        header.driver_feat.write((features >> 32) as u32);
+    }
+
+    fn status(&self) -> u32 {
+        self.header().status.read()
+    }
+
+    fn set_status(&self, status: u32) {
+        self.header().status.write(status);
+    }
+
+    fn reset(&self) {
+        self.header().status.reset();
+    }
+
+    fn max_queue_size(&self, queue: u16) -> u16 {
+        let header = self.header();
+
+        header.queue_sel.write(queue as u32);
+        header.queue_size_max.read().min(u16::MAX as u32) as u16
+    }
+
+    fn setup_queue(&self, queue: u16, size: u16, descriptors: PhysicalAddress, available: PhysicalAddress, used: PhysicalAddress) -> bool {
+        let header = self.header();
+
+        header.queue_sel.write(queue as u32);
+        header.queue_size.write(size as u32);
+        header.queue_desc.set(descriptors);
+        header.queue_avail.set(available);
+        header.queue_used.set(used);
+        header.queue_ready.ready();
+
+        true
+    }
+
+    fn notify(&self, queue: u16) {
+        self.header().queue_notify.notify(queue as u32);
+    }
+
+    fn ack_interrupt(&self) -> u32 {
+        let header = self.header();
+
+        let status = header.int_status.read();
+        header.int_ack.ack(status);
+
+        status
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.header().config_gen.read()
+    }
+
+    fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        for (index, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe { self.config().add(offset + index).read_volatile() };
+        }
+    }
+
+    fn write_config(&self, offset: usize, data: &[u8]) {
+        for (index, byte) in data.iter().enumerate() {
+            unsafe { self.config().add(offset + index).write_volatile(*byte) };
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::memory::PhysicalAddress;
+
+use super::{DeviceType, StatusFlag};
+
+pub mod mmio;
+pub mod pci;
+
+/// How a driver talks to its device, independent of the bus the device sits on
+pub trait VirtioTransport: Send {
+    /// Returns the kind of device, `None` if we don't know of it
+    fn device_type(&self) -> Option<DeviceType>;
+
+    /// Returns all 64 bits of the features the device offers
+    fn device_features(&self) -> u64;
+
+    /// Writes all 64 bits of the features the driver accepted
+    fn set_driver_features(&self, features: u64);
+
+    fn status(&self) -> u32;
+    fn set_status(&self, status: u32);
+
+    /// Resets the device, returning once it has finished
+    fn reset(&self);
+
+    /// Returns the largest size the queue supports, zero if the queue doesn't exist
+    fn max_queue_size(&self, queue: u16) -> u16;
+
+    /// Points configuration change events at the device's interrupt, returning false if the device refused
+    ///
+    /// Transports without interrupt vectors have nothing to do. Any reset undoes this, so it has to be done again
+    /// after one.
+    fn setup_config_interrupt(&self) -> bool {
+        true
+    }
+
+    /// Gives the device the descriptor, driver and device areas of a queue, points its events at the device's
+    /// interrupt, and enables it. Returns false if the device refused the interrupt, leaving the queue disabled.
+    ///
+    /// For split queues those are the descriptor table, available ring and used ring, for packed queues the
+    /// descriptor ring and the driver and device event suppression structures.
+    fn setup_queue(&self, queue: u16, size: u16, descriptors: PhysicalAddress, available: PhysicalAddress, used: PhysicalAddress) -> bool;
+
+    /// Tells the device there are new buffers in the queue
+    fn notify(&self, queue: u16);
+
+    /// Reads and acknowledges the interrupt status, bit 0 for a used buffer and bit 1 for a configuration change
+    fn ack_interrupt(&self) -> u32;
+
+    /// Changes whenever the device configuration space does
+    fn config_generation(&self) -> u32;
+
+    /// Reads bytes from the device specific configuration space
+    fn read_config(&self, offset: usize, buf: &mut [u8]);
+
+    /// Writes bytes to the device specific configuration space
+    fn write_config(&self, offset: usize, data: &[u8]);
+
+    fn add_status(&self, flag: StatusFlag) {
+        self.set_status(self.status() | flag as u32);
+
+        unsafe {
+            core::arch::asm!("fence");
+        }
+    }
+
+    fn has_status(&self, flag: StatusFlag) -> bool {
+        self.status() & flag as u32 == flag as u32
+    }
+
+    /// Reads a configuration field, retrying until the device doesn't change it mid read
+    fn read_config_consistent(&self, offset: usize, buf: &mut [u8]) {
+        loop {
+            let generation = self.config_generation();
+            self.read_config(offset, buf);
+
+            if generation == self.config_generation() {
+                break;
+            }
+        }
+    }
+}
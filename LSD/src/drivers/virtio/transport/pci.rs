@@ -0,0 +1,322 @@
+use alloc::boxed::Box;
+
+use crate::drivers::pci::{
+    capability,
+    driver::{AttachError, DeviceMatch, PciDriver},
+    interrupt::{self, Interrupt},
+    PCIHost,
+    PciDevice,
+};
+use crate::memory::PhysicalAddress;
+use crate::volatile::{Volatile, Read, ReadWrite};
+
+use super::VirtioTransport;
+use super::super::DeviceType;
+
+const VENDOR_ID: u16 = 0x1af4;
+
+/// Tells the device not to raise an MSI-X vector for an event
+const NO_VECTOR: u16 = 0xffff;
+
+/// The `cfg_type` of a virtio vendor specific capability
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum CfgType {
+    Common = 1,
+    Notify = 2,
+    Isr = 3,
+    Device = 4,
+}
+
+/// Layout of the common configuration structure
+#[repr(C)]
+struct CommonCfg {
+    device_feature_select: Volatile<u32, ReadWrite>,
+    device_feature: Volatile<u32, Read>,
+    driver_feature_select: Volatile<u32, ReadWrite>,
+    driver_feature: Volatile<u32, ReadWrite>,
+    config_msix_vector: Volatile<u16, ReadWrite>,
+    num_queues: Volatile<u16, Read>,
+    device_status: Volatile<u8, ReadWrite>,
+    config_generation: Volatile<u8, Read>,
+    queue_select: Volatile<u16, ReadWrite>,
+    queue_size: Volatile<u16, ReadWrite>,
+    queue_msix_vector: Volatile<u16, ReadWrite>,
+    queue_enable: Volatile<u16, ReadWrite>,
+    queue_notify_off: Volatile<u16, Read>,
+    queue_desc_low: Volatile<u32, ReadWrite>,
+    queue_desc_high: Volatile<u32, ReadWrite>,
+    queue_driver_low: Volatile<u32, ReadWrite>,
+    queue_driver_high: Volatile<u32, ReadWrite>,
+    queue_device_low: Volatile<u32, ReadWrite>,
+    queue_device_high: Volatile<u32, ReadWrite>,
+}
+
+/// Errors finding the structures of a modern virtio-pci device
+#[derive(Debug, Clone, Copy)]
+pub enum PciTransportError {
+    /// A structure the transport needs has no capability pointing at it
+    MissingCapability(u8),
+    /// A capability points outside of its BAR, or into one that wasn't assigned
+    BadCapability(u8),
+}
+
+/// A device using the modern virtio-pci layout
+pub struct PciTransport {
+    common: *mut CommonCfg,
+    notify: *mut u8,
+    notify_multiplier: u32,
+    isr: *mut u8,
+    device: *mut u8,
+    device_len: usize,
+    dev_type: Option<DeviceType>,
+
+    /// Whether events have to be assigned MSI-X vectors to be delivered
+    msix: bool,
+}
+
+unsafe impl Send for PciTransport {}
+
+impl PciTransport {
+    /// Finds the configuration structures through the function's vendor specific capabilities
+    ///
+    /// # Safety
+    /// The function's BARs must be assigned, and nothing else may be using the device
+    pub unsafe fn new(device: &PciDevice, irq: Option<Interrupt>) -> Result<Self, PciTransportError> {
+        let slot = device.slot();
+
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_cfg = None;
+
+        for cap in slot.capabilities().filter(|cap| cap.id == capability::id::VENDOR) {
+            let offset = cap.offset as usize;
+
+            let cfg_type: u8 = slot.read_config(offset + 3);
+            let bar: u8 = slot.read_config(offset + 4);
+            let bar_offset: u32 = slot.read_config(offset + 8);
+            let length: u32 = slot.read_config(offset + 12);
+
+            let Some(bar) = device.bars.get(bar as usize).copied().flatten() else {
+                continue;
+            };
+
+            if bar_offset as u64 + length as u64 > bar.size {
+                return Err(PciTransportError::BadCapability(cfg_type));
+            }
+
+            let addr = bar.virt().add(bar_offset as usize);
+
+            // The first capability of each type is the preferred one
+            match cfg_type {
+                t if t == CfgType::Common as u8 => { common.get_or_insert(addr); },
+                t if t == CfgType::Notify as u8 => {
+                    let multiplier: u32 = slot.read_config(offset + 16);
+                    notify.get_or_insert((addr, multiplier));
+                },
+                t if t == CfgType::Isr as u8 => { isr.get_or_insert(addr); },
+                t if t == CfgType::Device as u8 => { device_cfg.get_or_insert((addr, length as usize)); },
+                _ => {},
+            }
+        }
+
+        let common = common.ok_or(PciTransportError::MissingCapability(CfgType::Common as u8))?;
+        let (notify, notify_multiplier) = notify.ok_or(PciTransportError::MissingCapability(CfgType::Notify as u8))?;
+        let isr = isr.ok_or(PciTransportError::MissingCapability(CfgType::Isr as u8))?;
+        let (device_cfg, device_len) = device_cfg.unwrap_or((core::ptr::null_mut(), 0));
+
+        Ok(Self {
+            common: common.cast(),
+            notify,
+            notify_multiplier,
+            isr,
+            device: device_cfg,
+            device_len,
+            dev_type: dev_type(device.ident.device_id, slot),
+            msix: matches!(irq, Some(Interrupt::MsiX(_))),
+        })
+    }
+
+    fn common(&self) -> &CommonCfg {
+        unsafe { &*self.common }
+    }
+
+    /// The vector every event is delivered on
+    fn vector(&self) -> u16 {
+        match self.msix {
+            true => 0,
+            false => NO_VECTOR,
+        }
+    }
+}
+
+impl VirtioTransport for PciTransport {
+    fn device_type(&self) -> Option<DeviceType> {
+        self.dev_type
+    }
+
+    fn device_features(&self) -> u64 {
+        let common = self.common();
+
+        common.device_feature_select.write(0);
+        let low = common.device_feature.read() as u64;
+        common.device_feature_select.write(1);
+        let high = common.device_feature.read() as u64;
+
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        let common = self.common();
+
+        common.driver_feature_select.write(0);
+        common.driver_feature.write(features as u32);
+        common.driver_feature_select.write(1);
+        common.driver_feature.write((features >> 32) as u32);
+    }
+
+    fn status(&self) -> u32 {
+        self.common().device_status.read() as u32
+    }
+
+    fn set_status(&self, status: u32) {
+        self.common().device_status.write(status as u8);
+    }
+
+    fn reset(&self) {
+        self.common().device_status.write(0);
+
+        // The device may take a while to reset, it reads back zero once it's done
+        while self.common().device_status.read() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn max_queue_size(&self, queue: u16) -> u16 {
+        let common = self.common();
+
+        if queue >= common.num_queues.read() {
+            return 0;
+        }
+
+        common.queue_select.write(queue);
+        common.queue_size.read()
+    }
+
+    fn setup_config_interrupt(&self) -> bool {
+        let common = self.common();
+        let vector = self.vector();
+
+        // The device reads back NO_VECTOR if it couldn't take the one we asked for
+        common.config_msix_vector.write(vector);
+        vector == NO_VECTOR || common.config_msix_vector.read() == vector
+    }
+
+    fn setup_queue(&self, queue: u16, size: u16, descriptors: PhysicalAddress, available: PhysicalAddress, used: PhysicalAddress) -> bool {
+        let common = self.common();
+
+        common.queue_select.write(queue);
+        common.queue_size.write(size);
+        common.queue_desc_low.write(descriptors.0 as u32);
+        common.queue_desc_high.write((descriptors.0 >> 32) as u32);
+        common.queue_driver_low.write(available.0 as u32);
+        common.queue_driver_high.write((available.0 >> 32) as u32);
+        common.queue_device_low.write(used.0 as u32);
+        common.queue_device_high.write((used.0 >> 32) as u32);
+
+        let vector = self.vector();
+        common.queue_msix_vector.write(vector);
+
+        if vector != NO_VECTOR && common.queue_msix_vector.read() != vector {
+            return false;
+        }
+
+        common.queue_enable.write(1);
+
+        true
+    }
+
+    fn notify(&self, queue: u16) {
+        let common = self.common();
+
+        common.queue_select.write(queue);
+        let offset = common.queue_notify_off.read() as usize * self.notify_multiplier as usize;
+
+        unsafe {
+            core::arch::asm!("fence");
+            self.notify.add(offset).cast::<u16>().write_volatile(queue);
+        }
+    }
+
+    fn ack_interrupt(&self) -> u32 {
+        // Reading the ISR status clears it
+        unsafe { self.isr.read_volatile() as u32 }
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.common().config_generation.read() as u32
+    }
+
+    fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        assert!(offset + buf.len() <= self.device_len, "Read past the end of the device configuration");
+
+        for (index, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe { self.device.add(offset + index).read_volatile() };
+        }
+    }
+
+    fn write_config(&self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.device_len, "Write past the end of the device configuration");
+
+        for (index, byte) in data.iter().enumerate() {
+            unsafe { self.device.add(offset + index).write_volatile(*byte) };
+        }
+    }
+}
+
+/// Works out the device type from the PCI device ID
+fn dev_type(device_id: u16, slot: &crate::drivers::pci::slot::Slot) -> Option<DeviceType> {
+    match device_id {
+        // Modern devices are offset from their virtio device ID
+        0x1040..=0x107f => DeviceType::from_id((device_id - 0x1040) as u32),
+        // Transitional devices put it in the subsystem ID
+        0x1000..=0x103f => {
+            let subsystem: u16 = unsafe { slot.read_config(0x2e) };
+            DeviceType::from_id(subsystem as u32)
+        },
+        _ => None,
+    }
+}
+
+/// Binds virtio devices found on the PCI bus, and hands them to the driver for their device type
+pub struct VirtioPciDriver;
+
+impl PciDriver for VirtioPciDriver {
+    fn name(&self) -> &'static str {
+        "virtio-pci"
+    }
+
+    fn matches(&self) -> &'static [DeviceMatch] {
+        &[DeviceMatch::vendor(VENDOR_ID)]
+    }
+
+    fn probe(&self, device: &PciDevice) -> bool {
+        (0x1000..=0x107f).contains(&device.ident.device_id)
+    }
+
+    unsafe fn attach(&self, host: &PCIHost, device: &PciDevice) -> Result<(), AttachError> {
+        let dev_type = dev_type(device.ident.device_id, device.slot());
+
+        let irq = match dev_type.and_then(super::super::int_handler) {
+            Some(handler) => interrupt::enable(host, device, handler),
+            None => None,
+        };
+
+        let transport = PciTransport::new(device, irq).map_err(AttachError::driver)?;
+
+        super::super::attach(Box::new(transport));
+
+        Ok(())
+    }
+}
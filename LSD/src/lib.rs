@@ -37,6 +37,8 @@ pub mod traps;
 pub mod timing;
 pub mod drivers;
 pub mod userspace;
+pub mod random;
+pub mod net;
 
 pub mod arch;
 
@@ -71,6 +73,7 @@ pub unsafe fn init(map: &limine::MemoryMap, hhdm_start: u64, hart_id: usize, dtb
     memory::init_tls();
     traps::init();
     HART_ID.store(hart_id, core::sync::atomic::Ordering::Relaxed);
+    traps::task::register_hart();
     println!("Hart ID: {hart_id}");
     memory::vmm::init();
 
@@ -82,12 +85,17 @@ pub unsafe fn init(map: &limine::MemoryMap, hhdm_start: u64, hart_id: usize, dtb
     let fdt = alloc::boxed::Box::new(fdt);
     let fdt = alloc::boxed::Box::leak(fdt);
 
-    let plic = fdt.find_node("/soc/plic@c000000").unwrap();
-    let ptr = plic.reg().unwrap().next().unwrap().starting_address as usize;
-    let ptr = ptr + hhdm_start as usize;
+    // Machines using the AIA have an IMSIC and APLIC in place of the PLIC
+    if let Some(plic) = fdt.find_node("/soc/plic@c000000") {
+        let ptr = plic.reg().unwrap().next().unwrap().starting_address as usize;
+        let ptr = ptr + hhdm_start as usize;
 
-    traps::plic::PLIC_ADDR.store(ptr as *mut sifive_plic::Plic, Ordering::Relaxed);
-    traps::plic_init();
+        traps::plic::PLIC_ADDR.store(ptr as *mut sifive_plic::Plic, Ordering::Relaxed);
+        traps::plic_init();
+    }
+
+    traps::imsic::from_fdt(fdt);
+    traps::imsic::init();
 
     for node in fdt.all_nodes() {
         if node.name == "cpus" {
@@ -117,51 +125,21 @@ pub unsafe fn init(map: &limine::MemoryMap, hhdm_start: u64, hart_id: usize, dtb
             drivers::goldfish_rtc::RTC.set(reg);
         } else if node.name.contains("pci") {
             println!("\nPCI host found");
-            /*assert!(node.property("device_type").unwrap().as_str() == Some("pci"), "Not PCI bus");
-
-            let bus_range = node.property("bus-range").unwrap();
-
-            let ecam = node.reg().unwrap().next().unwrap();
-
-            let bus_range = bus_range.value;
-            let bus_start = u32::from_be_bytes(
-                [
-                    bus_range[0],
-                    bus_range[1],
-                    bus_range[2],
-                    bus_range[3],
-                ]
-            ) as u8;
-            let bus_end = u32::from_be_bytes(
-                [
-                    bus_range[4],
-                    bus_range[5],
-                    bus_range[6],
-                    bus_range[7],
-                ]
-            ) as u8;
-
-            let pci_host = drivers::pci::PCIHost {
-                interrupt_map_mask: 0,
-                interrupt_map: 0,
-                ecam_region: drivers::pci::EcamRegion {
-                    start: ecam.starting_address.add(IO_OFFSET as usize) as *mut u8,
-                    size: ecam.size.unwrap()
-                },
-                bus_range: bus_start..=bus_end,
-            };
-
-            drivers::pci::PCI_HOST.set(pci_host);*/
+
+            drivers::pci::PCI_HOST.set(drivers::pci::PCIHost::from_fdt(fdt, &node));
         } else if node.name.contains("serial") {
             println!("Found serial");
 
             for int in node.interrupts().unwrap() {
                 let plic = crate::traps::plic::PLIC_ADDR.load(core::sync::atomic::Ordering::Relaxed);
+                if plic.is_null() {
+                    break;
+                }
 
                 (*plic).enable_interrupt(current_context(), int);
                 (*plic).set_interrupt_priority(int, 0x2);
 
-                crate::traps::plic::INT_HANDLERS.lock()[int] = uart::uart_handler;
+                crate::traps::plic::add_handler(int, uart::uart_handler);
             }
             
             uart::UART.lock().set_int();
@@ -184,8 +162,8 @@ pub unsafe fn init(map: &limine::MemoryMap, hhdm_start: u64, hart_id: usize, dtb
 
     println!("Vmem initialized");
 
-    //drivers::virtio::init();
-    //drivers::pci::init();
+    drivers::virtio::init();
+    drivers::pci::init();
 
     // Test code for date code, as well as timing code
     /*let timestamp = (**drivers::goldfish_rtc::RTC.get()).time.read();
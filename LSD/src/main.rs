@@ -56,11 +56,15 @@ extern "C" fn kmain() -> ! {
         sstatus.set();
     }
 
-    let id = lsd::userspace::load(USER_PROG);
-    println!("Loaded user program task with id 0x{id:x}");
-    let id = lsd::userspace::load(NULL_TASK);
-    println!("Loaded null task with id 0x{id:x}");
-    lsd::timing::Unit::MilliSeconds(10).set().unwrap();
+    match lsd::userspace::load(USER_PROG) {
+        Ok(id) => println!("Loaded user program task with id 0x{id:x}"),
+        Err(error) => println!("Failed to load user program: {error:?}"),
+    }
+    match lsd::userspace::load(NULL_TASK) {
+        Ok(id) => println!("Loaded null task with id 0x{id:x}"),
+        Err(error) => println!("Failed to load null task: {error:?}"),
+    }
+    lsd::traps::task::arm_timer();
     lsd::userspace::start_tasks();
 
     // If we get here, thats bad, very bad
@@ -117,16 +121,17 @@ fn smp_init() {
 
             unsafe {
                 CORE_INIT.satp = core::mem::transmute(satp);
-                CORE_INIT.sp = lsd::memory::pmm::REGION_LIST.lock().claim_continuous(0x80).unwrap() as usize;
+                // The stack grows down from the end of the region
+                CORE_INIT.sp = lsd::memory::pmm::REGION_LIST.lock().claim_continuous(0x80).unwrap().byte_add(0x80 * 0x1000) as usize;
+                CORE_INIT.claimed.store(false, core::sync::atomic::Ordering::Relaxed);
 
                 println!("Core 0x{:x} starting", hart_id);
                 core.start(core_main, core::ptr::addr_of!(CORE_INIT) as usize);
 
-                while !CORE_INIT.claimed.load(core::sync::atomic::Ordering::Relaxed) {
+                // The core is done with `CORE_INIT` once it's claimed, so it can be reused for the next
+                while !CORE_INIT.claimed.load(core::sync::atomic::Ordering::Acquire) {
                     core::arch::riscv64::pause();
                 }
-    
-                CORE_INIT.claimed.store(true, core::sync::atomic::Ordering::Relaxed);
             }
         }
     }
@@ -156,52 +161,18 @@ pub unsafe extern "C" fn core_main(smpinfo: &limine::SmpInfo) -> ! {
     println!("Core 0x{:x} started", smpinfo.hartid);
     lsd::HART_ID.store(smpinfo.hartid, core::sync::atomic::Ordering::Relaxed);
 
-    CORE_INIT.claimed.store(true, core::sync::atomic::Ordering::Relaxed);
-
-    /*Trap on hart 0: TrapFrame {
-        sepc: 0xfffffffa80000568,
-        registers: GeneralRegisters {
-            ra: 0xfffffffa800001d0,
-            sp: 0xfffffffa80149f60,
-            gp: 0xfffffffa8000dea8,
-            tp: 0xffff800080339000,
-            t0: 0xdf,
-            t1: 0xffffffff90000000,
-            t2: 0xfffffffa8000e018,
-            s0: 0xfffffffa80012030,
-            s1: 0xfffffffa80012030,
-            a0: 0x0,
-            a1: 0x0,
-            a2: 0x0,
-            a3: 0xffff8000800a9000,
-            a4: 0x80,
-            a5: 0xffff8000800a9000,
-            a6: 0x1000,
-            a7: 0xf0,
-            s2: 0xffff80008002a000,
-            s3: 0xff,
-            s4: 0x1,
-            s5: 0xfffffffa8000d14e,
-            s6: 0xffff80017fe5b000,
-            s7: 0xffff80017fe5c010,
-            s8: 0x80025000,
-            s9: 0xffff80017fe5c008,
-            s10: 0xfffffffa8000e000,
-            s11: 0xff,
-            t3: 0x0,
-            t4: 0xfffffffa800098c2,
-            t5: 0x25,
-            t6: 0xffffffffffffff8f,
-        },
-    }
-    Cause: StorePageFault
-    Stval: 0x0
-    fffffffa80000560: 03 39 84 00   ld      s2, 8(s0)
-    fffffffa80000564: 03 35 89 00   ld      a0, 8(s2)
-    fffffffa80000568: 23 30 05 00   sd      zero, 0(a0)
-    */
+    lsd::traps::task::register_hart();
+    lsd::traps::imsic::init();
+    lsd::traps::plic_hart_init();
 
-    loop {
-        core::arch::riscv64::pause();
-    }
-}
\ No newline at end of file
+    CORE_INIT.claimed.store(true, core::sync::atomic::Ordering::Release);
+
+    // Make it so we'll jump to user mode on an `sret`
+    let mut sstatus = lsd::arch::regs::Sstatus::new();
+    sstatus.set_spp(false);
+    sstatus.set_spie(true);
+    sstatus.set();
+
+    lsd::traps::task::arm_timer();
+    lsd::userspace::start_tasks();
+}
@@ -113,6 +113,16 @@ impl FreeList {
         self.len += 1;
     }
 
+    /// Number of free frames
+    pub fn free_frames(&self) -> usize {
+        self.len
+    }
+
+    /// Like `claim`, but `None` rather than running off the end once only the last frame is left
+    pub fn try_claim(&mut self) -> Option<*mut u8> {
+        (self.len > 1).then(|| self.claim())
+    }
+
     pub fn claim(&mut self) -> *mut u8 {
         // Temporarily store original head and next entry pointer
         let og_head = self.head;
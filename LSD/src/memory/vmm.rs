@@ -195,12 +195,40 @@ pub fn new_with_upperhalf() -> *mut PageTable {
     let new_table = pmm::REGION_LIST.lock().claim() as *mut PageTable;
 
     unsafe {
+        // Frames come back from exited tasks with their data still in them, which would read as mappings
+        new_table.write_bytes(0, 1);
+
         clone_table_range(current_table(), new_table, 256..512);
     }
 
     new_table
 }
 
+/// Most frames mapping `pages` contiguous pages could take for tables below the root
+pub fn tables_needed(pages: u64, level: PageLevel) -> u64 {
+    let mut span = pages;
+    let mut tables = 0;
+
+    // A range that straddles a table's edge needs one more than its size suggests
+    for _ in 1..level.as_usize() {
+        span = span.div_ceil(512);
+        tables += span + 1;
+    }
+
+    tables
+}
+
+/// Number of tables `new_with_upperhalf` copies, besides the root
+pub fn upper_half_tables() -> usize {
+    unsafe fn count(table: *const PageTable, range: core::ops::Range<usize>) -> usize {
+        range.filter(|&index| (*table).0[index].is_branch())
+            .map(|index| 1 + count((*table).0[index].table(), 0..512))
+            .sum()
+    }
+
+    unsafe { count(current_table(), 256..512) }
+}
+
 pub fn flush_tlb(vaddr: Option<VirtualAddress>, asid: Option<u16>) {
     unsafe {
         match (vaddr, asid) {
@@ -344,6 +372,95 @@ pub unsafe fn clone_table_range(src: *const PageTable, dest: *mut PageTable, ran
     }
 }
 
+/// Returns every table below `table` in `range` to the pmm, along with the frames mapped there if `free_leaves`
+///
+/// # Safety
+/// Nothing may use the tables, or the frames if they're freed, again
+pub unsafe fn free_table_range(
+    table: *mut PageTable,
+    level: PageLevel,
+    range: core::ops::Range<usize>,
+    free_leaves: bool,
+    pmm_lock: &mut MutexGuard<super::pmm::FreeList>,
+) {
+    for index in range {
+        let entry = (*table).0[index];
+
+        if entry.is_branch() {
+            let next_table = entry.table().cast_mut();
+
+            free_table_range(next_table, level - 1, 0..512, free_leaves, pmm_lock);
+            pmm_lock.pull(next_table as *mut u8);
+        } else if entry.is_leaf() && free_leaves {
+            let base = entry.get_ppn() << 12;
+
+            // Larger pages are made of frames that were claimed together, but go back one at a time
+            for offset in (0..PageSize::from_level(level) as u64).step_by(0x1000) {
+                let frame = base + offset + super::HHDM_OFFSET.load(Ordering::Relaxed);
+                pmm_lock.pull(frame as *mut u8);
+            }
+        }
+
+        (*table).0[index] = PageEntry(0);
+    }
+}
+
+/// Frees a table made by `new_with_upperhalf`, with every frame mapped in its lower half
+///
+/// The upper half only had its tables copied, so the kernel's frames are left alone.
+///
+/// # Safety
+/// The table can't be loaded on any hart
+pub unsafe fn free_address_space(table: *mut PageTable, level: PageLevel, pmm_lock: &mut MutexGuard<super::pmm::FreeList>) {
+    free_table_range(table, level, 0..256, true, pmm_lock);
+    free_table_range(table, level, 256..512, false, pmm_lock);
+
+    pmm_lock.pull(table as *mut u8);
+}
+
+/// End of the lower half, everything the root table's first 256 entries cover
+pub fn lower_half_end() -> u64 {
+    let level = PageLevel::from_usize(LEVELS.load(Ordering::Relaxed) as usize);
+
+    PageSize::from_level(level) as u64 * 256
+}
+
+/// Splits `len` bytes of the current task's memory at `virt` into physically contiguous pieces
+///
+/// The whole range has to be in the lower half and mapped for user access, and writable as well if `write` is
+/// set. Returns `None` otherwise.
+pub fn user_segments(virt: usize, len: usize, write: bool) -> Option<alloc::vec::Vec<(PhysicalAddress, usize)>> {
+    let end = virt.checked_add(len)?;
+
+    if end as u64 > lower_half_end() {
+        return None;
+    }
+
+    let required = match write {
+        true => PageFlags::USER | PageFlags::WRITE,
+        false => PageFlags::USER,
+    };
+
+    let mut segments = alloc::vec::Vec::new();
+    let mut offset = 0;
+
+    while offset < len {
+        let addr = virt.checked_add(offset)?;
+        let in_page = (PAGE_SIZE - (addr % PAGE_SIZE)).min(len - offset);
+
+        let (phys, flags) = translate(VirtualAddress(addr as u64)).ok()?;
+
+        if !flags.contains(required) {
+            return None;
+        }
+
+        segments.push((phys, in_page));
+        offset += in_page;
+    }
+
+    Some(segments)
+}
+
 pub fn current_table() -> *const PageTable {
     let satp = Satp::new();
 
@@ -710,6 +827,11 @@ impl Default for Satp {
 }
 
 pub fn virt_to_phys(virt: VirtualAddress) -> Result<PhysicalAddress, &'static str> {
+    translate(virt).map(|(phys, _)| phys)
+}
+
+/// Looks `virt` up in the current table, returning where it maps to along with the flags of the leaf mapping it
+pub fn translate(virt: VirtualAddress) -> Result<(PhysicalAddress, PageFlags), &'static str> {
     //println!("Finding physical for address 0x{:x}", virt.0);
     let mut table = current_table();
 
@@ -736,7 +858,7 @@ pub fn virt_to_phys(virt: VirtualAddress) -> Result<PhysicalAddress, &'static st
                 addr.0 &= inv_mask;
                 addr.0 |= virt.0 & mask;
 
-                return Ok(addr);
+                return Ok((addr, PageFlags::from_bits_truncate(entry.0)));
             } else if entry.is_branch() {
                 table = entry.table();
             } else {
@@ -0,0 +1,75 @@
+use alloc::collections::BTreeMap;
+
+use super::{ethernet::MacAddress, ipv4::Ipv4Address};
+
+pub const REQUEST: u16 = 1;
+pub const REPLY: u16 = 2;
+
+/// Length of a packet for Ethernet and IPv4 addresses
+pub const LEN: usize = 28;
+
+/// An ARP packet mapping IPv4 addresses onto Ethernet addresses
+#[derive(Debug, Clone, Copy)]
+pub struct Packet {
+    pub op: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl Packet {
+    /// Parses a packet, ignoring any that aren't for Ethernet and IPv4
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < LEN {
+            return None;
+        }
+
+        let hardware = u16::from_be_bytes([data[0], data[1]]);
+        let protocol = u16::from_be_bytes([data[2], data[3]]);
+
+        if hardware != 1 || protocol != super::ethernet::ETHERTYPE_IPV4 || data[4] != 6 || data[5] != 4 {
+            return None;
+        }
+
+        Some(Self {
+            op: u16::from_be_bytes([data[6], data[7]]),
+            sender_mac: MacAddress(data[8..14].try_into().unwrap()),
+            sender_ip: Ipv4Address(data[14..18].try_into().unwrap()),
+            target_mac: MacAddress(data[18..24].try_into().unwrap()),
+            target_ip: Ipv4Address(data[24..28].try_into().unwrap()),
+        })
+    }
+
+    pub fn bytes(&self) -> [u8; LEN] {
+        let mut bytes = [0; LEN];
+
+        bytes[0..2].copy_from_slice(&1u16.to_be_bytes());
+        bytes[2..4].copy_from_slice(&super::ethernet::ETHERTYPE_IPV4.to_be_bytes());
+        bytes[4] = 6;
+        bytes[5] = 4;
+        bytes[6..8].copy_from_slice(&self.op.to_be_bytes());
+        bytes[8..14].copy_from_slice(&self.sender_mac.0);
+        bytes[14..18].copy_from_slice(&self.sender_ip.0);
+        bytes[18..24].copy_from_slice(&self.target_mac.0);
+        bytes[24..28].copy_from_slice(&self.target_ip.0);
+
+        bytes
+    }
+}
+
+/// Link layer addresses learned so far
+#[derive(Debug, Default)]
+pub struct Cache {
+    entries: BTreeMap<Ipv4Address, MacAddress>,
+}
+
+impl Cache {
+    pub fn insert(&mut self, ip: Ipv4Address, mac: MacAddress) {
+        self.entries.insert(ip, mac);
+    }
+
+    pub fn get(&self, ip: Ipv4Address) -> Option<MacAddress> {
+        self.entries.get(&ip).copied()
+    }
+}
@@ -0,0 +1,59 @@
+use alloc::vec::Vec;
+
+pub const HEADER_LEN: usize = 14;
+
+/// Frames shorter than this, not counting the frame check sequence, get padded
+const MIN_LEN: usize = 60;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: Self = Self([0xff; 6]);
+    pub const ZERO: Self = Self([0; 6]);
+}
+
+impl core::fmt::Debug for MacAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+pub struct Frame<'a> {
+    pub dest: MacAddress,
+    pub src: MacAddress,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+
+        Some(Self {
+            dest: MacAddress(data[0..6].try_into().unwrap()),
+            src: MacAddress(data[6..12].try_into().unwrap()),
+            ethertype: u16::from_be_bytes([data[12], data[13]]),
+            payload: &data[HEADER_LEN..],
+        })
+    }
+}
+
+/// Builds a frame around `payload`, padded to the minimum frame size
+pub fn build(dest: MacAddress, src: MacAddress, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity((HEADER_LEN + payload.len()).max(MIN_LEN));
+
+    frame.extend_from_slice(&dest.0);
+    frame.extend_from_slice(&src.0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.resize(frame.len().max(MIN_LEN), 0);
+
+    frame
+}
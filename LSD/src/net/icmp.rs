@@ -0,0 +1,50 @@
+use alloc::vec::Vec;
+
+use super::ipv4::checksum;
+
+pub const ECHO_REPLY: u8 = 0;
+pub const ECHO_REQUEST: u8 = 8;
+
+const HEADER_LEN: usize = 8;
+
+/// An echo request or reply, the only ICMP messages we handle
+pub struct Echo<'a> {
+    pub kind: u8,
+    pub ident: u16,
+    pub seq: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> Echo<'a> {
+    /// Parses an echo message, ignoring other messages and any with a bad checksum
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || !matches!(data[0], ECHO_REPLY | ECHO_REQUEST) || data[1] != 0 {
+            return None;
+        }
+
+        if checksum(&[data]) != 0 {
+            return None;
+        }
+
+        Some(Self {
+            kind: data[0],
+            ident: u16::from_be_bytes([data[4], data[5]]),
+            seq: u16::from_be_bytes([data[6], data[7]]),
+            data: &data[HEADER_LEN..],
+        })
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(HEADER_LEN + self.data.len());
+
+        message.extend_from_slice(&[self.kind, 0, 0, 0]);
+        message.extend_from_slice(&self.ident.to_be_bytes());
+        message.extend_from_slice(&self.seq.to_be_bytes());
+        message.extend_from_slice(self.data);
+
+        let sum = checksum(&[&message]);
+        message[2..4].copy_from_slice(&sum.to_be_bytes());
+
+        message
+    }
+}
@@ -0,0 +1,133 @@
+use alloc::vec::Vec;
+
+pub const HEADER_LEN: usize = 20;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const DEFAULT_TTL: u8 = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const BROADCAST: Self = Self([255; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    pub fn to_bits(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    /// Whether both addresses are on the same network, under `netmask`
+    pub fn same_network(self, other: Self, netmask: Self) -> bool {
+        self.to_bits() & netmask.to_bits() == other.to_bits() & netmask.to_bits()
+    }
+}
+
+impl core::fmt::Debug for Ipv4Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl core::fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
+    }
+}
+
+pub struct Packet<'a> {
+    pub src: Ipv4Address,
+    pub dest: Ipv4Address,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Parses a packet, dropping any with a bad header or that are fragments, since we don't reassemble them
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[0] >> 4 != 4 {
+            return None;
+        }
+
+        let header_len = (data[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+
+        // Ethernet padding can leave the frame longer than the packet, never shorter
+        if header_len < HEADER_LEN || total_len < header_len || total_len > data.len() {
+            return None;
+        }
+
+        if checksum(&[&data[..header_len]]) != 0 {
+            return None;
+        }
+
+        // More Fragments, or a fragment offset
+        let fragment = u16::from_be_bytes([data[6], data[7]]);
+        if fragment & 0x3fff != 0 {
+            return None;
+        }
+
+        Some(Self {
+            src: Ipv4Address(data[12..16].try_into().unwrap()),
+            dest: Ipv4Address(data[16..20].try_into().unwrap()),
+            protocol: data[9],
+            ttl: data[8],
+            payload: &data[header_len..total_len],
+        })
+    }
+}
+
+/// Builds a packet around `payload`, with Don't Fragment set
+pub fn build(src: Ipv4Address, dest: Ipv4Address, protocol: u8, ident: u16, payload: &[u8]) -> Vec<u8> {
+    let total_len = (HEADER_LEN + payload.len()) as u16;
+
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = 0x45;
+    header[2..4].copy_from_slice(&total_len.to_be_bytes());
+    header[4..6].copy_from_slice(&ident.to_be_bytes());
+    header[6] = 0x40;
+    header[8] = DEFAULT_TTL;
+    header[9] = protocol;
+    header[12..16].copy_from_slice(&src.0);
+    header[16..20].copy_from_slice(&dest.0);
+
+    let sum = checksum(&[&header]);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.extend_from_slice(&header);
+    packet.extend_from_slice(payload);
+
+    packet
+}
+
+/// The Internet checksum over the concatenation of `parts`, each of which but the last must be of even length
+///
+/// Summing data that already holds its checksum gives 0 when it's correct.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match word {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => unreachable!(),
+            };
+
+            sum += word as u32;
+        }
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
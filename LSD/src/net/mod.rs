@@ -0,0 +1,300 @@
+//! A small IPv4 stack over the network device
+//!
+//! Only what's needed to talk to hosts on the local network or through a gateway: ARP, ICMP echo, and UDP.
+//! Fragmented packets are dropped, and everything we send fits in one frame.
+
+pub mod ethernet;
+pub mod arp;
+pub mod ipv4;
+pub mod icmp;
+pub mod udp;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+
+use ethernet::MacAddress;
+use ipv4::Ipv4Address;
+
+use crate::{drivers::virtio::net as device, println, timing::Instant};
+
+pub static STACK: Mutex<Option<Stack>> = Mutex::new(None);
+
+/// Port of the built in UDP echo service
+pub const ECHO_PORT: u16 = 7;
+
+/// Identifier of the echo requests we send
+const PING_IDENT: u16 = 0x4c53;
+
+/// Largest IP packet that fits in a frame
+const MTU: usize = 1500;
+
+/// Packets held per address while waiting for it to be resolved
+const MAX_WAITING: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub address: Ipv4Address,
+    pub netmask: Ipv4Address,
+    pub gateway: Ipv4Address,
+}
+
+/// The addresses QEMU's user mode network hands out to its first guest
+pub const DEFAULT_CONFIG: Config = Config {
+    address: Ipv4Address::new(10, 0, 2, 15),
+    netmask: Ipv4Address::new(255, 255, 255, 0),
+    gateway: Ipv4Address::new(10, 0, 2, 2),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// There is no network device to send through
+    NoInterface,
+    /// Another socket is bound to the port
+    PortInUse,
+    /// The data doesn't fit in a single packet
+    TooLarge,
+    /// The device's transmit queue is full
+    Busy,
+}
+
+pub struct Stack {
+    mac: MacAddress,
+    config: Config,
+    arp: arp::Cache,
+
+    /// IP packets waiting on the link layer address of their next hop
+    waiting: BTreeMap<Ipv4Address, Vec<Vec<u8>>>,
+
+    pub udp: udp::Sockets,
+
+    /// When each echo request still waiting on a reply was sent, by sequence number
+    pings: BTreeMap<u16, Instant>,
+
+    /// Identification of the next IP packet sent
+    ident: u16,
+}
+
+impl Stack {
+    fn new(mac: MacAddress, config: Config) -> Self {
+        Self {
+            mac,
+            config,
+            arp: arp::Cache::default(),
+            waiting: BTreeMap::new(),
+            udp: udp::Sockets::default(),
+            pings: BTreeMap::new(),
+            ident: 0,
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    fn send_frame(&self, dest: MacAddress, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+        match device::transmit(&ethernet::build(dest, self.mac, ethertype, payload)) {
+            true => Ok(()),
+            false => Err(NetError::Busy),
+        }
+    }
+
+    fn send_arp(&self, op: u16, target_mac: MacAddress, target_ip: Ipv4Address) -> Result<(), NetError> {
+        let packet = arp::Packet {
+            op,
+            sender_mac: self.mac,
+            sender_ip: self.config.address,
+            target_mac,
+            target_ip,
+        };
+
+        let dest = match op {
+            arp::REQUEST => MacAddress::BROADCAST,
+            _ => target_mac,
+        };
+
+        self.send_frame(dest, ethernet::ETHERTYPE_ARP, &packet.bytes())
+    }
+
+    /// Sends an IP packet, resolving the address of the next hop first if we don't know it yet
+    pub fn send_ip(&mut self, dest: Ipv4Address, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+        if ipv4::HEADER_LEN + payload.len() > MTU {
+            return Err(NetError::TooLarge);
+        }
+
+        let packet = ipv4::build(self.config.address, dest, protocol, self.ident, payload);
+        self.ident = self.ident.wrapping_add(1);
+
+        if dest == Ipv4Address::BROADCAST {
+            return self.send_frame(MacAddress::BROADCAST, ethernet::ETHERTYPE_IPV4, &packet);
+        }
+
+        let next_hop = match dest.same_network(self.config.address, self.config.netmask) {
+            true => dest,
+            false => self.config.gateway,
+        };
+
+        if let Some(mac) = self.arp.get(next_hop) {
+            return self.send_frame(mac, ethernet::ETHERTYPE_IPV4, &packet);
+        }
+
+        // Only ask once, the reply sends everything queued
+        let waiting = self.waiting.entry(next_hop).or_default();
+        let first = waiting.is_empty();
+
+        if waiting.len() < MAX_WAITING {
+            waiting.push(packet);
+        }
+
+        match first {
+            true => self.send_arp(arp::REQUEST, MacAddress::ZERO, next_hop),
+            false => Ok(()),
+        }
+    }
+
+    pub fn send_udp(&mut self, src_port: u16, dest: Ipv4Address, dest_port: u16, data: &[u8]) -> Result<(), NetError> {
+        let datagram = udp::build(self.config.address, dest, src_port, dest_port, data)?;
+
+        self.send_ip(dest, ipv4::PROTOCOL_UDP, &datagram)
+    }
+
+    /// Sends an echo request, the reply is logged when it arrives
+    pub fn ping(&mut self, dest: Ipv4Address, seq: u16) -> Result<(), NetError> {
+        let request = icmp::Echo {
+            kind: icmp::ECHO_REQUEST,
+            ident: PING_IDENT,
+            seq,
+            data: b"LSD ping",
+        };
+
+        self.pings.insert(seq, Instant::now());
+
+        self.send_ip(dest, ipv4::PROTOCOL_ICMP, &request.build())
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        let Some(frame) = ethernet::Frame::parse(data) else {
+            return;
+        };
+
+        if frame.dest != self.mac && frame.dest != MacAddress::BROADCAST {
+            return;
+        }
+
+        match frame.ethertype {
+            ethernet::ETHERTYPE_ARP => self.receive_arp(frame.payload),
+            ethernet::ETHERTYPE_IPV4 => self.receive_ip(frame.payload),
+            _ => {},
+        }
+    }
+
+    fn receive_arp(&mut self, data: &[u8]) {
+        let Some(packet) = arp::Packet::parse(data) else {
+            return;
+        };
+
+        // Learn from every packet, not only replies, so the host asking us doesn't have to be asked back
+        self.arp.insert(packet.sender_ip, packet.sender_mac);
+
+        for waiting in self.waiting.remove(&packet.sender_ip).into_iter().flatten() {
+            let _ = self.send_frame(packet.sender_mac, ethernet::ETHERTYPE_IPV4, &waiting);
+        }
+
+        if packet.op == arp::REQUEST && packet.target_ip == self.config.address {
+            let _ = self.send_arp(arp::REPLY, packet.sender_mac, packet.sender_ip);
+        }
+    }
+
+    fn receive_ip(&mut self, data: &[u8]) {
+        let Some(packet) = ipv4::Packet::parse(data) else {
+            return;
+        };
+
+        if packet.dest != self.config.address && packet.dest != Ipv4Address::BROADCAST {
+            return;
+        }
+
+        match packet.protocol {
+            ipv4::PROTOCOL_ICMP => self.receive_icmp(&packet),
+            ipv4::PROTOCOL_UDP => self.receive_udp(&packet),
+            _ => {},
+        }
+    }
+
+    fn receive_icmp(&mut self, packet: &ipv4::Packet) {
+        let Some(echo) = icmp::Echo::parse(packet.payload) else {
+            return;
+        };
+
+        match echo.kind {
+            icmp::ECHO_REQUEST => {
+                let reply = icmp::Echo {
+                    kind: icmp::ECHO_REPLY,
+                    ..echo
+                };
+
+                let _ = self.send_ip(packet.src, ipv4::PROTOCOL_ICMP, &reply.build());
+            },
+            icmp::ECHO_REPLY if echo.ident == PING_IDENT => {
+                if let Some(sent) = self.pings.remove(&echo.seq) {
+                    println!(
+                        "Reply from {}: seq={} ttl={} time={:?}",
+                        packet.src,
+                        echo.seq,
+                        packet.ttl,
+                        Instant::now().duration_since(sent),
+                    );
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn receive_udp(&mut self, packet: &ipv4::Packet) {
+        let Some(datagram) = udp::parse(packet.src, packet.dest, packet.payload) else {
+            return;
+        };
+
+        if datagram.dest_port == ECHO_PORT {
+            let _ = self.send_udp(ECHO_PORT, datagram.src, datagram.src_port, &datagram.data);
+            return;
+        }
+
+        self.udp.deliver(datagram);
+    }
+}
+
+/// Runs `f` on the stack, returning `NetError::NoInterface` if there isn't one
+pub fn with_stack<R>(f: impl FnOnce(&mut Stack) -> Result<R, NetError>) -> Result<R, NetError> {
+    // Received frames are handled from the device's interrupt
+    crate::traps::without_interrupts(|| {
+        STACK.lock().as_mut().map_or(Err(NetError::NoInterface), f)
+    })
+}
+
+/// Brings the stack up on a network device, and pings the gateway to check the link works
+pub fn interface_up(mac: [u8; 6]) {
+    let config = DEFAULT_CONFIG;
+
+    println!(
+        "net: {} netmask {} gateway {}, UDP echo on port {}",
+        config.address,
+        config.netmask,
+        config.gateway,
+        ECHO_PORT,
+    );
+
+    crate::traps::without_interrupts(|| {
+        *STACK.lock() = Some(Stack::new(MacAddress(mac), config));
+    });
+
+    if let Err(e) = with_stack(|stack| stack.ping(config.gateway, 1)) {
+        println!("net: failed to ping the gateway: {:?}", e);
+    }
+}
+
+/// Hands a received Ethernet frame to the stack
+pub fn receive(frame: &[u8]) {
+    if let Some(stack) = STACK.lock().as_mut() {
+        stack.receive(frame);
+    }
+}
@@ -0,0 +1,113 @@
+use alloc::{collections::{BTreeMap, VecDeque}, vec::Vec};
+
+use super::{ipv4::{self, checksum, Ipv4Address}, NetError};
+
+const HEADER_LEN: usize = 8;
+
+/// Datagrams a socket holds before new ones are dropped
+const RECEIVE_BACKLOG: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub src: Ipv4Address,
+    pub src_port: u16,
+    pub dest_port: u16,
+    pub data: Vec<u8>,
+}
+
+/// Fills in the checksum, which covers a pseudo header taken from the IP addresses
+fn udp_checksum(src: Ipv4Address, dest: Ipv4Address, datagram: &[u8]) -> u16 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.0);
+    pseudo[4..8].copy_from_slice(&dest.0);
+    pseudo[9] = ipv4::PROTOCOL_UDP;
+    pseudo[10..12].copy_from_slice(&(datagram.len() as u16).to_be_bytes());
+
+    checksum(&[&pseudo, datagram])
+}
+
+/// Parses a datagram out of an IP payload, dropping it if the checksum is present and wrong
+pub fn parse(src: Ipv4Address, dest: Ipv4Address, data: &[u8]) -> Option<Datagram> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+
+    let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+    if len < HEADER_LEN || len > data.len() {
+        return None;
+    }
+
+    let data = &data[..len];
+
+    // A zero checksum means the sender didn't compute one
+    if u16::from_be_bytes([data[6], data[7]]) != 0 && udp_checksum(src, dest, data) != 0 {
+        return None;
+    }
+
+    Some(Datagram {
+        src,
+        src_port: u16::from_be_bytes([data[0], data[1]]),
+        dest_port: u16::from_be_bytes([data[2], data[3]]),
+        data: data[HEADER_LEN..].to_vec(),
+    })
+}
+
+pub fn build(src: Ipv4Address, dest: Ipv4Address, src_port: u16, dest_port: u16, data: &[u8]) -> Result<Vec<u8>, NetError> {
+    let len = u16::try_from(HEADER_LEN + data.len()).map_err(|_| NetError::TooLarge)?;
+
+    let mut datagram = Vec::with_capacity(len as usize);
+    datagram.extend_from_slice(&src_port.to_be_bytes());
+    datagram.extend_from_slice(&dest_port.to_be_bytes());
+    datagram.extend_from_slice(&len.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+
+    // A computed checksum of zero is sent as all ones, since zero means there isn't one
+    let sum = match udp_checksum(src, dest, &datagram) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    Ok(datagram)
+}
+
+/// Received datagrams, by the port they were sent to
+#[derive(Debug, Default)]
+pub struct Sockets {
+    bound: BTreeMap<u16, VecDeque<Datagram>>,
+}
+
+impl Sockets {
+    pub fn bind(&mut self, port: u16) -> Result<(), NetError> {
+        if self.bound.contains_key(&port) {
+            return Err(NetError::PortInUse);
+        }
+
+        self.bound.insert(port, VecDeque::new());
+
+        Ok(())
+    }
+
+    pub fn unbind(&mut self, port: u16) {
+        self.bound.remove(&port);
+    }
+
+    /// Queues a datagram on the socket bound to its port, returning `false` if there is none
+    pub fn deliver(&mut self, datagram: Datagram) -> bool {
+        match self.bound.get_mut(&datagram.dest_port) {
+            Some(queue) => {
+                if queue.len() < RECEIVE_BACKLOG {
+                    queue.push_back(datagram);
+                }
+
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn recv(&mut self, port: u16) -> Option<Datagram> {
+        self.bound.get_mut(&port)?.pop_front()
+    }
+}
@@ -0,0 +1,207 @@
+//! Kernel entropy pool
+//!
+//! Entropy from the hardware is folded into a ChaCha20 key. Output is generated with fast key erasure, so the
+//! key used for a request is replaced before the bytes are handed out.
+
+use spin::Mutex;
+
+use crate::{memory::vmm, traps::{task, TrapFrame}};
+
+pub static POOL: Mutex<Pool> = Mutex::new(Pool::new());
+
+/// Bytes of hardware entropy needed before the pool hands anything out
+const SEED_BYTES: usize = 32;
+
+/// Nonce used when mixing in entropy
+const MIX_NONCE: [u32; 3] = [1, 0, 0];
+
+/// How many requests are served before asking the hardware for fresh entropy
+const RESEED_INTERVAL: usize = 1024;
+
+pub struct Pool {
+    key: [u32; 8],
+    /// Hardware entropy folded in so far, saturating at `SEED_BYTES`
+    entropy: usize,
+    /// Requests served since entropy was last added
+    served: usize,
+}
+
+impl Pool {
+    const fn new() -> Self {
+        Self {
+            key: [0; 8],
+            entropy: 0,
+            served: 0,
+        }
+    }
+
+    pub fn is_seeded(&self) -> bool {
+        self.entropy >= SEED_BYTES
+    }
+
+    /// Whether the hardware should be asked for more entropy
+    pub fn wants_entropy(&self) -> bool {
+        !self.is_seeded() || self.served >= RESEED_INTERVAL
+    }
+
+    /// Folds bytes from an entropy source into the key
+    pub fn add_entropy(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(32) {
+            let mut input = [0u32; 8];
+
+            for (index, byte) in chunk.iter().enumerate() {
+                input[index / 4] |= (*byte as u32) << ((index % 4) * 8);
+            }
+
+            for (word, input) in self.key.iter_mut().zip(input) {
+                *word ^= input;
+            }
+
+            // Rekeying hides the input, the separate nonce keeps this apart from the blocks `fill` uses
+            let block = chacha20_block(&self.key, 0, MIX_NONCE);
+            self.key.copy_from_slice(&block[..8]);
+        }
+
+        self.entropy = (self.entropy + bytes.len()).min(SEED_BYTES);
+        self.served = 0;
+    }
+
+    /// Fills `buf` with random bytes, returning `false` if the pool hasn't been seeded yet
+    pub fn fill(&mut self, buf: &mut [u8]) -> bool {
+        if !self.is_seeded() {
+            return false;
+        }
+
+        // The first block replaces the key, later ones are output
+        let mut counter = 0;
+        let next_key = chacha20_block(&self.key, counter, [0; 3]);
+
+        for chunk in buf.chunks_mut(64) {
+            counter += 1;
+            let block = chacha20_block(&self.key, counter, [0; 3]);
+
+            for (index, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[index / 4] >> ((index % 4) * 8)) as u8;
+            }
+        }
+
+        self.key.copy_from_slice(&next_key[..8]);
+        self.served += 1;
+
+        true
+    }
+}
+
+/// Produces one 64 byte ChaCha20 block, as sixteen little endian words
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: [u32; 3]) -> [u32; 16] {
+    let mut state = [
+        0x61707865, 0x3320646e, 0x79622d32, 0x6b206574,
+        key[0], key[1], key[2], key[3],
+        key[4], key[5], key[6], key[7],
+        counter, nonce[0], nonce[1], nonce[2],
+    ];
+    let initial = state;
+
+    fn quarter(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(16);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(12);
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(8);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(7);
+    }
+
+    for _ in 0..10 {
+        quarter(&mut state, 0, 4, 8, 12);
+        quarter(&mut state, 1, 5, 9, 13);
+        quarter(&mut state, 2, 6, 10, 14);
+        quarter(&mut state, 3, 7, 11, 15);
+
+        quarter(&mut state, 0, 5, 10, 15);
+        quarter(&mut state, 1, 6, 11, 12);
+        quarter(&mut state, 2, 7, 8, 13);
+        quarter(&mut state, 3, 4, 9, 14);
+    }
+
+    for (word, initial) in state.iter_mut().zip(initial) {
+        *word = word.wrapping_add(initial);
+    }
+
+    state
+}
+
+/// Wakes every thread that asked for random bytes before the pool was seeded
+///
+/// They're parked with their `ecall` rewound, so they retry the request once they run.
+pub fn wake_waiters() {
+    task::THREADS.lock().wake_all(task::WaitSrc::Entropy);
+}
+
+/// Backs the random bytes syscall, `a2` holds a pointer to the buffer and `a3` its length
+///
+/// If the pool isn't seeded yet the thread waits until it is. `a0` is set to 0 on success, 1 if the buffer
+/// isn't writable user memory, or 2 if there is no entropy source to ever seed the pool.
+pub fn user_request(trap_frame: &mut TrapFrame) {
+    use crate::drivers::virtio::entropy;
+
+    let virt = trap_frame.a2;
+    let len = trap_frame.a3;
+
+    // Looked up before the pool is locked, the device's interrupt handler takes the device lock and then the pool's
+    let present = entropy::present();
+
+    let mut pool = POOL.lock();
+
+    if !pool.is_seeded() {
+        if !present {
+            trap_frame.a0 = 2;
+            return;
+        }
+
+        // Run the ecall again once woken
+        trap_frame.sepc -= 4;
+
+        // Parked with the pool still locked, so it can't be seeded and its waiters woken before we're one of them
+        task::park(trap_frame, task::WaitSrc::Entropy);
+        drop(pool);
+
+        entropy::request_entropy();
+        task::switch_to_next(trap_frame);
+
+        return;
+    }
+
+    // Checked before anything is generated, so a bad buffer doesn't use up a request
+    let Some(segments) = vmm::user_segments(virt, len, true) else {
+        trap_frame.a0 = 1;
+        return;
+    };
+
+    let mut buf = [0u8; 256];
+
+    for (phys, len) in segments {
+        let mut offset = 0;
+
+        while offset < len {
+            let chunk = (len - offset).min(buf.len());
+            pool.fill(&mut buf[..chunk]);
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(buf.as_ptr(), phys.as_ptr().add(offset), chunk);
+            }
+
+            offset += chunk;
+        }
+    }
+
+    let reseed = pool.wants_entropy();
+    drop(pool);
+
+    if reseed {
+        entropy::request_entropy();
+    }
+
+    trap_frame.a0 = 0;
+}
@@ -0,0 +1,164 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::println;
+
+/// Physical address of the first hart's supervisor interrupt file, zero if there is no IMSIC
+pub static IMSIC_BASE: AtomicU64 = AtomicU64::new(0);
+/// Distance between consecutive harts' interrupt files
+pub static IMSIC_STRIDE: AtomicU64 = AtomicU64::new(0x1000);
+/// Number of interrupt identities each file implements
+pub static NUM_IDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Handler for each identity, `None` for ones that are free
+pub static MSI_HANDLERS: Mutex<[Option<fn (usize)>; 64]> = Mutex::new([None; 64]);
+
+const SISELECT: usize = 0x150;
+const SIREG: usize = 0x151;
+const STOPEI: usize = 0x15c;
+
+const EIDELIVERY: usize = 0x70;
+const EITHRESHOLD: usize = 0x72;
+const EIE0: usize = 0xc0;
+
+/// S-mode external interrupt, as found in `interrupts-extended`
+const SUPERVISOR_EXTERNAL: u32 = 9;
+
+pub fn present() -> bool {
+    IMSIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Looks for the supervisor level `riscv,imsics` node, leaving the IMSIC unused if there is none
+pub fn from_fdt(fdt: &fdt::Fdt) {
+    let node = fdt.all_nodes().find(|node| {
+        let compatible = node.compatible().is_some_and(|compat| compat.all().any(|compat| compat == "riscv,imsics"));
+
+        // Each entry is a phandle and the interrupt it signals on that hart
+        let supervisor = node.property("interrupts-extended").is_some_and(|prop| {
+            prop.value.chunks_exact(8)
+                .any(|entry| u32::from_be_bytes([entry[4], entry[5], entry[6], entry[7]]) == SUPERVISOR_EXTERNAL)
+        });
+
+        compatible && supervisor
+    });
+
+    let Some(node) = node else {
+        return;
+    };
+
+    let Some(reg) = node.reg().and_then(|mut reg| reg.next()) else {
+        return;
+    };
+
+    let guest_bits = node.property("riscv,guest-index-bits").and_then(|prop| prop.as_usize()).unwrap_or(0);
+    let num_ids = node.property("riscv,num-ids").and_then(|prop| prop.as_usize()).unwrap_or(63);
+
+    IMSIC_STRIDE.store(0x1000 << guest_bits, Ordering::Relaxed);
+    NUM_IDS.store(num_ids.min(63), Ordering::Relaxed);
+    IMSIC_BASE.store(reg.starting_address as u64, Ordering::Relaxed);
+
+    println!("IMSIC found at {:#x} with {} identities", reg.starting_address as u64, num_ids);
+}
+
+/// Turns on delivery from this hart's interrupt file
+///
+/// # Safety
+/// Only call once per hart, after `from_fdt`
+pub unsafe fn init() {
+    if !present() {
+        return;
+    }
+
+    write_indirect(EIDELIVERY, 1);
+    // A threshold of zero lets every enabled identity through
+    write_indirect(EITHRESHOLD, 0);
+
+    println!("Initialized imsic");
+}
+
+/// Returns the address a device writes an identity to so it is delivered to `hart`
+pub fn message_address(hart: usize) -> u64 {
+    IMSIC_BASE.load(Ordering::Relaxed) + hart as u64 * IMSIC_STRIDE.load(Ordering::Relaxed)
+}
+
+/// Reserves an interrupt identity that calls `handler`, and enables it on this hart
+pub fn alloc(handler: fn(usize)) -> Option<usize> {
+    let mut handlers = MSI_HANDLERS.lock();
+
+    // Identity 0 is never delivered, so hand them out from 1
+    let id = (1..=NUM_IDS.load(Ordering::Relaxed)).find(|&id| handlers[id].is_none())?;
+    handlers[id] = Some(handler);
+
+    // On RV64 each even numbered eie register covers 64 identities
+    unsafe {
+        let reg = EIE0 + (id / 64) * 2;
+        let enabled = read_indirect(reg);
+        write_indirect(reg, enabled | (1 << (id % 64)));
+    }
+
+    Some(id)
+}
+
+/// Disables an identity from `alloc` on this hart and makes it free to hand out again
+pub fn free(id: usize) {
+    let mut handlers = MSI_HANDLERS.lock();
+
+    unsafe {
+        let reg = EIE0 + (id / 64) * 2;
+        let enabled = read_indirect(reg);
+        write_indirect(reg, enabled & !(1 << (id % 64)));
+    }
+
+    handlers[id] = None;
+}
+
+pub fn handle_external() {
+    loop {
+        // Reading and writing stopei together claims the highest priority pending identity
+        let top: usize;
+        unsafe {
+            core::arch::asm!("csrrw {top}, {stopei}, zero", top = out(reg) top, stopei = const STOPEI);
+        }
+
+        let id = (top >> 16) & 0x7ff;
+        if id == 0 {
+            break;
+        }
+
+        let handler = MSI_HANDLERS.lock()[id];
+        match handler {
+            Some(handler) => handler(id),
+            None => unknown(id),
+        }
+    }
+}
+
+unsafe fn read_indirect(reg: usize) -> usize {
+    let val;
+    core::arch::asm!(
+        "csrw {siselect}, {reg}",
+        "csrr {val}, {sireg}",
+        reg = in(reg) reg,
+        val = out(reg) val,
+        siselect = const SISELECT,
+        sireg = const SIREG,
+    );
+
+    val
+}
+
+unsafe fn write_indirect(reg: usize, val: usize) {
+    core::arch::asm!(
+        "csrw {siselect}, {reg}",
+        "csrw {sireg}, {val}",
+        reg = in(reg) reg,
+        val = in(reg) val,
+        siselect = const SISELECT,
+        sireg = const SIREG,
+    );
+}
+
+fn unknown(id: usize) {
+    panic!("Unknown message signaled interrupt 0x{:x}", id);
+}
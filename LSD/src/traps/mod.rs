@@ -9,6 +9,7 @@ use core::sync::atomic::Ordering;
 
 use crate::{println, current_context};
 
+pub mod imsic;
 pub mod plic;
 pub mod task;
 
@@ -24,6 +25,79 @@ pub unsafe fn plic_init() {
     println!("Initialized plic");
 }
 
+/// Lets this hart take interrupts through its own PLIC context
+///
+/// Every device interrupt is enabled on the boot hart's context only, so other harts never claim them, but their
+/// context still needs a threshold set.
+///
+/// # Safety
+/// Only call once per hart, after `plic_init`
+pub unsafe fn plic_hart_init() {
+    let plic = plic::PLIC_ADDR.load(Ordering::Relaxed);
+
+    if !plic.is_null() {
+        (*plic).set_context_threshold(current_context(), 0x1);
+    }
+}
+
+/// Runs `f` with interrupts disabled on this hart, restoring them afterwards if they were enabled
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let mut sstatus = crate::arch::regs::Sstatus::new();
+    let enabled = sstatus.sie();
+
+    sstatus.set_sie(false);
+    unsafe {sstatus.set()}
+
+    let ret = f();
+
+    if enabled {
+        let mut sstatus = crate::arch::regs::Sstatus::new();
+        sstatus.set_sie(true);
+        unsafe {sstatus.set()}
+    }
+
+    ret
+}
+
+/// Claims and handles every pending external interrupt
+fn handle_external() {
+    match imsic::present() {
+        true => imsic::handle_external(),
+        false => plic::handle_external(),
+    }
+}
+
+/// Sleeps until an interrupt is pending and handles it, for when no thread is ready to run
+///
+/// Interrupts stay disabled, `wfi` wakes once one is pending regardless, so a trap can't land in the middle of
+/// the trap handler we're idling in and overwrite its frame.
+pub fn wait_for_interrupt() {
+    unsafe {
+        core::arch::asm!("wfi");
+    }
+
+    let pending = crate::arch::regs::Sip::new();
+
+    if pending.seip() {
+        handle_external();
+    }
+
+    // Nothing is running to charge the tick to, but the timer has to be moved on to stop it being pending
+    if pending.stip() {
+        task::arm_timer();
+    }
+
+    // Whichever hart asked us to reschedule only wanted us out of `wfi`
+    if pending.ssip() {
+        task::clear_reschedule();
+    }
+}
+
+/// Whether the trap came from user mode, rather than the kernel with interrupts enabled
+fn from_user() -> bool {
+    !crate::arch::regs::Sstatus::new().spp()
+}
+
 pub fn init() {
     unsafe {
         let new_sscratch = alloc::alloc::alloc(alloc::alloc::Layout::new::<Sscratch>()) as *mut Sscratch;
@@ -298,8 +372,25 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, scause: usize, stval: usize
 
     match trap {
         Trap::SupervisorTimerInterrupt => {
-            crate::timing::Unit::MilliSeconds(1).set().unwrap();
-            task::advance_task(regs);
+            task::arm_timer();
+
+            if from_user() {
+                task::tick(regs);
+            }
+
+            return;
+        },
+        Trap::SupervisorSoftwareInterrupt => {
+            // Another hart queued a thread for us, or removed the one we're running
+            task::clear_reschedule();
+
+            if from_user() {
+                task::end_if_dying(regs);
+
+                if task::THREADS.lock().should_preempt() {
+                    task::advance_task(regs);
+                }
+            }
 
             return;
         },
@@ -324,17 +415,26 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, scause: usize, stval: usize
             }
         },
         Trap::SupervisorExternalInterrupt => {
-            plic::handle_external();
+            handle_external();
+
+            // A thread the interrupt woke may outrank the one it interrupted
+            if from_user() && task::THREADS.lock().should_preempt() {
+                task::advance_task(regs);
+            }
+
             return;
         },
         Trap::UserModeEnvironmentCall => {
             regs.sepc += 4;
             crate::arch::syscalls::syscall_core(regs);
+
+            // The thread may have been ended from another hart while the syscall ran
+            task::end_if_dying(regs);
             return;
         },
         Trap::Breakpoint => {
-            let mut reader = task::CURRENT_USER_TASK.write();
-            let cur_task = reader.current_task_mut();
+            let mut reader = task::THREADS.lock();
+            let cur_task = reader.current_thread_mut();
             cur_task.waiting_on = task::WaitSrc::Breakpoint;
 
             core::mem::drop(reader);
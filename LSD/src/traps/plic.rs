@@ -1,20 +1,44 @@
 use core::sync::atomic::{AtomicPtr, Ordering};
 
+use alloc::vec::Vec;
 use spin::Mutex;
 
 use crate::current_context;
 
 pub static PLIC_ADDR: AtomicPtr<sifive_plic::Plic> = AtomicPtr::new(core::ptr::null_mut());
-pub static INT_HANDLERS: Mutex<[fn (usize); 64]> = Mutex::new([unknown; 64]);
+/// Every handler for a shared line is called on each interrupt, so each has to check its own device
+pub static INT_HANDLERS: Mutex<[Vec<fn (usize)>; 64]> = Mutex::new({
+    const EMPTY: Vec<fn (usize)> = Vec::new();
+    [EMPTY; 64]
+});
+
+/// Adds `handler` to the ones called for `irq`, alongside any already there
+pub fn add_handler(irq: usize, handler: fn(usize)) {
+    INT_HANDLERS.lock()[irq].push(handler);
+}
 
 pub fn handle_external() {
     let addr = PLIC_ADDR.load(Ordering::Relaxed);
     let context = current_context();
 
     unsafe {
-        let claim = (*addr).claim(context).expect("No claim available");
-        
-        INT_HANDLERS.lock()[claim.interrupt_id()](claim.interrupt_id());
+        // Nothing is left to claim if the interrupt was spurious
+        let Some(claim) = (*addr).claim(context) else {
+            return;
+        };
+
+        let id = claim.interrupt_id();
+        let handlers = INT_HANDLERS.lock();
+
+        if handlers[id].is_empty() {
+            unknown(id);
+        }
+
+        for handler in handlers[id].iter() {
+            handler(id);
+        }
+
+        drop(handlers);
 
         claim.complete();
     }
@@ -1,56 +1,222 @@
-use alloc::vec::Vec;
-use spin::RwLock;
+mod zombies;
 
-pub static CURRENT_USER_TASK: RwLock<TaskQueue> = RwLock::new(TaskQueue::new());
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{collections::{BTreeMap, BTreeSet, VecDeque}, vec::Vec};
+use spin::Mutex;
+
+use zombies::Zombies;
+
+/// Every process and thread, along with each hart's run queues
+pub static THREADS: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+/// This hart's index into the scheduler's harts, set once it registers
+#[thread_local]
+static HART_INDEX: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Number of run queues, threads in lower ones always run first
+pub const PRIORITY_LEVELS: usize = 8;
+
+/// Lowest and highest nice values, which map onto the run queues from the top
+pub const NICE_MIN: i8 = -4;
+pub const NICE_MAX: i8 = 3;
+
+/// Length of a scheduler tick, in milliseconds
+pub const TICK_MS: u64 = 1;
+
+/// Ticks between lifting every thread back to its base priority, so CPU-bound threads can't starve
+const BOOST_TICKS: u64 = 200;
+
+/// A thread's task ID and thread ID
+pub type ThreadKey = (usize, usize);
+
+/// Size of a user thread's stack when it doesn't ask for one
+pub const USER_STACK_SIZE: usize = 0x10_0000;
+
+/// Largest stack a thread can ask for
+pub const MAX_STACK_SIZE: usize = 0x400_0000;
+
+/// Unmapped space left below each stack, so running off the end of one faults rather than landing in another
+const STACK_GUARD: usize = 0x1000;
+
+/// Programs this hart's timer for its next scheduler tick
+pub fn arm_timer() {
+    crate::timing::Unit::MilliSeconds(TICK_MS).set().unwrap();
+}
+
+/// Adds this hart to the scheduler, so threads can be placed on it
+///
+/// Call once per hart, after `HART_ID` is set and before it runs any threads.
+pub fn register_hart() {
+    let mut lock = THREADS.lock();
+
+    HART_INDEX.store(lock.harts.len(), Ordering::Relaxed);
+    lock.harts.push(Hart::new(crate::HART_ID.load(Ordering::Relaxed)));
+
+    for key in core::mem::take(&mut lock.unqueued) {
+        lock.enqueue(key);
+    }
+}
+
+fn hart_index() -> usize {
+    HART_INDEX.load(Ordering::Relaxed)
+}
+
+/// Asks a hart to look at its run queues again
+fn send_reschedule(hart_id: usize) {
+    let _ = sbi::ipi::send_ipi(sbi::HartMask::new(0).with(hart_id));
+}
+
+/// Clears this hart's pending reschedule request, it's acted on by whoever took the interrupt
+pub fn clear_reschedule() {
+    unsafe {
+        core::arch::asm!("csrc sip, {ssip}", ssip = in(reg) 1 << 1);
+    }
+}
 
 pub fn update_current(frame: &mut super::TrapFrame) {
-    let mut lock = CURRENT_USER_TASK.write();
+    let mut lock = THREADS.lock();
 
-    let current = lock.current_task_mut();
+    let current = lock.current_thread_mut();
     current.trap_frame = *frame;
 }
 
+/// Saves the running thread and switches to the next one, which may be the same thread if nothing else can run
 pub fn advance_task(frame: &mut super::TrapFrame) {
-    let mut lock = CURRENT_USER_TASK.write();
+    let mut lock = THREADS.lock();
 
-    let current = lock.current_task_mut();
+    if let Some(current) = lock.running_mut() {
+        current.trap_frame = *frame;
+    }
 
-    current.trap_frame = *frame;
+    lock.switch_out();
+    core::mem::drop(lock);
 
-    lock.advance();
-    let new_task = lock.current_task();
+    switch_to_next(frame);
+}
 
-    unsafe {
-        let new_satp = new_task.task_table.0;
-        
-        core::arch::asm!(
-            "csrw satp, {new_satp}",
-            new_satp = in(reg) new_satp
-        );
-        core::arch::asm!("sfence.vma");
+/// Parks the running thread waiting on `src`, with `frame` saved as where it carries on from
+///
+/// Call before the request being waited on is visible to whatever completes it, so a completion on another hart
+/// always finds the thread blocked with its frame saved. The hart then moves on with `switch_to_next`, not
+/// `advance_task`, which would save the frame again over any result filled in since.
+pub fn park(frame: &super::TrapFrame, src: WaitSrc) -> ThreadKey {
+    let mut lock = THREADS.lock();
+
+    let thread = lock.current_thread_mut();
+    thread.trap_frame = *frame;
+    thread.waiting_on = src;
+
+    let key = thread.key();
+    lock.switch_out();
+
+    key
+}
+
+/// Wakes a thread from `park` whose request could not be made after all, with `result` in `a0`
+pub fn unpark(key: ThreadKey, src: WaitSrc, result: usize) {
+    if let Some(thread) = THREADS.lock().wake(key.0, key.1, src) {
+        thread.trap_frame.a0 = result;
     }
+}
+
+/// Switches away from the running thread if another hart removed it, finishing the removal
+///
+/// Call on the way back to user mode, the thread is kept until then so a syscall it's partway through can finish.
+pub fn end_if_dying(frame: &mut super::TrapFrame) {
+    let mut lock = THREADS.lock();
 
-    *frame = new_task.trap_frame;
+    if lock.current().is_some_and(|key| lock.dying.contains(&key)) {
+        lock.switch_out();
+        core::mem::drop(lock);
+
+        switch_to_next(frame);
+    }
 }
 
-pub fn new_task(task_data: TaskData) {
-    CURRENT_USER_TASK.write().new_task(task_data);
+/// Charges the running thread for a tick, switching away from it if its time slice is up or a higher priority
+/// thread is waiting
+pub fn tick(frame: &mut super::TrapFrame) {
+    let preempt = THREADS.lock().tick();
+
+    if preempt {
+        advance_task(frame);
+    }
+}
+
+/// Loads the highest priority runnable thread into `frame`, waiting for an interrupt to wake one if there are none
+pub fn switch_to_next(frame: &mut super::TrapFrame) {
+    *frame = next_thread().trap_frame;
 }
 
-pub fn drop_task(task_index: usize) {
-    let mut lock = CURRENT_USER_TASK.write();
+/// Picks the next thread for this hart and switches to its process' address space, waiting for one if none can
+/// run
+pub fn next_thread() -> Thread {
+    loop {
+        let mut lock = THREADS.lock();
+
+        if let Some(next) = lock.pick() {
+            let thread = lock.threads[&next];
+            let table = lock.processes[&thread.task_id].table;
+
+            lock.load_table(Some(thread.task_id), table);
+            lock.reap();
+
+            return thread;
+        }
 
-    if lock.cur_task_idx == task_index {
-        lock.advance();
+        // The table of a task that just exited may still be loaded, and can't be freed until we let go of it
+        let kernel_table = lock.this_hart().kernel_table;
+        lock.load_table(None, kernel_table);
+        lock.reap();
+
+        // Interrupt handlers wake threads through the lock
+        core::mem::drop(lock);
+        super::wait_for_interrupt();
+    }
+}
+
+/// Makes the table this hart is using now the one it goes back to while idle
+///
+/// Call before the hart first runs a thread, from then on it only loads task tables.
+pub fn save_kernel_table() {
+    THREADS.lock().this_hart_mut().kernel_table = crate::memory::vmm::Satp::new();
+}
+
+/// Adds a process along with its first thread
+pub fn new_process(process: Process, thread: Thread) {
+    // Device interrupts wake threads through the same lock
+    super::without_interrupts(|| THREADS.lock().new_process(process, thread));
+}
+
+/// Ends the running thread and switches to the next one
+pub fn exit_thread(frame: &mut super::TrapFrame) {
+    let mut lock = THREADS.lock();
+
+    if let Some(key) = lock.current() {
+        lock.remove(key);
     }
 
-    lock.queue.swap_remove(task_index);
-    lock.cur_task_idx -= 1;
+    core::mem::drop(lock);
+    switch_to_next(frame);
 }
 
-pub fn full_drop_task(task_index: usize) {
-    // TODO: Properly drop tasks, release their memory
-    drop_task(task_index);
+/// Ends every thread of the running task with `code` as its exit code, and switches to the next one
+pub fn exit_task(frame: &mut super::TrapFrame, code: usize) {
+    let mut lock = THREADS.lock();
+
+    if let Some((task_id, _)) = lock.current() {
+        lock.processes.get_mut(&task_id).unwrap().exit_code = code;
+
+        let threads: Vec<_> = lock.processes[&task_id].threads.iter().copied().collect();
+
+        for thread_id in threads {
+            lock.remove((task_id, thread_id));
+        }
+    }
+
+    core::mem::drop(lock);
+    switch_to_next(frame);
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -62,22 +228,318 @@ pub enum Privilege {
     Guest
 }
 
+/// Scheduling state of a thread
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SchedInfo {
+    /// Shifts the thread's base priority, from `NICE_MIN` to `NICE_MAX`
+    pub nice: i8,
+    /// Run queue the thread goes in, it sinks a level every time it uses up a time slice
+    pub level: u8,
+    /// Ticks used of the current time slice
+    pub slice_used: u32,
+    /// Ticks the thread has run for in total
+    pub runtime: u64,
+    /// Index of the hart the thread is queued on, or last ran on
+    pub hart: usize,
+}
+
+impl SchedInfo {
+    pub fn new(nice: i8) -> Self {
+        let mut new_self = Self {
+            nice: nice.clamp(NICE_MIN, NICE_MAX),
+            level: 0,
+            slice_used: 0,
+            runtime: 0,
+            hart: 0,
+        };
+
+        new_self.level = new_self.base_level();
+        new_self
+    }
+
+    /// The run queue the thread starts in, and returns to when it blocks or is boosted
+    pub fn base_level(&self) -> u8 {
+        (self.nice - NICE_MIN) as u8
+    }
+
+    /// Ticks a thread gets at its level before it's preempted, lower priority threads run for longer at a time
+    pub fn slice_len(&self) -> u32 {
+        2 * (self.level as u32 + 1)
+    }
+}
+
+impl Default for SchedInfo {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+/// A task's address space, and everything else its threads share
+pub struct Process {
+    pub task_id: usize,
+    pub table: crate::memory::vmm::Satp,
+    pub privilege: Privilege,
+    /// Unused parts of the lower half
+    pub vmm: vmem::Vmem<'static, 'static>,
+    /// Thread IDs not in use
+    pub thread_ids: vmem::Vmem<'static, 'static>,
+    /// IDs of the threads that haven't exited yet
+    pub threads: BTreeSet<usize>,
+    /// Threads that have exited but haven't been joined or detached, their IDs stay taken until then
+    exited_threads: BTreeSet<usize>,
+    /// Threads that haven't exited yet and won't be joined, their IDs are let go of as soon as they exit
+    detached: BTreeSet<usize>,
+    /// Base and size of the stacks of exited threads, left mapped for the next threads to be spawned
+    spare_stacks: Vec<(usize, usize)>,
+    /// Given to whoever waits on the task once its last thread exits, zero unless the task ends itself
+    pub exit_code: usize,
+}
+
+impl Process {
+    pub fn new(task_id: usize, table: crate::memory::vmm::Satp, privilege: Privilege, vmm: vmem::Vmem<'static, 'static>) -> Self {
+        let thread_ids = vmem::Vmem::new(
+            alloc::borrow::Cow::Borrowed("task_thread_manager"),
+            1,
+            None
+        );
+
+        // Fork style spawning tells the parent apart by a thread ID of 0, and usize::MAX is a failed spawn, so
+        // neither is ever handed out
+        thread_ids.add(1, usize::MAX - 1).unwrap();
+
+        Self {
+            task_id,
+            table,
+            privilege,
+            vmm,
+            thread_ids,
+            threads: BTreeSet::new(),
+            exited_threads: BTreeSet::new(),
+            detached: BTreeSet::new(),
+            spare_stacks: Vec::new(),
+            exit_code: 0,
+        }
+    }
+
+    /// The process' root table, through the HHDM
+    pub fn table_ptr(&self) -> *mut crate::memory::vmm::PageTable {
+        let phys = self.table.get_ppn() << 12;
+
+        (phys + crate::memory::HHDM_OFFSET.load(Ordering::Relaxed)) as *mut crate::memory::vmm::PageTable
+    }
+
+    /// Makes a thread with its own stack of at least `stack_size` bytes, starting from `trap_frame` with the stack
+    /// pointer at the top of the stack
+    ///
+    /// The thread still has to be handed to the scheduler.
+    pub fn new_thread(&mut self, trap_frame: super::TrapFrame, nice: i8, stack_size: usize) -> Option<Thread> {
+        if stack_size > MAX_STACK_SIZE {
+            return None;
+        }
+
+        let stack_size = stack_size.max(0x1000).next_multiple_of(0x1000);
+        let thread_id = self.thread_ids.alloc(1, vmem::AllocStrategy::NextFit).ok()?;
+
+        let spare = self.spare_stacks.iter().position(|(_, size)| *size == stack_size);
+        let stack = match spare {
+            Some(index) => {
+                let (base, size) = self.spare_stacks.swap_remove(index);
+                Self::zero_stack(base, size);
+
+                Some(base)
+            },
+            None => self.map_stack(stack_size),
+        };
+
+        let Some(stack) = stack else {
+            self.thread_ids.free(thread_id, 1);
+            return None;
+        };
+
+        let mut thread = Thread {
+            trap_frame,
+            task_id: self.task_id,
+            thread_id,
+            waiting_on: WaitSrc::None,
+            sched: SchedInfo::new(nice),
+            stack,
+            stack_size,
+        };
+
+        thread.trap_frame.sp = stack + STACK_GUARD + stack_size;
+        self.threads.insert(thread_id);
+
+        Some(thread)
+    }
+
+    /// Maps `bytes` of zeroed memory wherever there's room in the lower half, returning where
+    pub fn alloc_heap(&self, bytes: usize) -> Option<usize> {
+        let size = bytes.checked_next_multiple_of(0x1000).filter(|size| *size != 0)?;
+        let base = self.vmm.alloc(size, vmem::AllocStrategy::NextFit).ok()?;
+
+        if !self.map_zeroed(base, size) {
+            self.vmm.free(base, size);
+            return None;
+        }
+
+        Some(base)
+    }
+
+    /// Maps zeroed frames over `size` bytes at `base`, readable and writable from user mode
+    ///
+    /// Returns false if memory runs out, with anything already mapped unmapped and its frames freed. Tables made
+    /// along the way stay in the process' table until it's torn down.
+    fn map_zeroed(&self, base: usize, size: usize) -> bool {
+        use crate::memory::{self, pmm, vmm};
+
+        let table = self.table_ptr();
+        let level = vmm::PageLevel::from_usize(vmm::LEVELS.load(Ordering::Relaxed) as usize);
+        let flags = vmm::PageFlags::READ | vmm::PageFlags::WRITE | vmm::PageFlags::USER;
+        let pages = (size / 0x1000) as u64;
+
+        let mut pmm_lock = pmm::REGION_LIST.lock();
+
+        // `map` takes frames for the tables it needs without checking there are any
+        if pages + vmm::tables_needed(pages, level) >= pmm_lock.free_frames() as u64 {
+            return false;
+        }
+
+        for offset in (0..size).step_by(0x1000) {
+            let Some(frame) = pmm_lock.try_claim() else {
+                for mapped in (0..offset).step_by(0x1000) {
+                    unsafe {
+                        let virt = memory::VirtualAddress((base + mapped) as u64);
+                        let phys = vmm::unmap(table, virt, level, vmm::PageLevel::Level1);
+
+                        pmm_lock.pull(phys.as_ptr());
+                    }
+                }
+
+                unsafe {
+                    core::arch::asm!("sfence.vma");
+                }
+
+                return false;
+            };
+
+            unsafe {
+                // Frames come back from exited tasks with their data still in them
+                frame.write_bytes(0, 0x1000);
+
+                vmm::map(
+                    table,
+                    memory::VirtualAddress((base + offset) as u64),
+                    memory::PhysicalAddress::from_ptr(frame),
+                    level,
+                    vmm::PageLevel::Level1,
+                    &mut pmm_lock,
+                    flags
+                );
+            }
+        }
+
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+
+        true
+    }
+
+    /// Maps a zeroed stack with a guard below it, returning the base of the guard
+    fn map_stack(&mut self, size: usize) -> Option<usize> {
+        let base = self.vmm.alloc(STACK_GUARD + size, vmem::AllocStrategy::NextFit).ok()?;
+
+        if !self.map_zeroed(base + STACK_GUARD, size) {
+            self.vmm.free(base, STACK_GUARD + size);
+            return None;
+        }
+
+        Some(base)
+    }
+
+    /// Clears whatever an exited thread left on its stack before it's handed to another
+    ///
+    /// Goes through the loaded table, spare stacks are only reused by threads their own process spawns.
+    fn zero_stack(base: usize, size: usize) {
+        use crate::memory::{self, vmm};
+
+        for offset in (STACK_GUARD..STACK_GUARD + size).step_by(0x1000) {
+            let Ok(phys) = vmm::virt_to_phys(memory::VirtualAddress((base + offset) as u64)) else {
+                continue;
+            };
+
+            unsafe {
+                phys.as_ptr().write_bytes(0, 0x1000);
+            }
+        }
+    }
+
+    /// Keeps an exited thread's stack for reuse, and its ID until it's joined unless it was detached
+    fn release_thread(&mut self, thread: &Thread) {
+        self.threads.remove(&thread.thread_id);
+        self.spare_stacks.push((thread.stack, thread.stack_size));
+
+        match self.detached.remove(&thread.thread_id) {
+            true => self.thread_ids.free(thread.thread_id, 1),
+            false => {
+                self.exited_threads.insert(thread.thread_id);
+            },
+        }
+    }
+
+    /// Lets go of the ID of a thread that has exited, returning whether there was one to join
+    pub fn join_exited(&mut self, thread_id: usize) -> bool {
+        let exited = self.exited_threads.remove(&thread_id);
+
+        if exited {
+            self.thread_ids.free(thread_id, 1);
+        }
+
+        exited
+    }
+
+    /// Marks a thread as never to be joined, letting go of its ID now if it has already exited, or as soon as it
+    /// does. Returns whether there was such a thread.
+    pub fn detach(&mut self, thread_id: usize) -> bool {
+        if self.join_exited(thread_id) {
+            return true;
+        }
+
+        if !self.threads.contains(&thread_id) {
+            return false;
+        }
+
+        self.detached.insert(thread_id);
+        true
+    }
+}
+
+/// A thread of a process, its user state and what it's waiting on
+///
+/// Threads never block in the kernel, so traps run on the hart's interrupt stack rather than one of their own.
 #[derive(Clone, Copy)]
 #[repr(C)]
-pub struct TaskData {
+pub struct Thread {
     pub trap_frame: super::TrapFrame,
     pub task_id: usize,
-    pub task_table: crate::memory::vmm::Satp,
-    pub privilege: Privilege,
-    pub waiting_on: WaitSrc,
     pub thread_id: usize,
-    pub thread_manager: &'static vmem::Vmem<'static, 'static>,
-    pub vmm: &'static vmem::Vmem<'static, 'static>
+    pub waiting_on: WaitSrc,
+    pub sched: SchedInfo,
+    /// Base of the thread's stack mapping, including the guard page
+    pub stack: usize,
+    /// Size of the stack, not including the guard page
+    pub stack_size: usize,
 }
 
-impl TaskData {
+impl Thread {
+    pub fn key(&self) -> ThreadKey {
+        (self.task_id, self.thread_id)
+    }
+
     /// # Safety
-    /// Mostly safe, but will not return, handle initialization of everything before running this
+    /// Mostly safe, but will not return, handle initialization of everything before running this, including
+    /// loading the process' table
     #[naked]
     pub unsafe extern "C" fn load(&self) -> !{
         // a0 starts loaded with a pointer to self, that we will treat as a pointer to its trap frame
@@ -87,12 +549,6 @@ impl TaskData {
                 ld t0, 0(a0)
                 csrw sepc, t0
 
-                // Load satp
-                ld t0, 264(a0)
-                csrw satp, t0
-
-                sfence.vma
-
                 // Load registers
                 ld x1, 8(a0)
                 ld x2, 16(a0)
@@ -134,70 +590,416 @@ impl TaskData {
     }
 }
 
-pub struct TaskQueue {
-    pub queue: Vec<TaskData>,
-    pub cur_task_idx: usize,
+/// A hart's share of the scheduler
+pub struct Hart {
+    pub hart_id: usize,
+    /// Runnable threads by priority, not including the running one
+    run_queues: [VecDeque<ThreadKey>; PRIORITY_LEVELS],
+    /// The running thread, `None` while the hart is idle
+    current: Option<ThreadKey>,
+    /// Task whose table is in `satp`, if it isn't `kernel_table`
+    loaded: Option<usize>,
+    /// Table loaded while the hart has nothing to run
+    kernel_table: crate::memory::vmm::Satp,
+
+    /// Ticks the hart has taken
+    ticks: u64,
+    last_boost: u64,
+}
+
+impl Hart {
+    fn new(hart_id: usize) -> Self {
+        const EMPTY: VecDeque<ThreadKey> = VecDeque::new();
+
+        Self {
+            hart_id,
+            run_queues: [EMPTY; PRIORITY_LEVELS],
+            current: None,
+            loaded: None,
+            kernel_table: crate::memory::vmm::Satp::new(),
+            ticks: 0,
+            last_boost: 0,
+        }
+    }
+
+    fn queued(&self) -> usize {
+        self.run_queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+pub struct Scheduler {
+    /// Every process with threads left
+    processes: BTreeMap<usize, Process>,
+    /// Every thread, running, runnable or blocked
+    threads: BTreeMap<ThreadKey, Thread>,
+    /// Threads waiting on something
+    blocked: BTreeSet<ThreadKey>,
+    /// Every hart taking part, by the index they registered at
+    harts: Vec<Hart>,
+    /// Processes whose threads have all exited, kept until no hart has their table loaded
+    exited: Vec<Process>,
+    /// Exit codes of tasks that have ended, kept until they're waited on
+    zombies: Zombies,
+    /// Runnable threads added before any hart registered, queued once one does
+    unqueued: Vec<ThreadKey>,
+    /// Threads removed while running on another hart, which that hart takes out once it switches away from them
+    dying: BTreeSet<ThreadKey>,
 }
 
-impl TaskQueue {
+impl Scheduler {
     pub const fn new() -> Self {
         Self {
-            queue: Vec::new(),
-            cur_task_idx: 0,
+            processes: BTreeMap::new(),
+            threads: BTreeMap::new(),
+            blocked: BTreeSet::new(),
+            harts: Vec::new(),
+            exited: Vec::new(),
+            zombies: Zombies::new(),
+            unqueued: Vec::new(),
+            dying: BTreeSet::new(),
+        }
+    }
+
+    fn this_hart(&self) -> &Hart {
+        &self.harts[hart_index()]
+    }
+
+    fn this_hart_mut(&mut self) -> &mut Hart {
+        &mut self.harts[hart_index()]
+    }
+
+    /// The thread running on this hart
+    pub fn current(&self) -> Option<ThreadKey> {
+        self.this_hart().current
+    }
+
+    pub fn current_thread(&self) -> &Thread {
+        self.current().and_then(|key| self.threads.get(&key)).expect("No thread running")
+    }
+
+    pub fn current_thread_mut(&mut self) -> &mut Thread {
+        self.running_mut().expect("No thread running")
+    }
+
+    /// The process of the thread running on this hart
+    pub fn current_process(&self) -> &Process {
+        self.current().and_then(|(task_id, _)| self.processes.get(&task_id)).expect("No thread running")
+    }
+
+    pub fn current_process_mut(&mut self) -> &mut Process {
+        self.current().and_then(|(task_id, _)| self.processes.get_mut(&task_id)).expect("No thread running")
+    }
+
+    fn running_mut(&mut self) -> Option<&mut Thread> {
+        self.current().and_then(|key| self.threads.get_mut(&key))
+    }
+
+    /// Level of the thread running on a hart, past the lowest priority if it's idle
+    fn running_level(&self, hart: usize) -> usize {
+        self.harts[hart].current
+            .and_then(|key| self.threads.get(&key))
+            .map_or(PRIORITY_LEVELS, |thread| thread.sched.level as usize)
+    }
+
+    /// Queues a runnable thread on whichever hart suits it, asking that hart to reschedule if the thread should
+    /// run there now
+    fn enqueue(&mut self, key: ThreadKey) {
+        let Some(thread) = self.threads.get_mut(&key) else {
+            return;
+        };
+
+        let Some(last_index) = self.harts.len().checked_sub(1) else {
+            self.unqueued.push(key);
+            return;
+        };
+
+        let last = thread.sched.hart.min(last_index);
+        let level = thread.sched.level as usize;
+
+        // The hart it last ran on if that has nothing else to do, otherwise an idle hart with the fewest threads
+        // queued, then any hart with the fewest queued
+        let free = |hart: &Hart| hart.current.is_none() && hart.queued() == 0;
+        let target = match free(&self.harts[last]) {
+            true => last,
+            false => (0..self.harts.len())
+                .min_by_key(|index| (self.harts[*index].current.is_some(), self.harts[*index].queued()))
+                .unwrap_or(last),
+        };
+
+        thread.sched.hart = target;
+        self.harts[target].run_queues[level].push_back(key);
+
+        // This hart checks its own queues before going back to user mode
+        if target != hart_index() && level < self.running_level(target) {
+            send_reschedule(self.harts[target].hart_id);
         }
     }
 
-    pub fn advance(&mut self) {
-        let start = self.cur_task_idx;
-        let mut sie_set = false;
+    pub fn new_process(&mut self, process: Process, thread: Thread) {
+        self.processes.insert(process.task_id, process);
+        self.new_thread(thread);
+    }
 
-        loop {
-            self.cur_task_idx += 1;
-            if self.cur_task_idx == self.queue.len() {
-                self.cur_task_idx = 0;
-            }
+    /// Adds a thread made by its process' `new_thread`
+    pub fn new_thread(&mut self, thread: Thread) {
+        let key = thread.key();
+        let waiting = thread.waiting_on != WaitSrc::None;
 
-            if self.current_task().waiting_on == WaitSrc::None {
-                if sie_set {
-                    let mut sstatus = crate::arch::regs::Sstatus::new();
-                    sstatus.set_sie(false);
-                    sstatus.set_spp(false);
+        self.threads.insert(key, thread);
 
-                    unsafe {sstatus.set()}
-                }
+        match waiting {
+            true => {
+                self.blocked.insert(key);
+            },
+            false => self.enqueue(key),
+        }
+    }
 
-                break;
+    /// Takes a thread out of the scheduler entirely, returning it
+    ///
+    /// A thread running on another hart may be partway through a syscall there, so it's only marked dying and
+    /// `None` returned, that hart takes it out once it switches away. Removing the last thread of a task leaves its
+    /// exit code as a zombie, and frees the task once no hart is using its table.
+    pub fn remove(&mut self, key: ThreadKey) -> Option<Thread> {
+        let own = hart_index();
+
+        let elsewhere = self.harts.iter().enumerate().find(|(index, hart)| *index != own && hart.current == Some(key));
+
+        if let Some((_, hart)) = elsewhere {
+            send_reschedule(hart.hart_id);
+            self.dying.insert(key);
+
+            return None;
+        }
+
+        let thread = self.threads.remove(&key)?;
+
+        self.dying.remove(&key);
+        self.blocked.remove(&key);
+
+        if let Some(hart) = self.harts.get_mut(thread.sched.hart) {
+            hart.run_queues[thread.sched.level as usize].retain(|queued| *queued != key);
+        }
+
+        if let Some(hart) = self.harts.get_mut(own).filter(|hart| hart.current == Some(key)) {
+            hart.current = None;
+        }
+
+        self.wake_all(WaitSrc::Thread(key));
+
+        if let Some(process) = self.processes.get_mut(&thread.task_id) {
+            process.release_thread(&thread);
+
+            if process.threads.is_empty() {
+                let process = self.processes.remove(&thread.task_id).unwrap();
+
+                self.zombies.exit(process.task_id, process.exit_code);
+                self.exited.push(process);
+
+                self.wake_all(WaitSrc::Task(thread.task_id));
             }
+        }
 
-            if self.cur_task_idx == start {
-                let mut sstatus = crate::arch::regs::Sstatus::new();
-                sstatus.set_sie(true);
-                sstatus.set_spp(true);
-                unsafe {sstatus.set()}
+        Some(thread)
+    }
 
-                sie_set = true;
+    /// Switches this hart to another table, recording which task it belongs to
+    fn load_table(&mut self, task: Option<usize>, table: crate::memory::vmm::Satp) {
+        unsafe {
+            core::arch::asm!(
+                "csrw satp, {new_satp}",
+                new_satp = in(reg) table.0
+            );
+            core::arch::asm!("sfence.vma");
+        }
+
+        self.this_hart_mut().loaded = task;
+    }
+
+    /// Frees every exited process no hart still has the table of loaded
+    fn reap(&mut self) {
+        let (loaded, free): (Vec<_>, Vec<_>) = self.exited.drain(..)
+            .partition(|process| self.harts.iter().any(|hart| hart.loaded == Some(process.task_id)));
+
+        self.exited = loaded;
+
+        for process in free {
+            let task_id = process.task_id;
+            unsafe { crate::userspace::unload(process) };
+
+            if self.zombies.freed(task_id) {
+                crate::userspace::TASK_IDS.lock().free(task_id, 1);
             }
         }
     }
 
-    pub fn current_task(&self) -> &TaskData {
-        &self.queue[self.cur_task_idx]
+    pub fn threads(&self) -> impl Iterator<Item = &Thread> {
+        self.threads.values()
     }
 
-    pub fn current_task_mut(&mut self) -> &mut TaskData {
-        &mut self.queue[self.cur_task_idx]
+    /// Takes the exit code of a task that has ended, letting its ID be reused once the task is freed
+    pub fn reap_zombie(&mut self, task_id: usize) -> Option<usize> {
+        let (code, reusable) = self.zombies.reap(task_id)?;
+
+        // Otherwise `reap` lets go of it
+        if reusable {
+            crate::userspace::TASK_IDS.lock().free(task_id, 1);
+        }
+
+        Some(code)
+    }
+
+    pub fn process(&self, task_id: usize) -> Option<&Process> {
+        self.processes.get(&task_id)
+    }
+
+    pub fn process_mut(&mut self, task_id: usize) -> Option<&mut Process> {
+        self.processes.get_mut(&task_id)
+    }
+
+    pub fn find_thread_mut(&mut self, task_id: usize, thread_id: usize) -> Option<&mut Thread> {
+        self.threads.get_mut(&(task_id, thread_id))
     }
 
-    pub fn new_task(&mut self, task_data: TaskData) {
-        self.queue.push(task_data);
+    /// Makes a thread waiting on `src` runnable again, returning it so the result of the wait can be filled in
+    pub fn wake(&mut self, task_id: usize, thread_id: usize, src: WaitSrc) -> Option<&mut Thread> {
+        let key = (task_id, thread_id);
+        let thread = self.threads.get_mut(&key).filter(|thread| thread.waiting_on == src && src != WaitSrc::None)?;
+
+        thread.waiting_on = WaitSrc::None;
+
+        // The running thread is only queued once it's switched out, which sees it's no longer waiting
+        if self.blocked.remove(&key) {
+            self.enqueue(key);
+        }
+
+        self.threads.get_mut(&key)
     }
 
-    pub fn find_task(&self, id: usize) -> Option<&TaskData> {
-        self.queue.iter().find(|entry| entry.task_id == id)
+    /// Makes every thread waiting on `src` runnable again
+    pub fn wake_all(&mut self, src: WaitSrc) {
+        let waiting: Vec<_> = self.threads.values()
+            .filter(|thread| thread.waiting_on == src)
+            .map(Thread::key)
+            .collect();
+
+        for (task_id, thread_id) in waiting {
+            self.wake(task_id, thread_id, src);
+        }
     }
 
-    pub fn find_task_mut(&mut self, id: usize) -> Option<&mut TaskData> {
-        self.queue.iter_mut().find(|entry| entry.task_id == id)
+    /// Puts this hart's running thread back in its run queue, or with the blocked threads if it's waiting on
+    /// something
+    fn switch_out(&mut self) {
+        let Some(key) = self.this_hart_mut().current.take() else {
+            return;
+        };
+
+        if self.dying.contains(&key) {
+            self.remove(key);
+            return;
+        }
+
+        let Some(thread) = self.threads.get_mut(&key) else {
+            return;
+        };
+
+        match thread.waiting_on {
+            WaitSrc::None => {
+                let level = thread.sched.level as usize;
+                self.this_hart_mut().run_queues[level].push_back(key);
+            },
+            _ => {
+                // Giving up the CPU to wait is what interactive threads do, so they keep a high priority
+                thread.sched.level = thread.sched.base_level();
+                thread.sched.slice_used = 0;
+
+                self.blocked.insert(key);
+            },
+        }
+    }
+
+    /// Takes the highest priority runnable thread and makes it this hart's running one
+    ///
+    /// If this hart has nothing to run, it takes a thread from the busiest other hart.
+    pub fn pick(&mut self) -> Option<ThreadKey> {
+        let own = hart_index();
+
+        let from = match self.harts[own].queued() {
+            0 => (0..self.harts.len()).max_by_key(|index| self.harts[*index].queued())?,
+            _ => own,
+        };
+
+        let key = self.harts[from].run_queues.iter_mut().find_map(|queue| queue.pop_front())?;
+
+        if let Some(thread) = self.threads.get_mut(&key) {
+            thread.sched.hart = own;
+        }
+
+        self.harts[own].current = Some(key);
+
+        Some(key)
+    }
+
+    /// Whether this hart should switch threads, either because a runnable thread outranks the running one or
+    /// because the running one was removed
+    pub fn should_preempt(&self) -> bool {
+        let hart = self.this_hart();
+
+        let Some(current) = hart.current.and_then(|key| self.threads.get(&key)) else {
+            return true;
+        };
+
+        hart.run_queues[..current.sched.level as usize].iter().any(|queue| !queue.is_empty())
+    }
+
+    /// Charges this hart's running thread for a tick, returning whether it should be switched out
+    fn tick(&mut self) -> bool {
+        let hart = self.this_hart_mut();
+        hart.ticks += 1;
+
+        if hart.ticks - hart.last_boost >= BOOST_TICKS {
+            self.boost();
+        }
+
+        let Some(thread) = self.running_mut() else {
+            return true;
+        };
+
+        thread.sched.runtime += 1;
+        thread.sched.slice_used += 1;
+
+        if thread.sched.slice_used >= thread.sched.slice_len() {
+            thread.sched.slice_used = 0;
+            thread.sched.level = (thread.sched.level + 1).min(PRIORITY_LEVELS as u8 - 1);
+
+            return true;
+        }
+
+        self.should_preempt()
+    }
+
+    /// Lifts every thread on this hart back to its base priority
+    fn boost(&mut self) {
+        let own = hart_index();
+        let hart = &mut self.harts[own];
+        hart.last_boost = hart.ticks;
+
+        // Keep the order threads were waiting in, higher priority ones first
+        let mut queued: Vec<_> = hart.run_queues.iter_mut().flat_map(|queue| queue.drain(..)).collect();
+        queued.extend(hart.current);
+
+        for key in queued {
+            let Some(thread) = self.threads.get_mut(&key) else {
+                continue;
+            };
+
+            thread.sched.level = thread.sched.base_level();
+
+            if Some(key) != self.harts[own].current {
+                self.harts[own].run_queues[thread.sched.level as usize].push_back(key);
+            }
+        }
     }
 }
 
@@ -205,5 +1007,13 @@ impl TaskQueue {
 pub enum WaitSrc {
     None,
     CharIn,
-    Breakpoint
+    Breakpoint,
+    /// Waiting on a disk request to complete
+    Disk,
+    /// Waiting on the entropy pool to be seeded
+    Entropy,
+    /// Waiting on a thread to exit
+    Thread(ThreadKey),
+    /// Waiting on every thread of a task to exit
+    Task(usize),
 }
\ No newline at end of file
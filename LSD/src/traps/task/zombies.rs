@@ -0,0 +1,90 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+
+/// Tasks that have ended, with the exit codes nothing has waited for yet
+///
+/// A task's ID stays taken until it has both been freed and had its exit code collected, so waiting on an ID never
+/// picks up the code of a later task given the same one.
+pub struct Zombies {
+    /// Exit codes not collected yet
+    codes: BTreeMap<usize, usize>,
+    /// Tasks that have ended but aren't freed yet
+    unfreed: BTreeSet<usize>,
+}
+
+impl Zombies {
+    pub const fn new() -> Self {
+        Self {
+            codes: BTreeMap::new(),
+            unfreed: BTreeSet::new(),
+        }
+    }
+
+    /// Records that `task_id` ended with `code`
+    pub fn exit(&mut self, task_id: usize, code: usize) {
+        self.codes.insert(task_id, code);
+        self.unfreed.insert(task_id);
+    }
+
+    /// Records that `task_id` was freed, returning whether its ID can be reused
+    pub fn freed(&mut self, task_id: usize) -> bool {
+        self.unfreed.remove(&task_id);
+
+        !self.codes.contains_key(&task_id)
+    }
+
+    /// Takes the exit code of `task_id`, along with whether its ID can be reused
+    pub fn reap(&mut self, task_id: usize) -> Option<(usize, bool)> {
+        let code = self.codes.remove(&task_id)?;
+
+        Some((code, !self.unfreed.contains(&task_id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_after_exit_returns_the_code() {
+        let mut zombies = Zombies::new();
+        zombies.exit(3, 42);
+        zombies.freed(3);
+
+        assert_eq!(zombies.reap(3), Some((42, true)));
+    }
+
+    #[test]
+    fn codes_are_only_collected_once() {
+        let mut zombies = Zombies::new();
+        zombies.exit(3, 42);
+
+        assert_eq!(zombies.reap(3).map(|(code, _)| code), Some(42));
+        assert_eq!(zombies.reap(3), None);
+    }
+
+    #[test]
+    fn unknown_tasks_have_no_code() {
+        let mut zombies = Zombies::new();
+        zombies.exit(3, 42);
+
+        assert_eq!(zombies.reap(4), None);
+    }
+
+    #[test]
+    fn id_is_kept_until_reaped() {
+        let mut zombies = Zombies::new();
+        zombies.exit(3, 42);
+
+        assert!(!zombies.freed(3));
+        assert_eq!(zombies.reap(3), Some((42, true)));
+    }
+
+    #[test]
+    fn id_is_kept_until_freed() {
+        let mut zombies = Zombies::new();
+        zombies.exit(3, 42);
+
+        assert_eq!(zombies.reap(3), Some((42, false)));
+        assert!(zombies.freed(3));
+    }
+}
@@ -47,18 +47,25 @@ impl fmt::Write for Uart16550 {
 }
 
 pub fn uart_handler(_: usize) {
-    let mut list = crate::arch::syscalls::INPUT_AWAIT_LIST.lock();
     let input = UART.lock().data_register.read();
 
+    deliver_char(input);
+}
+
+/// Hands a character of console input to every task waiting on one
+pub fn deliver_char(input: u8) {
+    let mut list = crate::arch::syscalls::INPUT_AWAIT_LIST.lock();
+
     for entry_id in list.iter() {
         use crate::traps::task;
-        let mut lock = task::CURRENT_USER_TASK.write();
+        let mut lock = task::THREADS.lock();
 
-        let task = lock.find_task_mut(*entry_id).unwrap();
+        let waiting = lock.threads()
+            .find(|thread| thread.task_id == *entry_id && thread.waiting_on == task::WaitSrc::CharIn)
+            .map(|thread| thread.thread_id);
 
-        if task.waiting_on == task::WaitSrc::CharIn {
-            task.trap_frame.a0 = input as usize;
-            task.waiting_on = task::WaitSrc::None;
+        if let Some(thread) = waiting.and_then(|thread_id| lock.wake(*entry_id, thread_id, task::WaitSrc::CharIn)) {
+            thread.trap_frame.a0 = input as usize;
         }
     }
 
@@ -78,4 +85,6 @@ macro_rules! print {
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     UART.lock().write_fmt(args).unwrap();
+
+    crate::drivers::virtio::console::log(args);
 }
\ No newline at end of file
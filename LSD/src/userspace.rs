@@ -1,7 +1,11 @@
+mod validate;
+
 use spin::Mutex;
 
 use crate::println;
 
+pub use validate::LoadError;
+
 pub static TASK_IDS: Mutex<vmem::Vmem> = Mutex::new(
     vmem::Vmem::new(
         alloc::borrow::Cow::Borrowed("TaskIDs"), 
@@ -14,78 +18,127 @@ pub fn init_task_ids() {
     TASK_IDS.lock().add(0, usize::MAX).unwrap();
 }
 
+/// Runs threads on this hart from now on, waiting for one to be queued if there are none yet
+///
+/// The caller has to have set `sstatus` up so `sret` returns to user mode.
 pub fn start_tasks() -> ! {
-    let lock = crate::traps::task::CURRENT_USER_TASK.read();
-    let task = *lock.current_task();
+    // Interrupts are only taken from user mode from here on, `next_thread` handles them while idle
+    let mut sstatus = crate::arch::regs::Sstatus::new();
+    sstatus.set_sie(false);
+    unsafe {
+        sstatus.set();
+    }
 
-    core::mem::drop(lock);
+    crate::traps::task::save_kernel_table();
+
+    let task = crate::traps::task::next_thread();
 
     unsafe {
-        println!("Jumping to userspace");
+        println!("Hart 0x{:x} jumping to userspace", crate::HART_ID.load(core::sync::atomic::Ordering::Relaxed));
         task.load();
     }
 }
 
-pub fn load(bytes: &[u8]) -> usize {
+/// Loads an ELF executable into a new task, returning the task's ID
+///
+/// Everything is checked before any memory is touched, so a bad file leaves nothing behind. If memory runs out
+/// anyway, whatever was set up is freed again.
+pub fn load(bytes: &[u8]) -> Result<usize, LoadError> {
+    use alloc::collections::BTreeMap;
     use crate::memory::{pmm, vmm, self};
+    use crate::traps::{self, task};
+
+    let hhdm = memory::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
+    let level = vmm::PageLevel::from_usize(vmm::LEVELS.load(core::sync::atomic::Ordering::Relaxed) as usize);
+
+    // Everything the root table's lower half entries cover
+    let user_end = vmm::PageSize::from_level(level) as u64 * 256;
+    let validate::Program { entry: entry_point, segments } = validate::validate(bytes, user_end)?;
+
+    // Segments sharing a page count it twice, so this only overestimates
+    let stack_pages = (task::USER_STACK_SIZE / 0x1000) as u64;
+    let needed = segments.iter().fold(1 + stack_pages + vmm::tables_needed(stack_pages, level), |needed, (_, segment)| {
+        let pages = ((segment.p_vaddr + segment.p_memsz).next_multiple_of(0x1000) - (segment.p_vaddr & !0xfff)) / 0x1000;
+
+        needed + pages + vmm::tables_needed(pages, level)
+    }) + vmm::upper_half_tables() as u64;
+
+    // `claim` can't hand out the last frame
+    if needed >= pmm::REGION_LIST.lock().free_frames() as u64 {
+        return Err(LoadError::OutOfMemory);
+    }
 
-    let elfbytes = elf::ElfBytes::<elf::endian::LittleEndian>::minimal_parse(bytes).unwrap();
-    let table = elfbytes.segments().unwrap();
+    let task_id = TASK_IDS.lock().alloc(0x1, vmem::AllocStrategy::NextFit).map_err(|_| LoadError::NoTaskIds)?;
 
     let new_table = vmm::new_with_upperhalf();
-    let level = vmm::PageLevel::from_usize(vmm::LEVELS.load(core::sync::atomic::Ordering::Relaxed) as usize);
+    let mut pmm_lock = pmm::REGION_LIST.lock();
 
-    for entry in table {
-        // If type is LOAD, load it into memory
-        if entry.p_type == 0x1 {
-            let mut frames = entry.p_memsz / 4096;
-            if (entry.p_memsz % 4096) != 0 {
-                frames += 1;
-            }
+    // Segments that don't start or end on a page boundary can share a page, which gets both sets of permissions
+    let mut pages: BTreeMap<u64, (*mut u8, vmm::PageFlags)> = BTreeMap::new();
 
-            let current_entry = pmm::REGION_LIST.lock().claim_continuous(frames as usize).unwrap();
+    for (_, segment) in &segments {
+        let flags = Flags::from_bits_truncate(segment.p_flags).to_pageflags() | vmm::PageFlags::USER;
+        let start = segment.p_vaddr & !0xfff;
+        let end = (segment.p_vaddr + segment.p_memsz).next_multiple_of(0x1000);
 
-            if entry.p_filesz == 0 {
-                for i in 0..entry.p_memsz as usize {
+        for page in (start..end).step_by(0x1000) {
+            if !pages.contains_key(&page) {
+                // Other harts may have taken frames since they were counted
+                let Some(frame) = pmm_lock.try_claim() else {
                     unsafe {
-                        *current_entry.add(i) = 0;
+                        for (frame, _) in pages.into_values() {
+                            pmm_lock.pull(frame);
+                        }
+
+                        vmm::free_address_space(new_table, level, &mut pmm_lock);
                     }
-                }
-            }
 
-            for i in 0..entry.p_filesz as usize {
-                unsafe {
-                    *current_entry.add(i) = bytes[i + entry.p_offset as usize];
-                }
+                    TASK_IDS.lock().free(task_id, 1);
+                    return Err(LoadError::OutOfMemory);
+                };
+
+                // Whatever the file doesn't fill in, the BSS included, has to read as zero
+                unsafe { frame.write_bytes(0, 0x1000) };
+
+                pages.insert(page, (frame, vmm::PageFlags::empty()));
             }
 
-            let flags = Flags::from_bits_retain(entry.p_flags);
-            let flags = flags.to_pageflags();
-            let flags = flags | vmm::PageFlags::USER;
-
-            let base_phys = (current_entry as u64) - memory::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
-            let base_virt = entry.p_vaddr;
-
-            for offset in (0..entry.p_memsz).step_by(4096) {
-                let phys = memory::PhysicalAddress(base_phys + offset);
-                let virt = memory::VirtualAddress(base_virt + offset);
-
-                unsafe {
-                    vmm::map(
-                        new_table, 
-                        virt, 
-                        phys, 
-                        level, 
-                        vmm::PageLevel::Level1, 
-                        &mut pmm::REGION_LIST.lock(), 
-                        flags
-                    );
-                }
+            pages.get_mut(&page).unwrap().1 |= flags;
+        }
+
+        // The frames aren't contiguous, so the file contents go in a page at a time
+        let mut copied = 0;
+        while copied < segment.p_filesz {
+            let vaddr = segment.p_vaddr + copied;
+            let page_offset = vaddr & 0xfff;
+            let len = (0x1000 - page_offset).min(segment.p_filesz - copied);
+
+            let (frame, _) = pages[&(vaddr & !0xfff)];
+            let source = &bytes[(segment.p_offset + copied) as usize..][..len as usize];
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(source.as_ptr(), frame.add(page_offset as usize), len as usize);
             }
+
+            copied += len;
         }
     }
 
-    let task_id = TASK_IDS.lock().alloc(0x1, vmem::AllocStrategy::NextFit).unwrap();
+    for (page, (frame, flags)) in pages {
+        unsafe {
+            vmm::map(
+                new_table,
+                memory::VirtualAddress(page),
+                memory::PhysicalAddress(frame as u64 - hhdm),
+                level,
+                vmm::PageLevel::Level1,
+                &mut pmm_lock,
+                flags
+            );
+        }
+    }
+
+    core::mem::drop(pmm_lock);
 
     let task_vmm = vmem::Vmem::new(
         alloc::borrow::Cow::Borrowed("task_vmm"), 
@@ -93,7 +146,8 @@ pub fn load(bytes: &[u8]) -> usize {
         None
     );
 
-    for i in 1..=256 {
+    // Only the lower half is the task's to hand out
+    for i in 1..256 {
         unsafe {
             let entry = &(*new_table).0[i];
             let mut vaddr = memory::VirtualAddress(0);
@@ -108,73 +162,46 @@ pub fn load(bytes: &[u8]) -> usize {
         }
     }
 
-    let stack = pmm::REGION_LIST.lock().claim_aligned(0x800, vmm::PageSize::Medium).unwrap();
-    let stack_paddr = (stack as u64) - memory::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
-    let stack_vaddr = task_vmm.alloc(0x80_0000, vmem::AllocStrategy::NextFit).unwrap() as u64;
-
-    println!("Physical address of new stack: 0x{:x}", stack_paddr);
-
-    for i in (0..0x80_0000).step_by(vmm::PageSize::Medium as usize) {
-        let flags = vmm::PageFlags::READ | vmm::PageFlags::WRITE | vmm::PageFlags::USER;
-
-        let virt = memory::VirtualAddress(stack_vaddr + i);
-        let phys = memory::PhysicalAddress(stack_paddr + i);
-        unsafe {
-            println!("Mapping 0x{:x} to 0x{:x}", virt.0, phys.0);
-            vmm::map(
-                new_table, 
-                virt, 
-                phys, 
-                level, 
-                vmm::PageLevel::Level2, 
-                &mut pmm::REGION_LIST.lock(), 
-                flags
-            )
-        }
-    }
-
-    use crate::traps::{self, task};
-
-    let phys = (new_table as u64) - memory::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
+    let phys = (new_table as u64) - hhdm;
 
     let mut task_table = vmm::Satp::new();
     task_table.set_asid(0);
     task_table.set_mode(vmm::PageType::from_levels(level) as u64);
     task_table.set_ppn(phys >> 12);
-    
-    let boxed_vmm = alloc::boxed::Box::new(task_vmm);
-    let leaked_vmm = alloc::boxed::Box::leak(boxed_vmm);
 
-    let task_tm = vmem::Vmem::new(
-        alloc::borrow::Cow::Borrowed("task_thread_manager"), 
-        1, 
-        None
-    );
+    let mut process = task::Process::new(task_id, task_table, task::Privilege::User, task_vmm);
+    let Some(mut thread) = process.new_thread(traps::TrapFrame::default(), 0, task::USER_STACK_SIZE) else {
+        unsafe { vmm::free_address_space(new_table, level, &mut pmm::REGION_LIST.lock()) };
+        TASK_IDS.lock().free(task_id, 1);
 
-    task_tm.add(0, usize::MAX).unwrap();
+        return Err(LoadError::NoStack);
+    };
 
-    let boxed_tm = alloc::boxed::Box::new(task_tm);
-    let leaked_tm = alloc::boxed::Box::leak(boxed_tm);
+    println!("Loading program with stack at {:?}", thread.trap_frame.sp());
 
-    let mut task_data = task::TaskData {
-        trap_frame: traps::TrapFrame::default(),
-        task_id,
-        task_table,
-        privilege: task::Privilege::User,
-        waiting_on: task::WaitSrc::None,
-        thread_id: leaked_tm.alloc(1, vmem::AllocStrategy::NextFit).unwrap(),
-        thread_manager: leaked_tm,
-        vmm: leaked_vmm
-    };
+    thread.trap_frame.sepc = entry_point as usize;
+    thread.trap_frame.a0 = task_id;
+
+    task::new_process(process, thread);
+    Ok(task_id)
+}
+
+/// Releases everything `load` set up for a process, once its last thread is gone
+///
+/// Disk requests its threads left behind still complete into their bounce buffers, but finding no thread to wake
+/// they never copy into the freed frames.
+///
+/// # Safety
+/// No hart can have the process' table loaded, and the scheduler's lock has to be held
+pub unsafe fn unload(process: crate::traps::task::Process) {
+    use crate::memory::{pmm, vmm};
 
-    task_data.trap_frame.sp = stack_vaddr as usize;
-    println!("Loading program with stack at {:?}", task_data.trap_frame.sp());
+    let level = vmm::PageLevel::from_usize(vmm::LEVELS.load(core::sync::atomic::Ordering::Relaxed) as usize);
 
-    task_data.trap_frame.sepc = elfbytes.ehdr.e_entry as usize;
-    task_data.trap_frame.a0 = task_id;
+    // The ID stays taken until the task's zombie is reaped
+    vmm::free_address_space(process.table_ptr(), level, &mut pmm::REGION_LIST.lock());
 
-    task::new_task(task_data);
-    task_id
+    println!("Freed task 0x{:x}", process.task_id);
 }
 
 bitflags::bitflags! {
@@ -194,8 +221,9 @@ impl Flags {
         if self.contains(Flags::EXECUTE) {
             flags |= PageFlags::EXECUTE;
         }
+        // Write-only pages are a reserved encoding, so writable pages are readable too
         if self.contains(Flags::WRITE) {
-            flags |= PageFlags::WRITE;
+            flags |= PageFlags::WRITE | PageFlags::READ;
         }
         if self.contains(Flags::READ) {
             flags |= PageFlags::READ;
@@ -0,0 +1,257 @@
+use alloc::vec::Vec;
+
+use elf::{abi, endian::LittleEndian, segment::ProgramHeader, ElfBytes};
+
+/// Why a program couldn't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    /// The ELF header or program header table couldn't be parsed
+    Parse(elf::ParseError),
+    /// Not a 64-bit ELF
+    WrongClass,
+    /// Built for something other than RISC-V
+    WrongMachine(u16),
+    /// Not an executable, nothing gets relocated so shared objects can't be loaded
+    WrongType(u16),
+    /// There's nothing to load
+    NoSegments,
+    /// The segment at this index in the program header table runs past the end of the file, is smaller in memory
+    /// than in the file, or can't be accessed at all
+    BadSegment(usize),
+    /// The segment's address and file offset don't line up modulo its alignment
+    Misaligned(usize),
+    /// Two segments cover the same memory
+    Overlap(usize, usize),
+    /// The segment reaches into the kernel's half of the address space
+    UpperHalf(usize),
+    /// The entry point isn't in an executable segment
+    BadEntry(u64),
+    /// Every task ID is taken
+    NoTaskIds,
+    /// There are too few free frames for the segments, the tables mapping them, and the main thread's stack
+    OutOfMemory,
+    /// The main thread's stack couldn't be set up
+    NoStack,
+}
+
+/// What `validate` found to load
+pub struct Program {
+    pub entry: u64,
+    /// Every loadable segment, along with its index in the program header table
+    pub segments: Vec<(usize, ProgramHeader)>,
+}
+
+/// Checks an ELF executable is something we can load into a lower half ending at `user_end`, without touching any
+/// memory
+pub fn validate(bytes: &[u8], user_end: u64) -> Result<Program, LoadError> {
+    let elfbytes = ElfBytes::<LittleEndian>::minimal_parse(bytes).map_err(LoadError::Parse)?;
+    let header = elfbytes.ehdr;
+
+    if header.class != elf::file::Class::ELF64 {
+        return Err(LoadError::WrongClass);
+    } else if header.e_machine != abi::EM_RISCV {
+        return Err(LoadError::WrongMachine(header.e_machine));
+    } else if header.e_type != abi::ET_EXEC {
+        return Err(LoadError::WrongType(header.e_type));
+    }
+
+    let segments: Vec<(usize, ProgramHeader)> = elfbytes.segments().ok_or(LoadError::NoSegments)?
+        .iter()
+        .enumerate()
+        .filter(|(_, segment)| segment.p_type == abi::PT_LOAD && segment.p_memsz != 0)
+        .collect();
+
+    if segments.is_empty() {
+        return Err(LoadError::NoSegments);
+    }
+
+    for (index, segment) in &segments {
+        let in_file = segment.p_offset.checked_add(segment.p_filesz).is_some_and(|end| end <= bytes.len() as u64);
+        let accessible = segment.p_flags & (abi::PF_R | abi::PF_W | abi::PF_X) != 0;
+
+        if segment.p_filesz > segment.p_memsz || !in_file || !accessible {
+            return Err(LoadError::BadSegment(*index));
+        } else if segment.p_align > 1 && segment.p_vaddr % segment.p_align != segment.p_offset % segment.p_align {
+            return Err(LoadError::Misaligned(*index));
+        } else if !segment.p_vaddr.checked_add(segment.p_memsz).is_some_and(|end| end <= user_end) {
+            return Err(LoadError::UpperHalf(*index));
+        }
+    }
+
+    let mut by_address: Vec<_> = segments.iter().collect();
+    by_address.sort_by_key(|(_, segment)| segment.p_vaddr);
+
+    for pair in by_address.windows(2) {
+        let ((first, lower), (second, upper)) = (pair[0], pair[1]);
+
+        if lower.p_vaddr + lower.p_memsz > upper.p_vaddr {
+            return Err(LoadError::Overlap(*first, *second));
+        }
+    }
+
+    let entry_point = header.e_entry;
+    let executable = segments.iter().any(|(_, segment)| {
+        segment.p_flags & abi::PF_X != 0 && (segment.p_vaddr..segment.p_vaddr + segment.p_memsz).contains(&entry_point)
+    });
+
+    if !executable {
+        return Err(LoadError::BadEntry(entry_point));
+    }
+
+    Ok(Program { entry: entry_point, segments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_END: u64 = 0x40_0000_0000;
+
+    struct Segment {
+        kind: u32,
+        flags: u32,
+        offset: u64,
+        vaddr: u64,
+        filesz: u64,
+        memsz: u64,
+        align: u64,
+    }
+
+    /// A readable and executable segment of `memsz` bytes at `vaddr`, all but the first page of it BSS
+    fn text(vaddr: u64, memsz: u64) -> Segment {
+        Segment {
+            kind: abi::PT_LOAD,
+            flags: abi::PF_R | abi::PF_X,
+            offset: 0x1000,
+            vaddr,
+            filesz: memsz.min(0x1000),
+            memsz,
+            align: 0x1000,
+        }
+    }
+
+    /// A little endian ELF64 header followed by the program header table, padded out to `len` bytes
+    fn elf(kind: u16, machine: u16, entry: u64, segments: &[Segment], len: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&machine.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&entry.to_le_bytes());
+        // Program headers straight after this header, and no section headers
+        bytes.extend_from_slice(&64u64.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&64u16.to_le_bytes());
+        bytes.extend_from_slice(&56u16.to_le_bytes());
+        bytes.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&64u16.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+
+        for segment in segments {
+            bytes.extend_from_slice(&segment.kind.to_le_bytes());
+            bytes.extend_from_slice(&segment.flags.to_le_bytes());
+            bytes.extend_from_slice(&segment.offset.to_le_bytes());
+            bytes.extend_from_slice(&segment.vaddr.to_le_bytes());
+            bytes.extend_from_slice(&segment.vaddr.to_le_bytes());
+            bytes.extend_from_slice(&segment.filesz.to_le_bytes());
+            bytes.extend_from_slice(&segment.memsz.to_le_bytes());
+            bytes.extend_from_slice(&segment.align.to_le_bytes());
+        }
+
+        bytes.resize(len.max(bytes.len()), 0);
+        bytes
+    }
+
+    fn executable(entry: u64, segments: &[Segment]) -> Vec<u8> {
+        elf(abi::ET_EXEC, abi::EM_RISCV, entry, segments, 0x2000)
+    }
+
+    #[test]
+    fn accepts_a_valid_executable() {
+        let data = Segment { flags: abi::PF_R | abi::PF_W, ..text(0x20000, 0x3000) };
+        let note = Segment { kind: abi::PT_NOTE, ..text(0, 0x10) };
+        let empty = Segment { memsz: 0, filesz: 0, ..text(0x30000, 0) };
+
+        let program = validate(&executable(0x10010, &[text(0x10000, 0x1000), note, data, empty]), USER_END).unwrap();
+
+        assert_eq!(program.entry, 0x10010);
+        assert_eq!(program.segments.iter().map(|(index, _)| *index).collect::<Vec<_>>(), [0, 2]);
+    }
+
+    #[test]
+    fn rejects_other_machines_and_types() {
+        let segments = [text(0x10000, 0x1000)];
+
+        let bytes = elf(abi::ET_EXEC, abi::EM_X86_64, 0x10000, &segments, 0x2000);
+        assert!(matches!(validate(&bytes, USER_END), Err(LoadError::WrongMachine(abi::EM_X86_64))));
+
+        let bytes = elf(abi::ET_DYN, abi::EM_RISCV, 0x10000, &segments, 0x2000);
+        assert!(matches!(validate(&bytes, USER_END), Err(LoadError::WrongType(abi::ET_DYN))));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(matches!(validate(&[0; 16], USER_END), Err(LoadError::Parse(_))));
+    }
+
+    #[test]
+    fn needs_something_to_load() {
+        assert!(matches!(validate(&executable(0, &[]), USER_END), Err(LoadError::NoSegments)));
+
+        let note = Segment { kind: abi::PT_NOTE, ..text(0x10000, 0x1000) };
+        assert!(matches!(validate(&executable(0x10000, &[note]), USER_END), Err(LoadError::NoSegments)));
+    }
+
+    #[test]
+    fn rejects_bad_segments() {
+        let past_eof = Segment { offset: 0x1800, ..text(0x10000, 0x1000) };
+        assert!(matches!(validate(&executable(0x10000, &[past_eof]), USER_END), Err(LoadError::BadSegment(0))));
+
+        let offset_overflows = Segment { offset: u64::MAX, ..text(0x10000, 0x1000) };
+        assert!(matches!(validate(&executable(0x10000, &[offset_overflows]), USER_END), Err(LoadError::BadSegment(0))));
+
+        let bigger_in_file = Segment { filesz: 0x800, memsz: 0x400, ..text(0x10000, 0x1000) };
+        assert!(matches!(validate(&executable(0x10000, &[bigger_in_file]), USER_END), Err(LoadError::BadSegment(0))));
+
+        let inaccessible = Segment { flags: 0, ..text(0x20000, 0x1000) };
+        let bytes = executable(0x10000, &[text(0x10000, 0x1000), inaccessible]);
+        assert!(matches!(validate(&bytes, USER_END), Err(LoadError::BadSegment(1))));
+    }
+
+    #[test]
+    fn rejects_misaligned_segments() {
+        let misaligned = Segment { vaddr: 0x10800, ..text(0x10000, 0x1000) };
+
+        assert!(matches!(validate(&executable(0x10800, &[misaligned]), USER_END), Err(LoadError::Misaligned(0))));
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let bytes = executable(0x10000, &[text(0x11000, 0x1000), text(0x10000, 0x2000)]);
+
+        assert!(matches!(validate(&bytes, USER_END), Err(LoadError::Overlap(1, 0))));
+    }
+
+    #[test]
+    fn stays_in_the_lower_half() {
+        let reaching_up = text(USER_END - 0x1000, 0x2000);
+        let bytes = executable(USER_END - 0x1000, &[reaching_up]);
+        assert!(matches!(validate(&bytes, USER_END), Err(LoadError::UpperHalf(0))));
+
+        let wrapping = Segment { memsz: u64::MAX, ..text(0x10000, 0x1000) };
+        assert!(matches!(validate(&executable(0x10000, &[wrapping]), USER_END), Err(LoadError::UpperHalf(0))));
+    }
+
+    #[test]
+    fn entry_has_to_be_executable() {
+        let data = Segment { flags: abi::PF_R | abi::PF_W, ..text(0x20000, 0x1000) };
+        let bytes = executable(0x20000, &[text(0x10000, 0x1000), data]);
+        assert!(matches!(validate(&bytes, USER_END), Err(LoadError::BadEntry(0x20000))));
+
+        let bytes = executable(0x11000, &[text(0x10000, 0x1000)]);
+        assert!(matches!(validate(&bytes, USER_END), Err(LoadError::BadEntry(0x11000))));
+    }
+}
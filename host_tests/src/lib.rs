@@ -0,0 +1,34 @@
+//! Runs the unit tests of the kernel code that doesn't touch hardware on the host, `cargo test` from here
+//!
+//! The kernel only builds for RISC-V, so the files with that code are pulled in as they are and whatever they use
+//! through `crate::` is stood in for below.
+
+// Only the parts of these files with tests get used
+#![allow(dead_code)]
+
+extern crate alloc;
+
+/// The parts of the kernel's `memory` module the files under test use
+mod memory {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PhysicalAddress(pub u64);
+
+    pub mod vmm {
+        pub const PAGE_SIZE: usize = 0x1000;
+    }
+}
+
+#[path = "../../LSD/src/drivers/pci/nvme/identify.rs"]
+mod identify;
+
+#[path = "../../LSD/src/drivers/pci/nvme/prp.rs"]
+mod prp;
+
+#[path = "../../LSD/src/drivers/virtio/event_idx.rs"]
+mod event_idx;
+
+#[path = "../../LSD/src/userspace/validate.rs"]
+mod validate;
+
+#[path = "../../LSD/src/traps/task/zombies.rs"]
+mod zombies;
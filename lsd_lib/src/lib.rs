@@ -1,6 +1,8 @@
 #![no_std]
 #![feature(core_intrinsics)]
 
+pub mod thread;
+
 struct RootPrinter;
 
 impl core::fmt::Write for RootPrinter {
@@ -40,37 +42,106 @@ pub fn in_char() -> char {
     unsafe {char::from_u32_unchecked(out)}
 }
 
-/// Spawns a new thread, can damage memory if not handled well
-/// Will also return task ID in `a0`, and thread ID in `a1`
-/// # Safety
-/// Always call `drop_thread` when done with the thread
-pub unsafe fn spawn_thread_raw() -> (usize, usize) {
-    let task_id: usize;
-    let thread_id: usize;
+/// Reads `count` blocks starting at `lba` from the disk into `buffer`, blocking until the read completes
+/// Returns the kernel's error code on failure
+pub fn read_blocks(lba: u64, count: u16, buffer: &mut [u8]) -> Result<(), usize> {
+    let status: usize;
 
     unsafe {
         core::arch::asm!(
             "ecall",
-            in("a0") 1,
-            in("a1") 2,
-            lateout("a0") task_id,
-            lateout("a1") thread_id,
+            in("a0") 0,
+            in("a1") 3,
+            in("a2") lba,
+            in("a3") count as usize,
+            in("a4") buffer.as_mut_ptr(),
+            in("a5") buffer.len(),
+            lateout("a0") status,
         );
     }
 
-    (task_id, thread_id)
+    match status {
+        0 => Ok(()),
+        code => Err(code),
+    }
 }
 
-/// Safely spawns a thread, ensuring it is dropped at the end of it's lifetime
-pub fn spawn_thread(f: fn()) {
-    let ids = unsafe {spawn_thread_raw()};
+/// Writes `count` blocks from `buffer` to the disk starting at `lba`, blocking until the write completes
+/// Returns the kernel's error code on failure
+pub fn write_blocks(lba: u64, count: u16, buffer: &[u8]) -> Result<(), usize> {
+    let status: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") 0,
+            in("a1") 4,
+            in("a2") lba,
+            in("a3") count as usize,
+            in("a4") buffer.as_ptr(),
+            in("a5") buffer.len(),
+            lateout("a0") status,
+        );
+    }
 
-    if ids.1 != 0 {
-        f();
-        unsafe {drop_thread()};
+    match status {
+        0 => Ok(()),
+        code => Err(code),
     }
 }
 
+/// Fills `buffer` with random bytes from the kernel's entropy pool, blocking until the pool has been seeded
+/// Returns the kernel's error code on failure
+pub fn getrandom(buffer: &mut [u8]) -> Result<(), usize> {
+    let status: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") 0,
+            in("a1") 5,
+            in("a2") buffer.as_mut_ptr(),
+            in("a3") buffer.len(),
+            lateout("a0") status,
+        );
+    }
+
+    match status {
+        0 => Ok(()),
+        code => Err(code),
+    }
+}
+
+/// Spawns a thread running `f` on a stack of its own, the thread is dropped once `f` returns
+pub fn spawn_thread(f: fn()) {
+    unsafe {
+        core::arch::asm!(
+            "
+                ecall
+                // Only the new thread gets a thread ID back, its stack is empty so it can't return from here
+                // It's handed its own thread ID in a1 as well, to detach itself with
+                beqz a1, 2f
+                mv a0, {f}
+                jr {entry}
+            2:
+            ",
+            f = in(reg) f as usize,
+            entry = in(reg) thread_entry as usize,
+            inlateout("a0") 1usize => _,
+            inlateout("a1") 2usize => _,
+        );
+    }
+}
+
+extern "C" fn thread_entry(f: fn(), thread_id: usize) -> ! {
+    // Nothing is going to join it
+    detach(thread_id);
+
+    f();
+
+    unsafe {drop_thread()}
+}
+
 /// Causes a thread to stop executing, should only be called at the end of  athread
 /// # Safety
 /// Only safe when ran at the end of a thread after everything it uses has been dropped
@@ -83,6 +154,70 @@ pub unsafe fn drop_thread() -> ! {
     );
 }
 
+/// Ends every thread of the current task, `code` is handed to whoever waits on it
+pub fn exit(code: usize) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") 1,
+            in("a1") 4,
+            in("a2") code,
+            options(noreturn)
+        );
+    }
+}
+
+/// Blocks until the task `task_id` has ended, returning its exit code
+/// Each task can only be waited on once, returns the kernel's error code if there is no such task
+/// The exit code is kept until the task is waited on, even if it ended before anything waited
+pub fn wait(task_id: usize) -> Result<usize, usize> {
+    let status: usize;
+    let code: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") 1,
+            in("a1") 8,
+            in("a2") task_id,
+            lateout("a0") status,
+            lateout("a1") code,
+        );
+    }
+
+    match status {
+        0 => Ok(code),
+        status => Err(status),
+    }
+}
+
+/// Blocks until the thread `thread_id` of the current task has exited
+pub fn join(thread_id: usize) {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") 1,
+            in("a1") 7,
+            in("a2") thread_id,
+            lateout("a0") _,
+            lateout("a1") _,
+        );
+    }
+}
+
+/// Lets the thread `thread_id` of the current task go without joining it, its ID is freed as soon as it exits
+pub fn detach(thread_id: usize) {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") 1,
+            in("a1") 9,
+            in("a2") thread_id,
+            lateout("a0") _,
+        );
+    }
+}
+
 /// Forfeits control to the next task immediately rather waiting on an IO call, or a timed switch
 pub fn forfeit() {
     unsafe {
@@ -94,6 +229,18 @@ pub fn forfeit() {
     }
 }
 
+/// Sets how nice the current thread is to others, from -4 to 3, higher values running less often
+/// Threads start at 0, values out of range are clamped
+pub fn set_nice(nice: i8) {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") 1,
+            in("a1") 5,
+            in("a2") nice as isize,
+        );
+    }
+}
 
 #[macro_export]
 macro_rules! println {
@@ -0,0 +1,97 @@
+//! Threads that start on a stack of their own, running a closure
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A running thread, which can be waited on to finish
+///
+/// Dropping the handle leaves the thread running on its own, detached.
+pub struct JoinHandle {
+    thread_id: usize,
+}
+
+impl JoinHandle {
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
+    }
+
+    /// Blocks until the thread has exited
+    pub fn join(self) {
+        crate::join(self.thread_id);
+
+        // Joining already let go of the thread
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        crate::detach(self.thread_id);
+    }
+}
+
+/// Why a thread couldn't be made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The kernel had no room for the thread or its stack
+    NoResources,
+}
+
+/// Handed to the new thread, which takes the closure out of it before the spawning thread can go on
+struct Start<F> {
+    f: Option<F>,
+    taken: AtomicBool,
+}
+
+/// Runs `f` on a new thread with the kernel's default stack size
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Result<JoinHandle, SpawnError> {
+    // The kernel picks the size when it's asked for zero
+    spawn_with_stack(f, 0)
+}
+
+/// Runs `f` on a new thread with a stack of at least `stack_size` bytes
+pub fn spawn_with_stack<F: FnOnce() + Send + 'static>(f: F, stack_size: usize) -> Result<JoinHandle, SpawnError> {
+    let mut start = Start {
+        f: Some(f),
+        taken: AtomicBool::new(false),
+    };
+    let start_ptr = &mut start as *mut Start<F>;
+
+    let thread_id: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") 1,
+            in("a1") 6,
+            in("a2") thread_start::<F> as usize,
+            in("a3") start_ptr as usize,
+            in("a4") stack_size,
+            lateout("a0") thread_id,
+            lateout("a1") _,
+        );
+    }
+
+    if thread_id == usize::MAX {
+        return Err(SpawnError::NoResources);
+    }
+
+    // `start` lives on our stack, so it has to stay put until the thread has moved the closure onto its own
+    while !unsafe {(*start_ptr).taken.load(Ordering::Acquire)} {
+        crate::forfeit();
+    }
+
+    Ok(JoinHandle { thread_id })
+}
+
+extern "C" fn thread_start<F: FnOnce()>(start: *mut Start<F>) -> ! {
+    let f = unsafe {
+        let f = (*start).f.take().unwrap();
+        (*start).taken.store(true, Ordering::Release);
+
+        f
+    };
+
+    f();
+
+    unsafe {crate::drop_thread()}
+}
@@ -3,9 +3,19 @@ use structopt::StructOpt;
 #[derive(StructOpt)]
 enum Command {
     Build {},
+    /// Runs the kernel's unit tests on the host
+    Test {},
     Run {
         #[structopt(long)]
         debug: bool,
+
+        /// Number of virtconsole ports to attach, each on its own pty
+        #[structopt(long, default_value = "0")]
+        virtconsole: u32,
+
+        /// Number of harts the machine has
+        #[structopt(long, default_value = "1")]
+        smp: u32,
     },
 }
 
@@ -36,7 +46,11 @@ fn main() -> anyhow::Result<()> {
             build_user()?;
             build_kernel()?;
         },
-        Command::Run { debug } => {
+        Command::Test {} => {
+            let _dir = xshell::pushd("./host_tests");
+            xshell::cmd!("cargo test").run()?;
+        },
+        Command::Run { debug, virtconsole, smp } => {
             build_user()?;
             build_kernel()?;
 
@@ -45,6 +59,20 @@ fn main() -> anyhow::Result<()> {
                 false => &[],
             };
 
+            // QEMU prints which pty each port ended up on
+            let mut virtconsole_args = Vec::new();
+            if virtconsole != 0 {
+                virtconsole_args.extend(["-device".to_string(), "virtio-serial-device".to_string()]);
+            }
+            for port in 0..virtconsole {
+                virtconsole_args.extend([
+                    "-chardev".to_string(), format!("pty,id=vcon{}", port),
+                    "-device".to_string(), format!("virtconsole,chardev=vcon{},name=lsd.console.{}", port, port),
+                ]);
+            }
+
+            let smp = smp.max(1).to_string();
+
             xshell::cmd!("rm -rf root/boot").run()?;
             xshell::cmd!("mkdir -p root/boot").run()?;
             xshell::cmd!("cp config/spark.cfg root/boot").run()?;
@@ -55,16 +83,21 @@ fn main() -> anyhow::Result<()> {
                 qemu-system-riscv64
                     -machine virt
                     -cpu rv64,svpbmt=on
-                    -smp 1
+                    -smp {smp}
                     -m 512M
                     -bios opensbi-riscv64-generic-fw_jump.bin
                     -kernel config/spark-riscv-sbi-release.bin
                     -global virtio-mmio.force-legacy=false
                     -device virtio-rng-device
                     -device virtio-keyboard-device
+                    -device virtio-mouse-device
+                    -device virtio-tablet-device
+                    -netdev user,id=net0,restrict=on,hostfwd=udp:127.0.0.1:5555-:7
+                    -device virtio-net-device,netdev=net0
                     -device nvme,serial=deadbeff,drive=disk1
                     -drive id=disk1,format=raw,if=none,file=fat:rw:./root
                     -serial mon:stdio
+                    {virtconsole_args...}
                     {debug_log...}
             ").run()?;
         }